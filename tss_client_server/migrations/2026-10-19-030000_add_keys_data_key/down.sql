@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE keys
+    DROP COLUMN data_key,
+    DROP COLUMN master_key_id
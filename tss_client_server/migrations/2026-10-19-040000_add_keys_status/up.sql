@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE keys
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active',
+    ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
+
+-- placeholders left behind by keygens that never finished
+UPDATE keys SET status = 'failed' WHERE local_share = '';
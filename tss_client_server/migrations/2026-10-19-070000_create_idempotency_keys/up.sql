@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE idempotency_keys (
+    owner VARCHAR NOT NULL,
+    endpoint VARCHAR NOT NULL,
+    idempotency_key VARCHAR NOT NULL,
+    request_hash VARCHAR NOT NULL,
+    -- set once the request succeeded
+    response TEXT,
+    created_at TIMESTAMP NOT NULL,
+    PRIMARY KEY (owner, endpoint, idempotency_key)
+)
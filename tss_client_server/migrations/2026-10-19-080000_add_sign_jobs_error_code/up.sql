@@ -0,0 +1,2 @@
+-- Your SQL goes here
+ALTER TABLE sign_jobs ADD COLUMN error_code VARCHAR
@@ -0,0 +1,7 @@
+-- Your SQL goes here
+-- filled in from the share at keygen or import, and on startup for older keys
+ALTER TABLE keys ADD COLUMN public_key VARCHAR;
+ALTER TABLE keys ADD COLUMN public_key_uncompressed VARCHAR;
+ALTER TABLE keys ADD COLUMN threshold INTEGER;
+ALTER TABLE keys ADD COLUMN parties INTEGER;
+ALTER TABLE keys ADD COLUMN party_index INTEGER;
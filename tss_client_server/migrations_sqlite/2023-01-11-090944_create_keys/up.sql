@@ -0,0 +1,6 @@
+-- Your SQL goes here
+CREATE TABLE keys (
+    id INTEGER PRIMARY KEY,
+    address VARCHAR NOT NULL,
+    local_share TEXT NOT NULL
+)
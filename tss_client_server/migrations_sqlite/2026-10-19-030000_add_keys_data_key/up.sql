@@ -0,0 +1,3 @@
+-- Your SQL goes here
+ALTER TABLE keys ADD COLUMN data_key BLOB;
+ALTER TABLE keys ADD COLUMN master_key_id VARCHAR;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE keys DROP COLUMN status;
+ALTER TABLE keys DROP COLUMN created_at;
+ALTER TABLE keys DROP COLUMN updated_at;
@@ -0,0 +1,8 @@
+-- Your SQL goes here
+ALTER TABLE keys ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active';
+ALTER TABLE keys ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE keys ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE keys SET created_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP;
+
+-- placeholders left behind by keygens that never finished
+UPDATE keys SET status = 'failed' WHERE local_share = '';
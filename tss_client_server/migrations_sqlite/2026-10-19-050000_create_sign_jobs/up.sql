@@ -0,0 +1,16 @@
+-- Your SQL goes here
+CREATE TABLE sign_jobs (
+    id INTEGER PRIMARY KEY,
+    from_address VARCHAR NOT NULL,
+    tx_data TEXT NOT NULL,
+    tx TEXT,
+    status VARCHAR NOT NULL,
+    tx_sender_id BIGINT,
+    message_to_sign VARCHAR,
+    signature TEXT,
+    raw_tx TEXT,
+    tx_hash VARCHAR,
+    error TEXT,
+    created_at TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP NOT NULL
+)
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX keys_owner_idx;
+ALTER TABLE sign_jobs DROP COLUMN owner;
+ALTER TABLE keys DROP COLUMN owner;
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- existing keys have no owner until assigned with `set-key-owner`
+ALTER TABLE keys ADD COLUMN owner VARCHAR;
+ALTER TABLE sign_jobs ADD COLUMN owner VARCHAR;
+CREATE INDEX keys_owner_idx ON keys (owner);
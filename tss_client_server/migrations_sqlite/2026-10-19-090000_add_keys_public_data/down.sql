@@ -0,0 +1,6 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE keys DROP COLUMN party_index;
+ALTER TABLE keys DROP COLUMN parties;
+ALTER TABLE keys DROP COLUMN threshold;
+ALTER TABLE keys DROP COLUMN public_key_uncompressed;
+ALTER TABLE keys DROP COLUMN public_key;
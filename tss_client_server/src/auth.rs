@@ -0,0 +1,85 @@
+use std::collections::HashMap;
+
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use subtle::ConstantTimeEq;
+
+use crate::{API_KEYS, TX_SENDER_API_KEY};
+
+/// Request guard resolving header X-Api-Key to the tenant it was issued to
+pub struct Tenant(pub String);
+
+impl Tenant {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for Tenant {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("X-Api-Key") {
+            Some(api_key) => match find_tenant(&API_KEYS, api_key) {
+                Some(tenant) => Outcome::Success(Tenant(tenant.to_owned())),
+                None => Outcome::Failure((Status::Unauthorized, "api key is not valid")),
+            },
+            None => Outcome::Failure((Status::Unauthorized, "api key is missing")),
+        }
+    }
+}
+
+/// Request guard for the tx sender's callbacks, checked against header
+/// X-Api-Key. Every request fails while TX_SENDER_API_KEY is not set.
+pub struct TxSender;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for TxSender {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match (
+            request.headers().get_one("X-Api-Key"),
+            TX_SENDER_API_KEY.as_deref(),
+        ) {
+            (_, None) => Outcome::Failure((Status::Unauthorized, "tx sender api is disabled")),
+            (Some(api_key), Some(tx_sender_api_key))
+                if bool::from(api_key.as_bytes().ct_eq(tx_sender_api_key.as_bytes())) =>
+            {
+                Outcome::Success(TxSender)
+            }
+            (Some(_), _) => Outcome::Failure((Status::Unauthorized, "api key is not valid")),
+            (None, _) => Outcome::Failure((Status::Unauthorized, "api key is missing")),
+        }
+    }
+}
+
+/// Looks up the tenant of `api_key`, comparing it to every configured key in
+/// constant time so that the response time does not reveal how much of a key
+/// was guessed.
+fn find_tenant<'a>(api_keys: &'a HashMap<String, String>, api_key: &str) -> Option<&'a str> {
+    api_keys.iter().fold(None, |found, (key, tenant)| {
+        if bool::from(key.as_bytes().ct_eq(api_key.as_bytes())) {
+            Some(tenant.as_str())
+        } else {
+            found
+        }
+    })
+}
+
+/// Parses `<tenant>:<api key>` pairs separated by commas into a map from api
+/// key to tenant.
+pub fn parse_api_keys(api_keys: &str) -> HashMap<String, String> {
+    api_keys
+        .split(',')
+        .map(str::trim)
+        .filter(|pair| !pair.is_empty())
+        .map(|pair| match pair.split_once(':') {
+            Some((tenant, api_key)) if !tenant.is_empty() && !api_key.is_empty() => {
+                (api_key.to_string(), tenant.to_string())
+            }
+            _ => panic!("API_KEYS should be <tenant>:<api key> pairs separated by commas"),
+        })
+        .collect()
+}
@@ -1,58 +1,709 @@
 pub mod models;
 pub mod schema;
 
+use chrono::{NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use dotenv::dotenv;
-use std::env;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use tss_common::db::{allowed_from, invalid_transition, ConnectionMut, DbConnection};
+use tss_common::envelope::MasterKey;
+
+pub use tss_common::db::{DbError, DbPool};
 
 use self::models::*;
-use self::schema::keys::dsl::{address, keys, local_share};
+use self::schema::keys::dsl::{
+    address, created_at, data_key, keys, local_share, master_key_id, owner, status, updated_at,
+};
+use diesel::result::DatabaseErrorKind::{CheckViolation, UniqueViolation};
 use diesel::result::Error::{DatabaseError, NotFound};
-use diesel::result::DatabaseErrorKind::UniqueViolation;
 
-pub fn establish_connection() -> PgConnection {
-    dotenv().ok();
+/// Applied to SQLite databases on connect.
+const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
-}
+/// Sealed shares are bound to their row of this table.
+const KEYS_TABLE: &str = "keys";
+
+/// The columns of `Key`; `owner` is only filtered on.
+const KEY_COLUMNS: (
+    schema::keys::id,
+    schema::keys::address,
+    schema::keys::local_share,
+    schema::keys::data_key,
+    schema::keys::master_key_id,
+    schema::keys::status,
+    schema::keys::created_at,
+    schema::keys::updated_at,
+) = (
+    schema::keys::id,
+    schema::keys::address,
+    schema::keys::local_share,
+    schema::keys::data_key,
+    schema::keys::master_key_id,
+    schema::keys::status,
+    schema::keys::created_at,
+    schema::keys::updated_at,
+);
+
+/// The columns of `KeyInfo`.
+const KEY_INFO_COLUMNS: (
+    schema::keys::id,
+    schema::keys::address,
+    schema::keys::status,
+    schema::keys::public_key,
+    schema::keys::public_key_uncompressed,
+    schema::keys::threshold,
+    schema::keys::parties,
+    schema::keys::party_index,
+    schema::keys::created_at,
+    schema::keys::updated_at,
+) = (
+    schema::keys::id,
+    schema::keys::address,
+    schema::keys::status,
+    schema::keys::public_key,
+    schema::keys::public_key_uncompressed,
+    schema::keys::threshold,
+    schema::keys::parties,
+    schema::keys::party_index,
+    schema::keys::created_at,
+    schema::keys::updated_at,
+);
+
+/// Key repository, implemented for Postgres and SQLite connections.
+pub trait Storage {
+    fn get_key_by_address(&mut self, query_address: &str) -> QueryResult<Key>;
+
+    /// Key `id_data` if it belongs to `owner_data`. Keys of other tenants are
+    /// `NotFound`, as if they did not exist.
+    fn get_owned_key(&mut self, id_data: i32, owner_data: &str) -> QueryResult<KeyInfo>;
+
+    /// Key at `query_address`, hiding keys of other tenants like `get_owned_key`.
+    fn get_owned_key_by_address(
+        &mut self,
+        query_address: &str,
+        owner_data: &str,
+    ) -> QueryResult<KeyInfo>;
+
+    /// Keys of `owner_data` ordered by id.
+    fn list_keys(&mut self, owner_data: &str, offset: i64, limit: i64)
+        -> QueryResult<Vec<KeyInfo>>;
+
+    fn count_keys(&mut self, owner_data: &str) -> QueryResult<i64>;
+
+    /// Reserves an id for a key of `owner_data` whose keygen has not finished yet.
+    fn insert_new_key(&mut self, owner_data: &str) -> QueryResult<i32>;
+
+    /// Stores the keygen result and moves the key from generating to active.
+    fn fill_in_key_data(
+        &mut self,
+        id: i32,
+        adress_data: &str,
+        local_share_data: &str,
+        public_data: &KeyPublicData,
+    ) -> QueryResult<Key>;
+
+    /// Inserts a key restored from a backup under its original id.
+    fn insert_key(
+        &mut self,
+        id_data: i32,
+        adress_data: &str,
+        local_share_data: &str,
+        public_data: &KeyPublicData,
+    ) -> QueryResult<Key>;
+
+    /// Keys with a share but without its public fields, stored before they
+    /// were kept in columns.
+    fn get_keys_without_public_data(&mut self) -> QueryResult<Vec<Key>>;
+
+    fn set_key_public_data(&mut self, id_data: i32, public_data: &KeyPublicData)
+        -> QueryResult<()>;
+
+    /// Re-wraps every data key under `MASTER_KEY`, unwrapping with `previous`, and
+    /// seals shares still stored in plaintext. Returns the number of rows updated.
+    fn rewrap_local_shares(&mut self, previous: Option<&MasterKey>) -> QueryResult<usize>;
+
+    /// Hands a key over to another tenant, e.g. one created before ownership
+    /// or restored from a backup.
+    fn set_key_owner(&mut self, id_data: i32, owner_data: &str) -> QueryResult<Key>;
+
+    /// Moves a key to `new_status`, failing with `CheckViolation` if its
+    /// current status does not allow it.
+    fn set_key_status(&mut self, id_data: i32, new_status: KeyStatus) -> QueryResult<Key>;
+
+    /// Fails keys stuck in pending or generating since before `older_than`.
+    fn fail_stale_keys(&mut self, older_than: NaiveDateTime) -> QueryResult<Vec<Key>>;
+
+    /// Claims an idempotency key for a request; if the key was claimed before,
+    /// returns the earlier claim instead. An unfinished claim made before
+    /// `stale_before` was left by a crash and is taken over.
+    fn claim_idempotency_key(
+        &mut self,
+        owner_data: &str,
+        endpoint_data: &str,
+        key_data: &str,
+        request_hash_data: &str,
+        stale_before: NaiveDateTime,
+    ) -> QueryResult<Option<IdempotencyKey>>;
+
+    /// Stores the response of a claimed request, or releases the claim if
+    /// `response_data` is `None`.
+    fn complete_idempotency_key(
+        &mut self,
+        owner_data: &str,
+        endpoint_data: &str,
+        key_data: &str,
+        response_data: Option<&str>,
+    ) -> QueryResult<()>;
+
+    /// Deletes completed idempotency keys claimed before `older_than`, and
+    /// returns how many were deleted.
+    fn purge_idempotency_keys(&mut self, older_than: NaiveDateTime) -> QueryResult<usize>;
+
+    /// Records a `/send-tx` request of `owner_data` as a job in simulating.
+    fn insert_sign_job(
+        &mut self,
+        owner_data: &str,
+        from_address_data: &str,
+        tx_data_data: &str,
+        tx_json: Option<&str>,
+    ) -> QueryResult<SignJob>;
 
-pub fn get_local_share(conn: &mut PgConnection, query_address: &str) -> QueryResult<String> {
-    let result = keys.filter(address.eq(query_address)).load::<Key>(conn)?;
+    fn get_sign_job(&mut self, id_data: i32) -> QueryResult<SignJob>;
 
-    if result.len() == 0 {
-        return Err(NotFound);
-    } else if result.len() != 1 {
-        return Err(DatabaseError(
-            UniqueViolation,
-            Box::new("found multiple entries".to_string()),
-        ));
+    /// Moves a sign job to `new_status` and records `update`, failing with
+    /// `CheckViolation` if its current status does not allow it.
+    fn set_sign_job_status(
+        &mut self,
+        id_data: i32,
+        new_status: SignJobStatus,
+        update: SignJobUpdate,
+    ) -> QueryResult<SignJob>;
+
+    /// Moves the signed job of the tx sender's `tx_sender_id_data` to
+    /// submitted, storing `tx_hash_data` unless the job has its own. A job
+    /// submitted before is returned as is; `NotFound` if none was signed.
+    fn set_sign_job_submitted(
+        &mut self,
+        tx_sender_id_data: i64,
+        tx_hash_data: Option<&str>,
+    ) -> QueryResult<SignJob>;
+
+    /// Fails jobs left in simulating or signing since before `older_than`
+    /// with `error_data`, and returns them.
+    fn fail_stale_sign_jobs(
+        &mut self,
+        older_than: NaiveDateTime,
+        error_data: &str,
+        error_code_data: &str,
+    ) -> QueryResult<Vec<SignJob>>;
+
+    /// Only active keys may sign.
+    fn get_local_share(&mut self, query_address: &str) -> QueryResult<String> {
+        tss_common::db::open_active_local_share(
+            KEYS_TABLE,
+            &self.get_key_by_address(query_address)?,
+        )
     }
+}
 
-    Ok(result[0].local_share.to_owned())
+pub fn establish_connection() -> Box<dyn Storage> {
+    match tss_common::db::establish_connection(SQLITE_MIGRATIONS) {
+        DbConnection::Postgres(conn) => Box::new(conn),
+        DbConnection::Sqlite(conn) => Box::new(conn),
+    }
 }
 
-pub fn insert_new_key(conn: &mut PgConnection) -> i32 {
-    use crate::db::schema::keys;
+/// Builds the connection pool shared by every request.
+pub fn establish_pool() -> Result<DbPool, Box<dyn std::error::Error>> {
+    tss_common::db::establish_pool(SQLITE_MIGRATIONS)
+}
 
-    let key_inserted: Key = diesel::insert_into(keys::table)
-        .values((address.eq(""), local_share.eq("")))
-        .get_result(conn)
-        .expect("Error saving new key");
+/// Runs `f` with a pooled connection on the blocking thread pool, so queries
+/// never block the async runtime.
+pub async fn run<T, F>(pool: &DbPool, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut dyn Storage) -> QueryResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tss_common::db::run(pool, move |conn| match conn {
+        ConnectionMut::Postgres(conn) => f(conn),
+        ConnectionMut::Sqlite(conn) => f(conn),
+    })
+    .await
+}
+
+pub fn open_local_share(key: &Key) -> QueryResult<String> {
+    tss_common::db::open_local_share(KEYS_TABLE, key)
+}
+
+fn invalid_sign_job_transition(job: &SignJob, new_status: SignJobStatus) -> diesel::result::Error {
+    DatabaseError(
+        CheckViolation,
+        Box::new(format!(
+            "sign job {} cannot move from {} to {}",
+            job.id, job.status, new_status
+        )),
+    )
+}
 
-    key_inserted.id
+/// Keeps generated ids ahead of explicitly inserted ones.
+trait KeyIdSequence {
+    fn sync_key_id_sequence(&mut self) -> QueryResult<()>;
 }
 
-pub fn fill_in_key_data(
-    conn: &mut PgConnection,
-    id: i32,
-    adress_data: &str,
-    local_share_data: &str,
-) {
-    diesel::update(keys.find(id))
-        .set((address.eq(adress_data), local_share.eq(local_share_data)))
-        .get_result::<Key>(conn)
-        .unwrap();
+impl KeyIdSequence for PgConnection {
+    fn sync_key_id_sequence(&mut self) -> QueryResult<()> {
+        diesel::sql_query(
+            "SELECT setval(pg_get_serial_sequence('keys', 'id'), (SELECT MAX(id) FROM keys))",
+        )
+        .execute(self)?;
+        Ok(())
+    }
+}
+
+impl KeyIdSequence for SqliteConnection {
+    // `INTEGER PRIMARY KEY` rowids always continue after the largest id
+    fn sync_key_id_sequence(&mut self) -> QueryResult<()> {
+        Ok(())
+    }
+}
+
+/// The queries are the same for both backends, only the connection type differs.
+macro_rules! impl_storage {
+    ($connection:ty) => {
+        impl Storage for $connection {
+            fn get_key_by_address(&mut self, query_address: &str) -> QueryResult<Key> {
+                let mut result = keys
+                    .filter(address.eq(query_address))
+                    .select(KEY_COLUMNS)
+                    .load::<Key>(self)?;
+
+                if result.len() == 0 {
+                    return Err(NotFound);
+                } else if result.len() != 1 {
+                    return Err(DatabaseError(
+                        UniqueViolation,
+                        Box::new("found multiple entries".to_string()),
+                    ));
+                }
+
+                Ok(result.remove(0))
+            }
+
+            fn get_owned_key(&mut self, id_data: i32, owner_data: &str) -> QueryResult<KeyInfo> {
+                keys.find(id_data)
+                    .filter(owner.eq(owner_data))
+                    .select(KEY_INFO_COLUMNS)
+                    .first::<KeyInfo>(self)
+            }
+
+            fn get_owned_key_by_address(
+                &mut self,
+                query_address: &str,
+                owner_data: &str,
+            ) -> QueryResult<KeyInfo> {
+                keys.filter(address.eq(query_address))
+                    .filter(owner.eq(owner_data))
+                    .select(KEY_INFO_COLUMNS)
+                    .first::<KeyInfo>(self)
+            }
+
+            fn list_keys(
+                &mut self,
+                owner_data: &str,
+                offset: i64,
+                limit: i64,
+            ) -> QueryResult<Vec<KeyInfo>> {
+                use self::schema::keys::dsl::id;
+
+                keys.filter(owner.eq(owner_data))
+                    .order(id)
+                    .offset(offset)
+                    .limit(limit)
+                    .select(KEY_INFO_COLUMNS)
+                    .load::<KeyInfo>(self)
+            }
+
+            fn count_keys(&mut self, owner_data: &str) -> QueryResult<i64> {
+                keys.filter(owner.eq(owner_data)).count().get_result(self)
+            }
+
+            fn insert_new_key(&mut self, owner_data: &str) -> QueryResult<i32> {
+                use crate::db::schema::keys;
+
+                let now = Utc::now().naive_utc();
+                let key_inserted: Key = diesel::insert_into(keys::table)
+                    .values((
+                        address.eq(""),
+                        local_share.eq(""),
+                        status.eq(KeyStatus::Pending.as_str()),
+                        owner.eq(owner_data),
+                        created_at.eq(now),
+                        updated_at.eq(now),
+                    ))
+                    .returning(KEY_COLUMNS)
+                    .get_result(self)?;
+
+                Ok(key_inserted.id)
+            }
+
+            fn fill_in_key_data(
+                &mut self,
+                id: i32,
+                adress_data: &str,
+                local_share_data: &str,
+                public_data: &KeyPublicData,
+            ) -> QueryResult<Key> {
+                let sealed = tss_common::db::seal_local_share(KEYS_TABLE, id, local_share_data);
+                let updated = diesel::update(
+                    keys.find(id)
+                        .filter(status.eq_any(allowed_from(KeyStatus::Active))),
+                )
+                .set((
+                    address.eq(adress_data),
+                    local_share.eq(sealed.local_share),
+                    data_key.eq(sealed.data_key),
+                    master_key_id.eq(sealed.master_key_id),
+                    status.eq(KeyStatus::Active.as_str()),
+                    updated_at.eq(Utc::now().naive_utc()),
+                    public_data,
+                ))
+                .returning(KEY_COLUMNS)
+                .get_result::<Key>(self)
+                .optional()?;
+
+                match updated {
+                    Some(key) => Ok(key),
+                    None => Err(invalid_transition(
+                        &keys.find(id).select(KEY_COLUMNS).first::<Key>(self)?,
+                        KeyStatus::Active,
+                    )),
+                }
+            }
+
+            fn insert_key(
+                &mut self,
+                id_data: i32,
+                adress_data: &str,
+                local_share_data: &str,
+                public_data: &KeyPublicData,
+            ) -> QueryResult<Key> {
+                use self::schema::keys::dsl::id;
+
+                let sealed =
+                    tss_common::db::seal_local_share(KEYS_TABLE, id_data, local_share_data);
+                let now = Utc::now().naive_utc();
+                self.transaction(|conn| {
+                    let key_inserted = diesel::insert_into(keys)
+                        .values((
+                            id.eq(id_data),
+                            address.eq(adress_data),
+                            local_share.eq(sealed.local_share),
+                            data_key.eq(sealed.data_key),
+                            master_key_id.eq(sealed.master_key_id),
+                            status.eq(KeyStatus::Active.as_str()),
+                            created_at.eq(now),
+                            updated_at.eq(now),
+                            public_data,
+                        ))
+                        .returning(KEY_COLUMNS)
+                        .get_result(conn)?;
+
+                    conn.sync_key_id_sequence()?;
+
+                    Ok(key_inserted)
+                })
+            }
+
+            fn get_keys_without_public_data(&mut self) -> QueryResult<Vec<Key>> {
+                use self::schema::keys::dsl::public_key;
+
+                keys.filter(public_key.is_null())
+                    .filter(local_share.ne(""))
+                    .select(KEY_COLUMNS)
+                    .load::<Key>(self)
+            }
+
+            fn set_key_public_data(
+                &mut self,
+                id_data: i32,
+                public_data: &KeyPublicData,
+            ) -> QueryResult<()> {
+                diesel::update(keys.find(id_data))
+                    .set(public_data)
+                    .execute(self)?;
+                Ok(())
+            }
+
+            fn rewrap_local_shares(&mut self, previous: Option<&MasterKey>) -> QueryResult<usize> {
+                self.transaction(|conn| {
+                    let stored_keys = keys
+                        .filter(local_share.ne(""))
+                        .select(KEY_COLUMNS)
+                        .load::<Key>(conn)?;
+                    let mut updated = 0;
+
+                    for key in stored_keys {
+                        let sealed =
+                            match tss_common::db::reseal_local_share(KEYS_TABLE, &key, previous)? {
+                                Some(sealed) => sealed,
+                                None => continue,
+                            };
+                        diesel::update(keys.find(key.id))
+                            .set((
+                                local_share.eq(sealed.local_share),
+                                data_key.eq(sealed.data_key),
+                                master_key_id.eq(sealed.master_key_id),
+                            ))
+                            .execute(conn)?;
+                        updated += 1;
+                    }
+
+                    Ok(updated)
+                })
+            }
+
+            fn set_key_owner(&mut self, id_data: i32, owner_data: &str) -> QueryResult<Key> {
+                diesel::update(keys.find(id_data))
+                    .set((owner.eq(owner_data), updated_at.eq(Utc::now().naive_utc())))
+                    .returning(KEY_COLUMNS)
+                    .get_result(self)
+            }
+
+            fn set_key_status(&mut self, id_data: i32, new_status: KeyStatus) -> QueryResult<Key> {
+                let updated = diesel::update(
+                    keys.find(id_data)
+                        .filter(status.eq_any(allowed_from(new_status))),
+                )
+                .set((
+                    status.eq(new_status.as_str()),
+                    updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .returning(KEY_COLUMNS)
+                .get_result::<Key>(self)
+                .optional()?;
+
+                match updated {
+                    Some(key) => Ok(key),
+                    None => Err(invalid_transition(
+                        &keys.find(id_data).select(KEY_COLUMNS).first::<Key>(self)?,
+                        new_status,
+                    )),
+                }
+            }
+
+            fn fail_stale_keys(&mut self, older_than: NaiveDateTime) -> QueryResult<Vec<Key>> {
+                diesel::update(
+                    keys.filter(status.eq_any(allowed_from(KeyStatus::Failed)))
+                        .filter(updated_at.lt(older_than)),
+                )
+                .set((
+                    status.eq(KeyStatus::Failed.as_str()),
+                    updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .returning(KEY_COLUMNS)
+                .get_results(self)
+            }
+
+            fn claim_idempotency_key(
+                &mut self,
+                owner_data: &str,
+                endpoint_data: &str,
+                key_data: &str,
+                request_hash_data: &str,
+                stale_before: NaiveDateTime,
+            ) -> QueryResult<Option<IdempotencyKey>> {
+                use crate::db::schema::idempotency_keys;
+
+                let now = Utc::now().naive_utc();
+                let inserted = diesel::insert_into(idempotency_keys::table)
+                    .values((
+                        idempotency_keys::owner.eq(owner_data),
+                        idempotency_keys::endpoint.eq(endpoint_data),
+                        idempotency_keys::idempotency_key.eq(key_data),
+                        idempotency_keys::request_hash.eq(request_hash_data),
+                        idempotency_keys::created_at.eq(now),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(self)?;
+                if inserted == 1 {
+                    return Ok(None);
+                }
+
+                let taken_over = diesel::update(
+                    idempotency_keys::table
+                        .find((owner_data, endpoint_data, key_data))
+                        .filter(idempotency_keys::response.is_null())
+                        .filter(idempotency_keys::created_at.lt(stale_before)),
+                )
+                .set((
+                    idempotency_keys::request_hash.eq(request_hash_data),
+                    idempotency_keys::created_at.eq(now),
+                ))
+                .execute(self)?;
+                if taken_over == 1 {
+                    return Ok(None);
+                }
+
+                idempotency_keys::table
+                    .find((owner_data, endpoint_data, key_data))
+                    .first::<IdempotencyKey>(self)
+                    .map(Some)
+            }
+
+            fn complete_idempotency_key(
+                &mut self,
+                owner_data: &str,
+                endpoint_data: &str,
+                key_data: &str,
+                response_data: Option<&str>,
+            ) -> QueryResult<()> {
+                use crate::db::schema::idempotency_keys;
+
+                let claim = idempotency_keys::table.find((owner_data, endpoint_data, key_data));
+                match response_data {
+                    Some(response_data) => diesel::update(claim)
+                        .set(idempotency_keys::response.eq(response_data))
+                        .execute(self)?,
+                    None => diesel::delete(claim).execute(self)?,
+                };
+                Ok(())
+            }
+
+            fn purge_idempotency_keys(&mut self, older_than: NaiveDateTime) -> QueryResult<usize> {
+                use crate::db::schema::idempotency_keys;
+
+                diesel::delete(
+                    idempotency_keys::table
+                        .filter(idempotency_keys::response.is_not_null())
+                        .filter(idempotency_keys::created_at.lt(older_than)),
+                )
+                .execute(self)
+            }
+
+            fn insert_sign_job(
+                &mut self,
+                owner_data: &str,
+                from_address_data: &str,
+                tx_data_data: &str,
+                tx_json: Option<&str>,
+            ) -> QueryResult<SignJob> {
+                use crate::db::schema::sign_jobs;
+
+                let now = Utc::now().naive_utc();
+                diesel::insert_into(sign_jobs::table)
+                    .values((
+                        sign_jobs::from_address.eq(from_address_data),
+                        sign_jobs::tx_data.eq(tx_data_data),
+                        sign_jobs::tx.eq(tx_json),
+                        sign_jobs::status.eq(SignJobStatus::Simulating.as_str()),
+                        sign_jobs::owner.eq(owner_data),
+                        sign_jobs::created_at.eq(now),
+                        sign_jobs::updated_at.eq(now),
+                    ))
+                    .get_result(self)
+            }
+
+            fn get_sign_job(&mut self, id_data: i32) -> QueryResult<SignJob> {
+                use crate::db::schema::sign_jobs;
+
+                sign_jobs::table.find(id_data).first::<SignJob>(self)
+            }
+
+            fn set_sign_job_status(
+                &mut self,
+                id_data: i32,
+                new_status: SignJobStatus,
+                update: SignJobUpdate,
+            ) -> QueryResult<SignJob> {
+                use crate::db::schema::sign_jobs;
+
+                let allowed: Vec<&str> = new_status
+                    .allowed_from()
+                    .iter()
+                    .map(SignJobStatus::as_str)
+                    .collect();
+                let updated = diesel::update(
+                    sign_jobs::table
+                        .find(id_data)
+                        .filter(sign_jobs::status.eq_any(allowed)),
+                )
+                .set((
+                    sign_jobs::status.eq(new_status.as_str()),
+                    sign_jobs::updated_at.eq(Utc::now().naive_utc()),
+                    update,
+                ))
+                .get_result::<SignJob>(self)
+                .optional()?;
+
+                match updated {
+                    Some(job) => Ok(job),
+                    None => Err(invalid_sign_job_transition(
+                        &sign_jobs::table.find(id_data).first::<SignJob>(self)?,
+                        new_status,
+                    )),
+                }
+            }
+
+            fn set_sign_job_submitted(
+                &mut self,
+                tx_sender_id_data: i64,
+                tx_hash_data: Option<&str>,
+            ) -> QueryResult<SignJob> {
+                use crate::db::schema::sign_jobs;
+
+                let job = sign_jobs::table
+                    .filter(sign_jobs::tx_sender_id.eq(tx_sender_id_data))
+                    .filter(sign_jobs::status.eq_any([
+                        SignJobStatus::Signed.as_str(),
+                        SignJobStatus::Submitted.as_str(),
+                    ]))
+                    .order(sign_jobs::id.desc())
+                    .first::<SignJob>(self)?;
+                if job.status == SignJobStatus::Submitted.as_str() {
+                    return Ok(job);
+                }
+
+                let update = SignJobUpdate {
+                    tx_hash: match job.tx_hash {
+                        Some(_) => None,
+                        None => tx_hash_data.map(str::to_string),
+                    },
+                    ..Default::default()
+                };
+                self.set_sign_job_status(job.id, SignJobStatus::Submitted, update)
+            }
+
+            fn fail_stale_sign_jobs(
+                &mut self,
+                older_than: NaiveDateTime,
+                error_data: &str,
+                error_code_data: &str,
+            ) -> QueryResult<Vec<SignJob>> {
+                use crate::db::schema::sign_jobs;
+
+                let stale: Vec<&str> = SignJobStatus::Failed
+                    .allowed_from()
+                    .iter()
+                    .map(SignJobStatus::as_str)
+                    .collect();
+                diesel::update(
+                    sign_jobs::table
+                        .filter(sign_jobs::status.eq_any(stale))
+                        .filter(sign_jobs::updated_at.lt(older_than)),
+                )
+                .set((
+                    sign_jobs::status.eq(SignJobStatus::Failed.as_str()),
+                    sign_jobs::updated_at.eq(Utc::now().naive_utc()),
+                    sign_jobs::error.eq(error_data),
+                    sign_jobs::error_code.eq(error_code_data),
+                ))
+                .get_results(self)
+            }
+        }
+    };
 }
+
+impl_storage!(PgConnection);
+impl_storage!(SqliteConnection);
@@ -1,8 +1,121 @@
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
+pub use tss_common::db::models::{Key, KeyStatus};
+
+/// The public fields of a `keys` row, read without its share.
 #[derive(Queryable, Debug)]
-pub struct Key {
+pub struct KeyInfo {
     pub id: i32,
     pub address: String,
-    pub local_share: String,
+    pub status: String,
+    /// SEC1 hex, set once the key is active
+    pub public_key: Option<String>,
+    pub public_key_uncompressed: Option<String>,
+    pub threshold: Option<i32>,
+    pub parties: Option<i32>,
+    pub party_index: Option<i32>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// The public fields of a share, stored next to it so that reading a key
+/// never opens the share.
+#[derive(Insertable, AsChangeset, Debug)]
+#[diesel(table_name = crate::db::schema::keys)]
+pub struct KeyPublicData {
+    pub public_key: String,
+    pub public_key_uncompressed: String,
+    pub threshold: i32,
+    pub parties: i32,
+    pub party_index: i32,
+}
+
+#[derive(Queryable, Debug)]
+pub struct IdempotencyKey {
+    pub owner: String,
+    pub endpoint: String,
+    pub idempotency_key: String,
+    pub request_hash: String,
+    /// JSON of the stored response, `None` while the request is running
+    pub response: Option<String>,
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug)]
+pub struct SignJob {
+    pub id: i32,
+    pub from_address: String,
+    pub tx_data: String,
+    /// JSON of the structured `Transaction`, if the request carried one
+    pub tx: Option<String>,
+    pub status: String,
+    pub tx_sender_id: Option<i64>,
+    pub message_to_sign: Option<String>,
+    pub signature: Option<String>,
+    pub raw_tx: Option<String>,
+    pub tx_hash: Option<String>,
+    pub error: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+    pub owner: Option<String>,
+    /// Stable code of the `ApiError` the job failed with
+    pub error_code: Option<String>,
+}
+
+/// Fields recorded alongside a sign job's status change; `None` leaves a
+/// column untouched.
+#[derive(AsChangeset, Default)]
+#[diesel(table_name = crate::db::schema::sign_jobs)]
+pub struct SignJobUpdate {
+    pub tx_sender_id: Option<i64>,
+    pub message_to_sign: Option<String>,
+    pub signature: Option<String>,
+    pub raw_tx: Option<String>,
+    pub tx_hash: Option<String>,
+    pub error: Option<String>,
+    pub error_code: Option<String>,
+}
+
+/// Lifecycle of a `/send-tx` request; transitions are enforced by
+/// `Storage::set_sign_job_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SignJobStatus {
+    /// Waiting for the tx sender's simulation
+    Simulating,
+    Signing,
+    /// Share 2 delivers its signature of the same tx to the tx sender
+    Signed,
+    /// The tx sender confirmed it submitted the tx
+    Submitted,
+    Failed,
+}
+
+impl SignJobStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            SignJobStatus::Simulating => "simulating",
+            SignJobStatus::Signing => "signing",
+            SignJobStatus::Signed => "signed",
+            SignJobStatus::Submitted => "submitted",
+            SignJobStatus::Failed => "failed",
+        }
+    }
+
+    /// States a sign job may move to this state from.
+    pub fn allowed_from(&self) -> &'static [SignJobStatus] {
+        match self {
+            SignJobStatus::Simulating => &[],
+            SignJobStatus::Signing => &[SignJobStatus::Simulating],
+            SignJobStatus::Signed => &[SignJobStatus::Signing],
+            SignJobStatus::Submitted => &[SignJobStatus::Signed],
+            SignJobStatus::Failed => &[SignJobStatus::Simulating, SignJobStatus::Signing],
+        }
+    }
+}
+
+impl std::fmt::Display for SignJobStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
 }
@@ -1,9 +1,53 @@
 // @generated automatically by Diesel CLI.
 
+diesel::table! {
+    idempotency_keys (owner, endpoint, idempotency_key) {
+        owner -> Varchar,
+        endpoint -> Varchar,
+        idempotency_key -> Varchar,
+        request_hash -> Varchar,
+        response -> Nullable<Text>,
+        created_at -> Timestamp,
+    }
+}
+
 diesel::table! {
     keys (id) {
         id -> Int4,
         address -> Varchar,
         local_share -> Text,
+        data_key -> Nullable<Bytea>,
+        master_key_id -> Nullable<Varchar>,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        owner -> Nullable<Varchar>,
+        public_key -> Nullable<Varchar>,
+        public_key_uncompressed -> Nullable<Varchar>,
+        threshold -> Nullable<Int4>,
+        parties -> Nullable<Int4>,
+        party_index -> Nullable<Int4>,
+    }
+}
+
+diesel::table! {
+    sign_jobs (id) {
+        id -> Int4,
+        from_address -> Varchar,
+        tx_data -> Text,
+        tx -> Nullable<Text>,
+        status -> Varchar,
+        tx_sender_id -> Nullable<Int8>,
+        message_to_sign -> Nullable<Varchar>,
+        signature -> Nullable<Text>,
+        raw_tx -> Nullable<Text>,
+        tx_hash -> Nullable<Varchar>,
+        error -> Nullable<Text>,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+        owner -> Nullable<Varchar>,
+        error_code -> Nullable<Varchar>,
     }
 }
+
+diesel::allow_tables_to_appear_in_same_query!(keys, sign_jobs,);
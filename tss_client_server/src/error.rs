@@ -0,0 +1,204 @@
+//! Errors returned by the api, each with an HTTP status and a stable code.
+//!
+//! Every failure, including those caught by Rocket before a route runs, is
+//! answered with the same body:
+//!
+//! ```json
+//! {"success": false, "error": {"code": "key_not_found", "message": "..."}}
+//! ```
+
+use std::fmt;
+
+use diesel::result::DatabaseErrorKind::CheckViolation;
+use diesel::result::Error::DatabaseError;
+use rocket::http::Status;
+use rocket::request::Request;
+use rocket::response::{self, Responder};
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::Catcher;
+
+use crate::db::DbError;
+
+#[derive(Debug)]
+pub enum ApiError {
+    InvalidRequest(String),
+    KeyNotFound(String),
+    /// The key exists but is not active, e.g. revoked
+    KeyNotActive(String),
+    SignJobNotFound(String),
+    /// The job was left in simulating or signing, e.g. by a restart
+    SignJobTimedOut,
+    IdempotencyKeyMismatch,
+    IdempotencyKeyInProgress,
+    TxSenderUnreachable(String),
+    TxSenderInvalidResponse(String),
+    SimulationFailed,
+    /// The tx sender's `message_to_sign` is not the signing hash of the tx
+    MessageMismatch,
+    /// The other party did not finish the MPC protocol in `MPC_TIMEOUT_SECS`
+    MpcTimeout,
+    /// The MPC protocol terminated with an error
+    PeerAbort(String),
+    Database(DbError),
+    Internal(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(crate = "rocket::serde")]
+pub struct ErrorBody {
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+struct ErrorRes {
+    success: bool,
+    error: ErrorBody,
+}
+
+impl ApiError {
+    pub fn status(&self) -> Status {
+        match self {
+            ApiError::InvalidRequest(_) => Status::BadRequest,
+            ApiError::KeyNotFound(_) | ApiError::SignJobNotFound(_) => Status::NotFound,
+            ApiError::KeyNotActive(_) | ApiError::IdempotencyKeyInProgress => Status::Conflict,
+            ApiError::IdempotencyKeyMismatch
+            | ApiError::SimulationFailed
+            | ApiError::MessageMismatch => Status::UnprocessableEntity,
+            ApiError::TxSenderUnreachable(_)
+            | ApiError::TxSenderInvalidResponse(_)
+            | ApiError::PeerAbort(_) => Status::BadGateway,
+            ApiError::MpcTimeout | ApiError::SignJobTimedOut => Status::GatewayTimeout,
+            ApiError::Database(DbError::Pool(_)) => Status::ServiceUnavailable,
+            ApiError::Database(DbError::Query(_)) | ApiError::Internal(_) => {
+                Status::InternalServerError
+            }
+        }
+    }
+
+    pub fn code(&self) -> &'static str {
+        match self {
+            ApiError::InvalidRequest(_) => "invalid_request",
+            ApiError::KeyNotFound(_) => "key_not_found",
+            ApiError::KeyNotActive(_) => "key_not_active",
+            ApiError::SignJobNotFound(_) => "sign_job_not_found",
+            ApiError::SignJobTimedOut => "sign_job_timed_out",
+            ApiError::IdempotencyKeyMismatch => "idempotency_key_mismatch",
+            ApiError::IdempotencyKeyInProgress => "idempotency_key_in_progress",
+            ApiError::TxSenderUnreachable(_) => "tx_sender_unreachable",
+            ApiError::TxSenderInvalidResponse(_) => "tx_sender_invalid_response",
+            ApiError::SimulationFailed => "simulation_failed",
+            ApiError::MessageMismatch => "message_mismatch",
+            ApiError::MpcTimeout => "mpc_timeout",
+            ApiError::PeerAbort(_) => "peer_abort",
+            ApiError::Database(DbError::Pool(_)) => "database_unavailable",
+            ApiError::Database(DbError::Query(_)) => "database_error",
+            ApiError::Internal(_) => "internal_error",
+        }
+    }
+
+    pub fn body(&self) -> ErrorBody {
+        ErrorBody {
+            code: self.code().to_string(),
+            message: self.to_string(),
+        }
+    }
+
+    /// Maps a failed key lookup, telling a missing key from an inactive one.
+    pub fn key_lookup(key: &str, e: DbError) -> ApiError {
+        match e {
+            DbError::Query(diesel::result::Error::NotFound) => {
+                ApiError::KeyNotFound(key.to_string())
+            }
+            DbError::Query(DatabaseError(CheckViolation, info)) => {
+                ApiError::KeyNotActive(info.message().to_string())
+            }
+            e => ApiError::Database(e),
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ApiError::InvalidRequest(info) => write!(f, "invalid request: {}", info),
+            ApiError::KeyNotFound(key) => write!(f, "no key for {}", key),
+            ApiError::KeyNotActive(info) => f.write_str(info),
+            ApiError::SignJobNotFound(id) => write!(f, "no sign job {}", id),
+            ApiError::SignJobTimedOut => write!(
+                f,
+                "sign job did not finish within {}s",
+                *crate::SIGN_JOB_TIMEOUT_SECS
+            ),
+            ApiError::IdempotencyKeyMismatch => {
+                f.write_str("idempotency key was used with a different request")
+            }
+            ApiError::IdempotencyKeyInProgress => {
+                f.write_str("a request with this idempotency key is still in progress")
+            }
+            ApiError::TxSenderUnreachable(info) => write!(f, "fail to call tx sender: {}", info),
+            ApiError::TxSenderInvalidResponse(info) => {
+                write!(f, "fail on parsing tx sender response: {}", info)
+            }
+            ApiError::SimulationFailed => f.write_str("tx simulation failed"),
+            ApiError::MessageMismatch => f.write_str("message_to_sign does not match tx"),
+            ApiError::MpcTimeout => f.write_str("mpc protocol timed out"),
+            ApiError::PeerAbort(info) => write!(f, "mpc protocol aborted: {}", info),
+            ApiError::Database(e) => e.fmt(f),
+            ApiError::Internal(info) => f.write_str(info),
+        }
+    }
+}
+
+impl std::error::Error for ApiError {}
+
+impl From<DbError> for ApiError {
+    fn from(e: DbError) -> Self {
+        ApiError::Database(e)
+    }
+}
+
+fn error_res(status: Status, error: ErrorBody) -> (Status, Json<ErrorRes>) {
+    (
+        status,
+        Json(ErrorRes {
+            success: false,
+            error,
+        }),
+    )
+}
+
+impl<'r> Responder<'r, 'static> for ApiError {
+    fn respond_to(self, request: &'r Request<'_>) -> response::Result<'static> {
+        if self.status() == Status::InternalServerError {
+            println!("internal error: {}", self);
+        }
+        error_res(self.status(), self.body()).respond_to(request)
+    }
+}
+
+#[catch(default)]
+fn default_catcher(status: Status, _: &Request) -> (Status, Json<ErrorRes>) {
+    let code = match status.code {
+        400 => "bad_request",
+        401 => "unauthorized",
+        404 => "not_found",
+        422 => "unprocessable_entity",
+        500 => "internal_error",
+        _ => "http_error",
+    };
+    error_res(
+        status,
+        ErrorBody {
+            code: code.to_string(),
+            message: status.reason().unwrap_or("unknown error").to_lowercase(),
+        },
+    )
+}
+
+/// Answers errors raised outside the routes, e.g. by request guards or bad
+/// JSON, with the same body.
+pub fn catchers() -> Vec<Catcher> {
+    catchers![default_catcher]
+}
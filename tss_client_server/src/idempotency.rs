@@ -0,0 +1,122 @@
+//! `Idempotency-Key` handling for `/new-key` and `/send-tx`.
+//!
+//! The first request with a key claims it, per tenant and endpoint, and
+//! stores its response once it succeeded; repeats with the same body get that
+//! response back. A failed request releases the key so it can be retried.
+//!
+//! A claim without a response after `IDEMPOTENCY_CLAIM_TIMEOUT_SECS` was left
+//! by the server stopping mid-request and is taken over by the next request.
+//! Stored responses are kept for `IDEMPOTENCY_KEY_RETENTION_SECS`, after which
+//! the key can be used again.
+
+use std::time::Duration;
+
+use crypto::digest::Digest;
+use crypto::sha2::Sha256;
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use rocket::serde::{de::DeserializeOwned, Serialize};
+
+use crate::db::{self, DbPool};
+use crate::error::ApiError;
+use crate::{IDEMPOTENCY_CLAIM_TIMEOUT_SECS, IDEMPOTENCY_KEY_RETENTION_SECS};
+
+/// Request guard for the optional header Idempotency-Key
+pub struct IdempotencyKey(pub Option<String>);
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for IdempotencyKey {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("Idempotency-Key") {
+            Some(key) if key.is_empty() || key.len() > 255 => {
+                Outcome::Failure((Status::BadRequest, "idempotency key is not valid"))
+            }
+            key => Outcome::Success(IdempotencyKey(key.map(str::to_string))),
+        }
+    }
+}
+
+/// Claims `key` for `request`. Returns the stored response of an earlier
+/// request with the same key and body, or the error to reject this one with.
+pub async fn claim<T, R>(
+    pool: &DbPool,
+    owner: &str,
+    endpoint: &'static str,
+    key: &str,
+    request: &R,
+) -> Result<Option<T>, ApiError>
+where
+    T: DeserializeOwned,
+    R: Serialize,
+{
+    let request_hash = request_hash(request);
+    let (owner_data, key_data, hash_data) =
+        (owner.to_owned(), key.to_owned(), request_hash.to_owned());
+    let stale_before = (chrono::Utc::now()
+        - chrono::Duration::seconds(*IDEMPOTENCY_CLAIM_TIMEOUT_SECS))
+    .naive_utc();
+    let claimed = db::run(pool, move |conn| {
+        conn.claim_idempotency_key(&owner_data, endpoint, &key_data, &hash_data, stale_before)
+    })
+    .await?;
+
+    let claimed = match claimed {
+        Some(claimed) => claimed,
+        None => return Ok(None),
+    };
+    if claimed.request_hash != request_hash {
+        return Err(ApiError::IdempotencyKeyMismatch);
+    }
+    match claimed.response {
+        Some(response) => serde_json::from_str(&response)
+            .map(Some)
+            .map_err(|e| ApiError::Internal(format!("cannot read stored response: {}", e))),
+        None => Err(ApiError::IdempotencyKeyInProgress),
+    }
+}
+
+/// Stores `response` for repeats of a claimed request, or releases the claim
+/// if the request failed.
+pub async fn complete<T: Serialize>(
+    pool: &DbPool,
+    owner: &str,
+    endpoint: &'static str,
+    key: &str,
+    response: Option<&T>,
+) {
+    let response = response
+        .map(|response| serde_json::to_string(response).expect("error serializing response"));
+    let (owner_data, key_data) = (owner.to_owned(), key.to_owned());
+    if let Err(e) = db::run(pool, move |conn| {
+        conn.complete_idempotency_key(&owner_data, endpoint, &key_data, response.as_deref())
+    })
+    .await
+    {
+        println!("error storing response for idempotency key {}: {}", key, e);
+    }
+}
+
+/// Deletes stored responses past their retention.
+pub async fn purge_idempotency_keys(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(3600));
+    loop {
+        interval.tick().await;
+
+        let older_than = (chrono::Utc::now()
+            - chrono::Duration::seconds(*IDEMPOTENCY_KEY_RETENTION_SECS))
+        .naive_utc();
+        match db::run(&pool, move |conn| conn.purge_idempotency_keys(older_than)).await {
+            Ok(0) => {}
+            Ok(purged) => println!("purged {} idempotency keys", purged),
+            Err(e) => println!("error purging idempotency keys: {}", e),
+        }
+    }
+}
+
+fn request_hash<R: Serialize>(request: &R) -> String {
+    let mut hasher = Sha256::new();
+    hasher.input(&serde_json::to_vec(request).expect("error serializing request"));
+    hasher.result_str()
+}
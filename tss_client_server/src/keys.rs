@@ -0,0 +1,297 @@
+//! Background keygen jobs and key status polling.
+//!
+//! `/new-key` only reserves a key; its id doubles as the job id. The key's
+//! lifecycle status is the job status.
+
+use std::ops::Deref;
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use tss_common::{address, share};
+use tss_sm_client::{LocalKey, Secp256k1};
+
+use crate::auth::Tenant;
+use crate::db::models::{KeyInfo, KeyPublicData, KeyStatus};
+use crate::db::{self, DbPool};
+use crate::error::ApiError;
+use crate::webhook;
+use crate::{
+    sm_manager_url, with_mpc_timeout, NewKeyRes, HTTP_CLIENT, KEY_PENDING_TIMEOUT_SECS,
+    PARTY_INDEX, TX_SENDER_URL,
+};
+
+const MAX_PER_PAGE: i64 = 100;
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct PublicKeyRes {
+    /// SEC1 hex, 33 bytes
+    compressed: String,
+    /// SEC1 hex, 65 bytes
+    uncompressed: String,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeyRes {
+    id: i32,
+    status: String,
+    /// Set once the key is active
+    address: Option<String>,
+    public_key: Option<PublicKeyRes>,
+    threshold: Option<i32>,
+    parties: Option<i32>,
+    party_index: Option<i32>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct KeysRes {
+    keys: Vec<KeyRes>,
+    page: i64,
+    per_page: i64,
+    total: i64,
+}
+
+/// The public fields of a share, stored with it at keygen and import.
+pub fn public_data(local_key: &LocalKey<Secp256k1>) -> KeyPublicData {
+    KeyPublicData {
+        public_key: format!(
+            "0x{}",
+            hex::encode(local_key.y_sum_s.to_bytes(true).deref())
+        ),
+        public_key_uncompressed: format!(
+            "0x{}",
+            hex::encode(local_key.y_sum_s.to_bytes(false).deref())
+        ),
+        threshold: local_key.t.into(),
+        parties: local_key.n.into(),
+        party_index: local_key.i.into(),
+    }
+}
+
+fn key_res(key: KeyInfo) -> KeyRes {
+    KeyRes {
+        id: key.id,
+        status: key.status,
+        address: Some(key.address).filter(|address| !address.is_empty()),
+        public_key: key.public_key.zip(key.public_key_uncompressed).map(
+            |(compressed, uncompressed)| PublicKeyRes {
+                compressed,
+                uncompressed,
+            },
+        ),
+        threshold: key.threshold,
+        parties: key.parties,
+        party_index: key.party_index,
+        created_at: key.created_at,
+        updated_at: key.updated_at,
+    }
+}
+
+/// Lists the caller's keys by id, `per_page` (default 20) at most 100.
+#[get("/keys?<page>&<per_page>")]
+pub(crate) async fn list_keys(
+    tenant: Tenant,
+    pool: &State<DbPool>,
+    page: Option<i64>,
+    per_page: Option<i64>,
+) -> Result<Json<KeysRes>, ApiError> {
+    let page = page.unwrap_or(1);
+    let per_page = per_page.unwrap_or(20);
+    if page < 1 || !(1..=MAX_PER_PAGE).contains(&per_page) {
+        return Err(ApiError::InvalidRequest(format!(
+            "page should be at least 1 and per_page between 1 and {}",
+            MAX_PER_PAGE
+        )));
+    }
+
+    let offset = (page - 1)
+        .checked_mul(per_page)
+        .ok_or_else(|| ApiError::InvalidRequest("page is too large".to_string()))?;
+
+    let res = db::run(pool, move |conn| {
+        let total = conn.count_keys(tenant.as_str())?;
+        let keys = conn.list_keys(tenant.as_str(), offset, per_page)?;
+        Ok(KeysRes {
+            keys: keys.into_iter().map(key_res).collect(),
+            page,
+            per_page,
+            total,
+        })
+    })
+    .await?;
+
+    Ok(Json(res))
+}
+
+#[get("/keys/<id>")]
+pub(crate) async fn get_key(
+    tenant: Tenant,
+    pool: &State<DbPool>,
+    id: i32,
+) -> Result<Json<KeyRes>, ApiError> {
+    db::run(pool, move |conn| {
+        conn.get_owned_key(id, tenant.as_str()).map(key_res)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| ApiError::key_lookup(&id.to_string(), e))
+}
+
+#[get("/keys/by-address/<address>")]
+pub(crate) async fn get_key_by_address(
+    tenant: Tenant,
+    pool: &State<DbPool>,
+    address: &str,
+) -> Result<Json<KeyRes>, ApiError> {
+    // addresses are stored checksummed
+    let hex_digits = address.strip_prefix("0x").unwrap_or_default();
+    if hex_digits.len() != 40 || !hex_digits.chars().all(|c| c.is_ascii_hexdigit()) {
+        return Err(ApiError::KeyNotFound(address.to_string()));
+    }
+    let address = eth_checksum::checksum(address);
+
+    let lookup = address.to_owned();
+    db::run(pool, move |conn| {
+        conn.get_owned_key_by_address(&address, tenant.as_str())
+            .map(key_res)
+    })
+    .await
+    .map(Json)
+    .map_err(|e| ApiError::key_lookup(&lookup, e))
+}
+
+/// Runs keygen for a reserved key and reports the outcome to `webhook_url`.
+pub async fn keygen_job(pool: DbPool, id: i32, webhook_url: Option<String>) {
+    let res = match keygen(&pool, id).await {
+        Ok(address) => {
+            println!("keygen for key {} finished: {}", id, address);
+            NewKeyRes {
+                success: true,
+                user_id: id.to_string(),
+                address: Some(address),
+                error: None,
+            }
+        }
+        Err(e) => {
+            println!("keygen for key {} failed: {}", id, e);
+            set_key_status(&pool, id, KeyStatus::Failed).await;
+            NewKeyRes {
+                success: false,
+                user_id: id.to_string(),
+                address: None,
+                error: Some(e.body()),
+            }
+        }
+    };
+
+    if let Some(webhook_url) = webhook_url {
+        if let Err(error) = webhook::post(&webhook_url, &res).await {
+            println!("error calling keygen webhook {}: {}", webhook_url, error);
+        }
+    }
+}
+
+async fn keygen(pool: &DbPool, id: i32) -> Result<String, ApiError> {
+    let client = &*HTTP_CLIENT;
+    let mut body = std::collections::HashMap::new();
+    body.insert("userId", id.to_string());
+    client
+        .post(format!("{}{}", *TX_SENDER_URL, "/new-key"))
+        .json(&body)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map_err(|e| ApiError::TxSenderUnreachable(e.to_string()))?;
+
+    db::run(pool, move |conn| {
+        conn.set_key_status(id, KeyStatus::Generating)
+    })
+    .await?;
+
+    let local_key = with_mpc_timeout(tss_sm_client::keygen(
+        sm_manager_url()?,
+        id.to_string(),
+        PARTY_INDEX,
+        1,
+        2,
+    ))
+    .await?;
+
+    let address = address::local_key_address(&local_key);
+
+    let key_address = address.to_owned();
+    let local_share = share::to_local_share(&local_key).map_err(ApiError::Internal)?;
+    let public_data = public_data(&local_key);
+    db::run(pool, move |conn| {
+        conn.fill_in_key_data(id, &key_address, &local_share, &public_data)
+    })
+    .await?;
+
+    Ok(address)
+}
+
+/// Records a status change the caller cannot act on if it fails.
+async fn set_key_status(pool: &DbPool, id: i32, new_status: KeyStatus) {
+    if let Err(e) = db::run(pool, move |conn| conn.set_key_status(id, new_status)).await {
+        println!("error moving key {} to {}: {}", id, new_status, e);
+    }
+}
+
+/// Fails keys whose keygen never finished, e.g. because the server restarted.
+pub async fn fail_stale_keys(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+
+        let older_than =
+            (chrono::Utc::now() - chrono::Duration::seconds(*KEY_PENDING_TIMEOUT_SECS)).naive_utc();
+        match db::run(&pool, move |conn| conn.fail_stale_keys(older_than)).await {
+            Ok(failed) => {
+                for key in failed {
+                    println!("keygen for key {} timed out", key.id);
+                }
+            }
+            Err(e) => println!("error failing stale keys: {}", e),
+        }
+    }
+}
+
+/// Stores the public fields of keys generated before they had columns. Their
+/// shares are opened once here, so that reading a key never does.
+pub async fn fill_in_public_data(pool: DbPool) {
+    let stored_keys = match db::run(&pool, |conn| conn.get_keys_without_public_data()).await {
+        Ok(stored_keys) => stored_keys,
+        Err(e) => {
+            println!("error getting keys without public data: {}", e);
+            return;
+        }
+    };
+
+    for key in stored_keys {
+        let key_id = key.id;
+        let local_key = match db::open_local_share(&key)
+            .map_err(|e| e.to_string())
+            .and_then(|local_share| share::parse_local_share(&local_share))
+        {
+            Ok(local_key) => local_key,
+            Err(e) => {
+                println!("error reading share of key {}: {}", key_id, e);
+                continue;
+            }
+        };
+        let public_data = public_data(&local_key);
+        if let Err(e) = db::run(&pool, move |conn| {
+            conn.set_key_public_data(key_id, &public_data)
+        })
+        .await
+        {
+            println!("error storing public data of key {}: {}", key_id, e);
+        }
+    }
+}
@@ -1,32 +1,46 @@
+pub mod auth;
 pub mod db;
+pub mod error;
+pub mod idempotency;
+pub mod keys;
+pub mod txs;
+pub mod webhook;
 
 #[macro_use]
 extern crate rocket;
-use crypto::digest::Digest;
-use crypto::sha3::Sha3;
+use auth::Tenant;
+use db::models::KeyStatus;
 use dotenv::dotenv;
-use lazy_static::__Deref;
-use reqwest::Client;
+use error::{ApiError, ErrorBody};
+use idempotency::IdempotencyKey;
 use rocket::serde::{json::Json, Deserialize, Serialize};
-use std::vec;
+use rocket::State;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use tss_common::{backup, config, envelope, share};
+use tss_sm_client::tx::Transaction;
 
 #[get("/")]
 fn index() -> &'static str {
     "Hello, world!"
 }
 
-#[derive(Deserialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct SendTxReq {
     from_address: String,
     tx_data: String,
+    /// When set, the signing hash is derived locally instead of trusting the tx sender
+    tx: Option<Transaction>,
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct SendTxRes {
     success: bool,
-    info: Option<String>,
+    /// Sign job id for `/txs/<id>`
+    id: i32,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -37,168 +51,324 @@ struct TxSenderRes {
     id: usize,
 }
 
+/// The client server's index in the 2-party keys it holds with share 2.
+const PARTY_INDEX: u16 = 2;
+
 lazy_static::lazy_static! {
     static ref PORT: String = std::env::var("PORT").expect("PORT should be set");
+    static ref HTTP_CLIENT: reqwest::Client = config::http_client();
     static ref TX_SENDER_URL: String = std::env::var("TX_SENDER_URL").expect("TX_SENDER_URL should be set");
     static ref SM_MANAGER_URL: String = std::env::var("SM_MANAGER_URL").expect("SM_MANAGER_URL should be set");
+    static ref KEY_PENDING_TIMEOUT_SECS: i64 = std::env::var("KEY_PENDING_TIMEOUT_SECS")
+        .map(|secs| secs.parse::<i64>().expect("KEY_PENDING_TIMEOUT_SECS should be a number"))
+        .unwrap_or(600);
+    static ref IDEMPOTENCY_CLAIM_TIMEOUT_SECS: i64 = std::env::var("IDEMPOTENCY_CLAIM_TIMEOUT_SECS")
+        .map(|secs| secs.parse::<i64>().expect("IDEMPOTENCY_CLAIM_TIMEOUT_SECS should be a number"))
+        .unwrap_or(300);
+    static ref IDEMPOTENCY_KEY_RETENTION_SECS: i64 = std::env::var("IDEMPOTENCY_KEY_RETENTION_SECS")
+        .map(|secs| secs.parse::<i64>().expect("IDEMPOTENCY_KEY_RETENTION_SECS should be a number"))
+        .unwrap_or(86400);
+    static ref MPC_TIMEOUT_SECS: u64 = std::env::var("MPC_TIMEOUT_SECS")
+        .map(|secs| secs.parse::<u64>().expect("MPC_TIMEOUT_SECS should be a number"))
+        .unwrap_or(120);
+    static ref WEBHOOK_ALLOWED_HOSTS: Option<Vec<String>> = std::env::var("WEBHOOK_ALLOWED_HOSTS")
+        .ok()
+        .map(|hosts| hosts.split(',').map(|host| host.trim().to_lowercase()).collect());
+    static ref SIGN_JOB_TIMEOUT_SECS: i64 = std::env::var("SIGN_JOB_TIMEOUT_SECS")
+        .map(|secs| secs.parse::<i64>().expect("SIGN_JOB_TIMEOUT_SECS should be a number"))
+        .unwrap_or(600);
+    static ref TX_SENDER_API_KEY: Option<String> = std::env::var("TX_SENDER_API_KEY").ok();
+    static ref API_KEYS: std::collections::HashMap<String, String> =
+        auth::parse_api_keys(&std::env::var("API_KEYS").expect("API_KEYS should be set"));
 }
 
-#[post("/send-tx", format = "json", data = "<send_tx_req>")]
-async fn send_tx(send_tx_req: Json<SendTxReq>) -> Json<SendTxRes> {
-    // talk to tx sender for simulation
-    let client = Client::new();
-    let mut body = std::collections::HashMap::new();
-    body.insert("from_address", &send_tx_req.from_address);
-    body.insert("tx_data", &send_tx_req.tx_data);
-    let call_tx_sender_result = client
-        .post(format!("{}{}", *TX_SENDER_URL, "/request-tx"))
-        .json(&body)
-        .send()
-        .await;
-
-    let res_text_result = match call_tx_sender_result {
-        Ok(res) => res.text().await,
-        Err(_) => {
-            return Json(SendTxRes {
-                success: false,
-                info: Some("fail to call tx sender".to_string()),
-            })
-        }
-    };
+fn sm_manager_url() -> Result<surf::Url, ApiError> {
+    surf::Url::parse(&SM_MANAGER_URL)
+        .map_err(|e| ApiError::Internal(format!("SM_MANAGER_URL is not valid: {}", e)))
+}
 
-    let res_parsed_result: Result<TxSenderRes, serde_json::Error> = match res_text_result {
-        Ok(res_text) => serde_json::from_str(&res_text),
-        Err(_) => {
-            return Json(SendTxRes {
-                success: false,
-                info: Some("fail to get tx sender response text".to_string()),
-            })
-        }
-    };
+/// Bounds a keygen or signing run with the other party by `MPC_TIMEOUT_SECS`.
+async fn with_mpc_timeout<T, E: std::fmt::Debug>(
+    protocol: impl std::future::Future<Output = Result<T, E>>,
+) -> Result<T, ApiError> {
+    match tokio::time::timeout(Duration::from_secs(*MPC_TIMEOUT_SECS), protocol).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(ApiError::PeerAbort(format!("{:?}", e))),
+        Err(_) => Err(ApiError::MpcTimeout),
+    }
+}
 
-    let tx_sender_res = match res_parsed_result {
-        Ok(tx_sender_res) => tx_sender_res,
-        Err(_) => {
-            return Json(SendTxRes {
-                success: false,
-                info: Some("fail on parsing tx sender response".to_string()),
-            })
+/// Starts signing in the background; poll `/txs/<id>` for its status and result.
+#[post("/send-tx", format = "json", data = "<send_tx_req>")]
+async fn send_tx(
+    tenant: Tenant,
+    idempotency_key: IdempotencyKey,
+    pool: &State<db::DbPool>,
+    send_tx_req: Json<SendTxReq>,
+) -> Result<Json<SendTxRes>, ApiError> {
+    let send_tx_req = send_tx_req.into_inner();
+    if let Some(key) = &idempotency_key.0 {
+        let claimed = idempotency::claim(pool, tenant.as_str(), "/send-tx", key, &send_tx_req);
+        if let Some(res) = claimed.await? {
+            return Ok(Json(res));
         }
-    };
+    }
 
-    println!("tx_sender_res.success: {}", tx_sender_res.success);
-    if tx_sender_res.success == false {
-        return Json(SendTxRes {
-            success: false,
-            info: Some("tx simulation failed".to_string()),
-        });
+    let res = start_sign_job(pool, tenant.as_str(), send_tx_req).await;
+    if let Some(key) = &idempotency_key.0 {
+        idempotency::complete(pool, tenant.as_str(), "/send-tx", key, res.as_ref().ok()).await;
     }
+    res.map(Json)
+}
 
-    // talk to SM
-    let db_conn = &mut db::establish_connection();
-    let local_share = db::get_local_share(db_conn, &send_tx_req.from_address)
-        .expect("cannot get local_share from db");
-
-    // TODO: implement timeout for this function
-    let sigature = match tss_sm_client::sign(
-        tx_sender_res.message_to_sign,
-        local_share.to_string(),
-        vec![1, 2],
-        surf::Url::parse(&SM_MANAGER_URL).unwrap(),
-        tx_sender_res.id.to_string(),
-    )
-    .await
-    {
-        Ok(result) => result,
-        Err(error) => format!("error in sign {:?}", error),
+async fn start_sign_job(
+    pool: &db::DbPool,
+    tenant: &str,
+    send_tx_req: SendTxReq,
+) -> Result<SendTxRes, ApiError> {
+    let owner = tenant.to_owned();
+    let from_address = send_tx_req.from_address.to_owned();
+    let tx_data = send_tx_req.tx_data.to_owned();
+    let tx_json = match &send_tx_req.tx {
+        Some(tx) => Some(
+            serde_json::to_string(tx)
+                .map_err(|e| ApiError::Internal(format!("error serializing tx: {}", e)))?,
+        ),
+        None => None,
     };
+    let job = db::run(pool, move |conn| {
+        conn.get_owned_key_by_address(&from_address, &owner)?;
+        conn.insert_sign_job(&owner, &from_address, &tx_data, tx_json.as_deref())
+    })
+    .await
+    .map_err(|e| ApiError::key_lookup(&send_tx_req.from_address, e))?;
+
+    tokio::task::spawn(txs::sign_job(pool.clone(), job.id, send_tx_req));
 
-    println!("signature: {}", sigature);
-    Json(SendTxRes {
+    Ok(SendTxRes {
         success: true,
-        info: None,
+        id: job.id,
     })
 }
 
-#[derive(Serialize)]
+#[derive(Serialize, Deserialize)]
 #[serde(crate = "rocket::serde")]
 struct NewKeyRes {
     success: bool,
     user_id: String,
     address: Option<String>,
-    info: Option<String>,
-}
-
-fn pubkey_to_address(uncompressed_pubkey: Vec<u8>) -> String {
-    let mut hasher = Sha3::keccak256();
-    hasher.input(&uncompressed_pubkey[1..]);
-    let hash_result = hasher.result_str();
-    let address = format!("0x{}", &hash_result[24..]);
-    address
-}
-
-#[post("/new-key")]
-async fn new_key() -> Json<NewKeyRes> {
-    let db_conn = &mut db::establish_connection();
-    let new_key_id = db::insert_new_key(db_conn);
-
-    let client = Client::new();
-    let mut body = std::collections::HashMap::new();
-    body.insert("userId", new_key_id.to_string());
-    let call_tx_sender_result = client
-        .post(format!("{}{}", *TX_SENDER_URL, "/new-key"))
-        .json(&body)
-        .send()
-        .await;
-
-    let _res = match call_tx_sender_result {
-        Ok(res) => res.text().await,
-        Err(_) => {
-            return Json(NewKeyRes {
-                success: false,
-                user_id: new_key_id.to_string(),
-                address: None,
-                info: Some("fail to call tx sender".to_string()),
-            })
+    /// Only set in the keygen webhook
+    error: Option<ErrorBody>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(crate = "rocket::serde")]
+struct NewKeyReq {
+    /// Receives the keygen outcome as a `NewKeyRes` once the job finishes
+    webhook_url: Option<String>,
+}
+
+/// Starts keygen in the background; poll `/keys/<user_id>` for its status.
+#[post("/new-key", data = "<new_key_req>")]
+async fn new_key(
+    tenant: Tenant,
+    idempotency_key: IdempotencyKey,
+    pool: &State<db::DbPool>,
+    new_key_req: Option<Json<NewKeyReq>>,
+) -> Result<Json<NewKeyRes>, ApiError> {
+    let new_key_req = new_key_req.map(Json::into_inner);
+    if let Some(key) = &idempotency_key.0 {
+        let claimed = idempotency::claim(pool, tenant.as_str(), "/new-key", key, &new_key_req);
+        if let Some(res) = claimed.await? {
+            return Ok(Json(res));
         }
-    };
+    }
 
-    let local_key = tss_sm_client::keygen(
-        surf::Url::parse(&SM_MANAGER_URL).unwrap(),
-        new_key_id.to_string(),
-        2,
-        1,
-        2,
-    )
-    .await
-    .expect("unwrap local key");
+    let res = start_keygen_job(pool, tenant.as_str(), new_key_req).await;
+    if let Some(key) = &idempotency_key.0 {
+        idempotency::complete(pool, tenant.as_str(), "/new-key", key, res.as_ref().ok()).await;
+    }
+    res.map(Json)
+}
 
-    let address = pubkey_to_address(local_key.y_sum_s.to_bytes(false).deref().to_vec());
-    let address = eth_checksum::checksum(&address);
+async fn start_keygen_job(
+    pool: &db::DbPool,
+    tenant: &str,
+    new_key_req: Option<NewKeyReq>,
+) -> Result<NewKeyRes, ApiError> {
+    let webhook_url = new_key_req.and_then(|req| req.webhook_url);
+    if let Some(webhook_url) = &webhook_url {
+        webhook::validate(webhook_url)?;
+    }
 
-    db::fill_in_key_data(
-        db_conn,
-        new_key_id,
-        &address,
-        &serde_json::to_string(&local_key).expect("error parsing local_key"),
-    );
+    let owner = tenant.to_owned();
+    let new_key_id = db::run(pool, move |conn| conn.insert_new_key(&owner)).await?;
 
-    Json(NewKeyRes {
+    tokio::task::spawn(keys::keygen_job(pool.clone(), new_key_id, webhook_url));
+
+    Ok(NewKeyRes {
         success: true,
         user_id: new_key_id.to_string(),
-        address: Some(address),
-        info: None,
+        address: None,
+        error: None,
     })
 }
 
+#[derive(StructOpt)]
+struct Cli {
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(StructOpt)]
+enum Cmd {
+    /// Re-wrap stored shares under MASTER_KEY after rotating from PREVIOUS_MASTER_KEY
+    RewrapKeys,
+    /// Export the local share of a key as a password-encrypted backup file
+    ExportKey {
+        #[structopt(long)]
+        address: String,
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+        /// Read the password from this file instead of env BACKUP_PASSWORD
+        #[structopt(long, parse(from_os_str))]
+        password_file: Option<PathBuf>,
+    },
+    /// Revoke a key so it can no longer sign
+    RevokeKey {
+        #[structopt(long)]
+        address: String,
+    },
+    /// Assign a key to the tenant allowed to use it through the API
+    SetKeyOwner {
+        #[structopt(long)]
+        address: String,
+        #[structopt(long)]
+        owner: String,
+    },
+    /// Restore a local share from a backup file written by export-key
+    ImportKey {
+        #[structopt(long, parse(from_os_str))]
+        file: PathBuf,
+        /// Read the password from this file instead of env BACKUP_PASSWORD
+        #[structopt(long, parse(from_os_str))]
+        password_file: Option<PathBuf>,
+    },
+}
+
+fn run_command(cmd: Cmd) -> Result<(), Box<dyn std::error::Error>> {
+    let db_conn = &mut *db::establish_connection();
+    match cmd {
+        Cmd::RewrapKeys => {
+            let previous = envelope::MasterKey::from_env("PREVIOUS_MASTER_KEY");
+            let updated = db_conn.rewrap_local_shares(previous.as_ref())?;
+            println!(
+                "re-wrapped {} shares under master key {}",
+                updated,
+                config::MASTER_KEY.id
+            );
+        }
+        Cmd::ExportKey {
+            address,
+            out,
+            password_file,
+        } => {
+            let password = backup::read_password(password_file)?;
+            let key = db_conn.get_key_by_address(&address)?;
+            let local_share = db::open_local_share(&key)?;
+            let share_backup = backup::export(&local_share, key.id, &key.address, &password)?;
+            std::fs::write(&out, serde_json::to_string_pretty(&share_backup)?)?;
+            println!(
+                "exported key {} ({}) to {}",
+                key.id,
+                key.address,
+                out.display()
+            );
+        }
+        Cmd::RevokeKey { address } => {
+            let key = db_conn.get_key_by_address(&address)?;
+            db_conn.set_key_status(key.id, KeyStatus::Revoked)?;
+            println!("revoked key {} ({})", key.id, key.address);
+        }
+        Cmd::SetKeyOwner { address, owner } => {
+            let key = db_conn.get_key_by_address(&address)?;
+            db_conn.set_key_owner(key.id, &owner)?;
+            println!("key {} ({}) now belongs to {}", key.id, key.address, owner);
+        }
+        Cmd::ImportKey {
+            file,
+            password_file,
+        } => {
+            let password = backup::read_password(password_file)?;
+            let share_backup =
+                serde_json::from_str::<backup::ShareBackup>(&std::fs::read_to_string(&file)?)?;
+            let local_share = backup::import(&share_backup, &password)?;
+            let metadata = &share_backup.metadata;
+            if metadata.party_index != PARTY_INDEX {
+                return Err(format!(
+                    "backup is a share of party {}, the client server is party {}",
+                    metadata.party_index, PARTY_INDEX
+                )
+                .into());
+            }
+            match db_conn.get_key_by_address(&metadata.address) {
+                Err(diesel::result::Error::NotFound) => {}
+                Ok(_) => {
+                    return Err(format!("a key for {} already exists", metadata.address).into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+            let public_data = keys::public_data(&share::parse_local_share(&local_share)?);
+            db_conn.insert_key(
+                metadata.key_id,
+                &metadata.address,
+                &local_share,
+                &public_data,
+            )?;
+            println!("imported key {} ({})", metadata.key_id, metadata.address);
+        }
+    }
+    Ok(())
+}
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     dotenv().ok();
+    lazy_static::initialize(&config::MASTER_KEY);
+
+    if let Some(cmd) = Cli::from_args().cmd {
+        return run_command(cmd);
+    }
+
+    lazy_static::initialize(&API_KEYS);
+    // a job still running its signing round must not be failed under it
+    if *SIGN_JOB_TIMEOUT_SECS <= *MPC_TIMEOUT_SECS as i64 {
+        panic!("SIGN_JOB_TIMEOUT_SECS should be greater than MPC_TIMEOUT_SECS");
+    }
+    let pool = db::establish_pool()?;
     let figment = rocket::Config::figment()
         .merge(("port", PORT.parse::<u16>().unwrap()))
         .merge(("address", "0.0.0.0"));
-    let _rocket_instance = rocket::custom(figment)
-        .mount("/", routes![index, send_tx, new_key])
+    tokio::task::spawn(keys::fill_in_public_data(pool.clone()));
+    tokio::task::spawn(keys::fail_stale_keys(pool.clone()));
+    tokio::task::spawn(txs::fail_stale_sign_jobs(pool.clone()));
+    tokio::task::spawn(idempotency::purge_idempotency_keys(pool.clone()));
+    let _rocket_instance = rocket::custom(config::with_tls(figment))
+        .manage(pool)
+        .register("/", error::catchers())
+        .mount(
+            "/",
+            routes![
+                index,
+                send_tx,
+                new_key,
+                keys::list_keys,
+                keys::get_key,
+                keys::get_key_by_address,
+                txs::get_tx,
+                txs::tx_submitted
+            ],
+        )
         .launch()
         .await?;
     Ok(())
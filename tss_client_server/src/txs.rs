@@ -0,0 +1,247 @@
+//! Background sign jobs and their status and result polling.
+//!
+//! `/send-tx` only records the request; the job moves through simulating →
+//! signing → signed → submitted, or to failed with the reason in `error`.
+//! Share 2 submits its signature to the tx sender, which confirms the
+//! submission on `/txs/submitted`. Jobs left in simulating or signing for
+//! `SIGN_JOB_TIMEOUT_SECS`, e.g. by a restart, are failed.
+
+use chrono::NaiveDateTime;
+use diesel::result::Error::NotFound;
+use rocket::serde::{json::Json, Deserialize, Serialize};
+use rocket::State;
+use std::collections::HashMap;
+use std::time::Duration;
+use tss_sm_client::tx::{keccak256, Signature};
+
+use crate::auth::{Tenant, TxSender};
+use crate::db::models::{SignJob, SignJobStatus, SignJobUpdate};
+use crate::db::{self, DbError, DbPool};
+use crate::error::{ApiError, ErrorBody};
+use crate::{
+    sm_manager_url, with_mpc_timeout, SendTxReq, TxSenderRes, HTTP_CLIENT, SIGN_JOB_TIMEOUT_SECS,
+    TX_SENDER_URL,
+};
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct TxRes {
+    id: i32,
+    status: String,
+    from_address: String,
+    message_to_sign: Option<String>,
+    signature: Option<String>,
+    /// Set when the request carried a structured `tx`
+    raw_tx: Option<String>,
+    tx_hash: Option<String>,
+    /// Why the job failed
+    error: Option<ErrorBody>,
+    created_at: NaiveDateTime,
+    updated_at: NaiveDateTime,
+}
+
+#[get("/txs/<id>")]
+pub(crate) async fn get_tx(
+    tenant: Tenant,
+    pool: &State<DbPool>,
+    id: i32,
+) -> Result<Json<TxRes>, ApiError> {
+    let job = db::run(pool, move |conn| {
+        let job = conn.get_sign_job(id)?;
+        if job.owner.as_deref() != Some(tenant.as_str()) {
+            return Err(NotFound);
+        }
+        Ok(job)
+    })
+    .await
+    .map_err(|e| match e {
+        DbError::Query(NotFound) => ApiError::SignJobNotFound(id.to_string()),
+        e => ApiError::Database(e),
+    })?;
+
+    Ok(Json(tx_res(job)))
+}
+
+#[derive(Deserialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SubmittedReq {
+    /// The id the tx sender answered `/request-tx` with
+    id: i64,
+    tx_hash: Option<String>,
+}
+
+/// Called by the tx sender once it submitted a signed tx.
+#[post("/txs/submitted", data = "<submitted_req>")]
+pub(crate) async fn tx_submitted(
+    _tx_sender: TxSender,
+    pool: &State<DbPool>,
+    submitted_req: Json<SubmittedReq>,
+) -> Result<Json<TxRes>, ApiError> {
+    let SubmittedReq { id, tx_hash } = submitted_req.into_inner();
+    let job = db::run(pool, move |conn| {
+        conn.set_sign_job_submitted(id, tx_hash.as_deref())
+    })
+    .await
+    .map_err(|e| match e {
+        DbError::Query(NotFound) => {
+            ApiError::SignJobNotFound(format!("signed for tx sender id {}", id))
+        }
+        e => ApiError::Database(e),
+    })?;
+    println!("sign job {} submitted", job.id);
+
+    Ok(Json(tx_res(job)))
+}
+
+fn tx_res(job: SignJob) -> TxRes {
+    TxRes {
+        id: job.id,
+        status: job.status,
+        from_address: job.from_address,
+        message_to_sign: job.message_to_sign,
+        signature: job.signature,
+        raw_tx: job.raw_tx,
+        tx_hash: job.tx_hash,
+        error: job.error.map(|message| ErrorBody {
+            // jobs failed before error codes were recorded
+            code: job
+                .error_code
+                .unwrap_or_else(|| "internal_error".to_string()),
+            message,
+        }),
+        created_at: job.created_at,
+        updated_at: job.updated_at,
+    }
+}
+
+/// Runs simulation and signing for a recorded sign job.
+pub(crate) async fn sign_job(pool: DbPool, id: i32, send_tx_req: SendTxReq) {
+    match sign(&pool, id, &send_tx_req).await {
+        Ok(()) => println!("sign job {} signed", id),
+        Err(e) => {
+            println!("sign job {} failed: {}", id, e);
+            let update = SignJobUpdate {
+                error: Some(e.to_string()),
+                error_code: Some(e.code().to_string()),
+                ..Default::default()
+            };
+            if let Err(e) = db::run(&pool, move |conn| {
+                conn.set_sign_job_status(id, SignJobStatus::Failed, update)
+            })
+            .await
+            {
+                println!("error moving sign job {} to failed: {}", id, e);
+            }
+        }
+    }
+}
+
+/// Fails sign jobs whose simulation or signing never finished, e.g. because
+/// the server restarted.
+pub async fn fail_stale_sign_jobs(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+
+        let older_than =
+            (chrono::Utc::now() - chrono::Duration::seconds(*SIGN_JOB_TIMEOUT_SECS)).naive_utc();
+        let error = ApiError::SignJobTimedOut;
+        let (error_data, error_code) = (error.to_string(), error.code());
+        match db::run(&pool, move |conn| {
+            conn.fail_stale_sign_jobs(older_than, &error_data, error_code)
+        })
+        .await
+        {
+            Ok(failed) => {
+                for job in failed {
+                    println!("sign job {} timed out", job.id);
+                }
+            }
+            Err(e) => println!("error failing stale sign jobs: {}", e),
+        }
+    }
+}
+
+async fn sign(pool: &DbPool, id: i32, send_tx_req: &SendTxReq) -> Result<(), ApiError> {
+    // talk to tx sender for simulation
+    let client = &*HTTP_CLIENT;
+    let mut body = HashMap::new();
+    body.insert("from_address", &send_tx_req.from_address);
+    body.insert("tx_data", &send_tx_req.tx_data);
+    let res_text = client
+        .post(format!("{}{}", *TX_SENDER_URL, "/request-tx"))
+        .json(&body)
+        .send()
+        .await
+        .map_err(|e| ApiError::TxSenderUnreachable(e.to_string()))?
+        .text()
+        .await
+        .map_err(|e| ApiError::TxSenderInvalidResponse(e.to_string()))?;
+    let tx_sender_res: TxSenderRes = serde_json::from_str(&res_text)
+        .map_err(|e| ApiError::TxSenderInvalidResponse(e.to_string()))?;
+
+    println!("tx_sender_res.success: {}", tx_sender_res.success);
+    if !tx_sender_res.success {
+        return Err(ApiError::SimulationFailed);
+    }
+
+    let message_to_sign = match &send_tx_req.tx {
+        Some(tx) => {
+            let signing_hash = hex::encode(tx.signing_hash());
+            if tx_sender_res
+                .message_to_sign
+                .trim_start_matches("0x")
+                .to_lowercase()
+                != signing_hash
+            {
+                return Err(ApiError::MessageMismatch);
+            }
+            signing_hash
+        }
+        None => tx_sender_res.message_to_sign,
+    };
+
+    let update = SignJobUpdate {
+        tx_sender_id: Some(tx_sender_res.id as i64),
+        message_to_sign: Some(message_to_sign.to_owned()),
+        ..Default::default()
+    };
+    db::run(pool, move |conn| {
+        conn.set_sign_job_status(id, SignJobStatus::Signing, update)
+    })
+    .await?;
+
+    // talk to SM
+    let from_address = send_tx_req.from_address.to_owned();
+    let local_share = db::run(pool, move |conn| conn.get_local_share(&from_address))
+        .await
+        .map_err(|e| ApiError::key_lookup(&send_tx_req.from_address, e))?;
+
+    let signature = with_mpc_timeout(tss_sm_client::sign(
+        message_to_sign,
+        local_share,
+        vec![1, 2],
+        sm_manager_url()?,
+        tx_sender_res.id.to_string(),
+    ))
+    .await?;
+
+    println!("signature: {}", signature);
+    let mut update = SignJobUpdate {
+        signature: Some(signature.to_owned()),
+        ..Default::default()
+    };
+    if let Some(tx) = &send_tx_req.tx {
+        let tx_signature = Signature::from_sign_output(&signature)
+            .map_err(|e| ApiError::Internal(format!("error assembling signed tx {:?}", e)))?;
+        let raw_tx = tx.encode_signed(&tx_signature);
+        update.raw_tx = Some(format!("0x{}", hex::encode(&raw_tx)));
+        update.tx_hash = Some(format!("0x{}", hex::encode(keccak256(&raw_tx))));
+    }
+    db::run(pool, move |conn| {
+        conn.set_sign_job_status(id, SignJobStatus::Signed, update)
+    })
+    .await?;
+
+    Ok(())
+}
@@ -0,0 +1,151 @@
+//! Keygen webhooks, posted to urls chosen by the tenant.
+//!
+//! A webhook url has to be https, and its host one of `WEBHOOK_ALLOWED_HOSTS`
+//! if set. Hosts resolving to loopback, private, link-local or other
+//! non-public addresses are refused, both on `/new-key` and again right
+//! before the post, which goes to the address checked then. Webhooks are
+//! posted without the client certificate and without following redirects.
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use reqwest::Url;
+use rocket::serde::Serialize;
+use tss_common::config;
+
+use crate::error::ApiError;
+use crate::WEBHOOK_ALLOWED_HOSTS;
+
+/// Checks a webhook url without resolving its host.
+pub fn validate(webhook_url: &str) -> Result<Url, ApiError> {
+    let invalid = |reason: &str| ApiError::InvalidRequest(format!("webhook_url {}", reason));
+
+    let url = Url::parse(webhook_url).map_err(|e| invalid(&format!("is not valid: {}", e)))?;
+    if url.scheme() != "https" {
+        return Err(invalid("should be https"));
+    }
+    let host = url
+        .host_str()
+        .ok_or_else(|| invalid("should have a host"))?
+        .to_lowercase();
+    if let Some(allowed_hosts) = WEBHOOK_ALLOWED_HOSTS.as_ref() {
+        if !allowed_hosts.contains(&host) {
+            return Err(invalid("host is not allowed"));
+        }
+    }
+    if let Ok(ip) = host
+        .trim_matches(|c| c == '[' || c == ']')
+        .parse::<IpAddr>()
+    {
+        if !is_public(ip) {
+            return Err(invalid("should not point to a non-public address"));
+        }
+    }
+    Ok(url)
+}
+
+/// Posts `body` to `webhook_url`, pinned to an address checked to be public.
+pub async fn post<T: Serialize>(webhook_url: &str, body: &T) -> Result<(), String> {
+    let url = validate(webhook_url).map_err(|e| e.to_string())?;
+    let host = url.host_str().unwrap_or_default().to_owned();
+    let port = url.port_or_known_default().unwrap_or(443);
+
+    let addrs = tokio::net::lookup_host((host.trim_matches(|c| c == '[' || c == ']'), port))
+        .await
+        .map_err(|e| format!("cannot resolve {}: {}", host, e))?
+        .collect::<Vec<SocketAddr>>();
+    if addrs.is_empty() || addrs.iter().any(|addr| !is_public(addr.ip())) {
+        return Err(format!(
+            "{} does not resolve to public addresses only",
+            host
+        ));
+    }
+
+    // a client of its own, so the post never presents TLS_CLIENT_CERT and
+    // cannot be resolved to another address than the one checked
+    let client = reqwest::Client::builder()
+        .timeout(config::http_timeout())
+        .redirect(reqwest::redirect::Policy::none())
+        .resolve(&host, addrs[0])
+        .build()
+        .map_err(|e| format!("error building webhook client: {}", e))?;
+    client
+        .post(url)
+        .json(body)
+        .send()
+        .await
+        .and_then(|res| res.error_for_status())
+        .map(|_| ())
+        .map_err(|e| e.to_string())
+}
+
+fn is_public(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_v4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_v4(ip),
+            None => is_public_v6(ip),
+        },
+    }
+}
+
+fn is_public_v4(ip: Ipv4Addr) -> bool {
+    let [a, b, ..] = ip.octets();
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_broadcast()
+        || ip.is_documentation()
+        || ip.is_multicast()
+        // 0.0.0.0/8, 100.64.0.0/10 (shared) and 240.0.0.0/4 (reserved)
+        || a == 0
+        || (a == 100 && (64..128).contains(&b))
+        || a >= 240)
+}
+
+fn is_public_v6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_unspecified()
+        || ip.is_loopback()
+        || ip.is_multicast()
+        // fc00::/7 (unique local) and fe80::/10 (link-local)
+        || (first & 0xfe00) == 0xfc00
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn refuses_non_public_addresses() {
+        for ip in [
+            "127.0.0.1",
+            "10.1.2.3",
+            "172.16.0.1",
+            "192.168.1.1",
+            "169.254.169.254",
+            "100.64.0.1",
+            "0.0.0.0",
+            "::1",
+            "fd00::1",
+            "fe80::1",
+            "::ffff:127.0.0.1",
+        ] {
+            assert!(!is_public(ip.parse().unwrap()), "{}", ip);
+        }
+        for ip in ["93.184.216.34", "2606:2800:220:1::1"] {
+            assert!(is_public(ip.parse().unwrap()), "{}", ip);
+        }
+    }
+
+    #[test]
+    fn validates_scheme_and_host() {
+        assert!(validate("https://example.com/hook").is_ok());
+        assert!(validate("http://example.com/hook").is_err());
+        assert!(validate("https://127.0.0.1/hook").is_err());
+        assert!(validate("https://[::1]/hook").is_err());
+        assert!(validate("file:///etc/passwd").is_err());
+        assert!(validate("not a url").is_err());
+    }
+}
@@ -0,0 +1,57 @@
+//! Ethereum addresses of keys.
+
+use std::ops::Deref;
+
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use tss_sm_client::{LocalKey, Secp256k1};
+
+/// Lowercase `0x` address of a 65-byte uncompressed secp256k1 public key.
+pub fn pubkey_to_address(uncompressed_pubkey: &[u8]) -> String {
+    let mut hasher = Sha3::keccak256();
+    hasher.input(&uncompressed_pubkey[1..]);
+    let hash_result = hasher.result_str();
+    format!("0x{}", &hash_result[24..])
+}
+
+/// EIP-55 checksummed address of a 65-byte uncompressed public key.
+pub fn checksummed_address(uncompressed_pubkey: &[u8]) -> String {
+    eth_checksum::checksum(&pubkey_to_address(uncompressed_pubkey))
+}
+
+/// Checksummed address of the key a local share belongs to.
+pub fn local_key_address(local_key: &LocalKey<Secp256k1>) -> String {
+    checksummed_address(local_key.y_sum_s.to_bytes(false).deref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Public keys of the private keys 1 and 2, i.e. the generator G and 2G.
+    const PUBKEY_1: &str = "0479be667ef9dcbbac55a06295ce870b07029bfcdb2dce28d959f2815b16f81798483ada7726a3c4655da4fbfc0e1108a8fd17b448a68554199c47d08ffb10d4b8";
+    const PUBKEY_2: &str = "04c6047f9441ed7d6d3045406e95c07cd85c778e4b8cef3ca7abac09b95c709ee51ae168fea63dc339a3c58419466ceaeef7f632653266d0e1236431a950cfe52a";
+
+    #[test]
+    fn derives_lowercase_address() {
+        let pubkey = hex::decode(PUBKEY_1).unwrap();
+        assert_eq!(
+            pubkey_to_address(&pubkey),
+            "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf"
+        );
+    }
+
+    #[test]
+    fn derives_checksummed_address() {
+        let pubkey_1 = hex::decode(PUBKEY_1).unwrap();
+        let pubkey_2 = hex::decode(PUBKEY_2).unwrap();
+        assert_eq!(
+            checksummed_address(&pubkey_1),
+            "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf"
+        );
+        assert_eq!(
+            checksummed_address(&pubkey_2),
+            "0x2B5AD5c4795c026514f8317c7a215E218DcCD6cF"
+        );
+    }
+}
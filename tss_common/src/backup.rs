@@ -0,0 +1,283 @@
+//! Password-encrypted export and import of a single local share.
+//!
+//! The share is encrypted with AES-256-GCM under a key derived from the
+//! password with argon2id. The metadata is authenticated as associated data,
+//! so a backup file cannot be relabelled to another key.
+
+use std::path::PathBuf;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use argon2::{Algorithm, Argon2, Params, Version};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use serde::{Deserialize, Serialize};
+
+use crate::address::local_key_address;
+use crate::share::parse_local_share;
+
+const BACKUP_VERSION: u32 = 1;
+
+/// Upper bounds on the argon2 parameters of an imported backup, so that a
+/// crafted file cannot make import allocate or compute without limit. Export
+/// writes argon2's defaults, well below them.
+const MAX_M_COST: u32 = 256 * 1024;
+const MAX_T_COST: u32 = 16;
+const MAX_P_COST: u32 = 8;
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct BackupMetadata {
+    pub address: String,
+    pub key_id: i32,
+    pub threshold: u16,
+    pub parties: u16,
+    pub party_index: u16,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub salt: String,
+    pub m_cost: u32,
+    pub t_cost: u32,
+    pub p_cost: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ShareBackup {
+    pub version: u32,
+    pub metadata: BackupMetadata,
+    pub kdf: KdfParams,
+    pub nonce: String,
+    pub ciphertext: String,
+}
+
+/// Reads the backup password from `password_file`, or from env BACKUP_PASSWORD.
+pub fn read_password(password_file: Option<PathBuf>) -> Result<Vec<u8>, String> {
+    let password = match password_file {
+        Some(path) => std::fs::read_to_string(&path)
+            .map_err(|e| format!("cannot read password file {}: {}", path.display(), e))?
+            .trim_end_matches(&['\r', '\n'][..])
+            .to_string(),
+        None => std::env::var("BACKUP_PASSWORD")
+            .map_err(|_| "BACKUP_PASSWORD or --password-file should be set".to_string())?,
+    };
+    if password.is_empty() {
+        return Err("backup password is empty".to_string());
+    }
+    Ok(password.into_bytes())
+}
+
+pub fn export(
+    local_share: &str,
+    key_id: i32,
+    address: &str,
+    password: &[u8],
+) -> Result<ShareBackup, String> {
+    let local_key = parse_local_share(local_share)?;
+    if !local_key_address(&local_key).eq_ignore_ascii_case(address) {
+        return Err(format!("local share does not belong to {}", address));
+    }
+
+    let metadata = BackupMetadata {
+        address: address.to_string(),
+        key_id,
+        threshold: local_key.t,
+        parties: local_key.n,
+        party_index: local_key.i,
+    };
+
+    let mut salt = [0u8; 16];
+    OsRng.fill_bytes(&mut salt);
+    let kdf = KdfParams {
+        algorithm: "argon2id".to_string(),
+        salt: BASE64.encode(salt),
+        m_cost: Params::DEFAULT_M_COST,
+        t_cost: Params::DEFAULT_T_COST,
+        p_cost: Params::DEFAULT_P_COST,
+    };
+
+    seal(local_share, metadata, kdf, password)
+}
+
+/// Decrypts a backup and checks the share against its metadata.
+pub fn import(backup: &ShareBackup, password: &[u8]) -> Result<String, String> {
+    let local_share = open(backup, password)?;
+
+    let local_key = parse_local_share(&local_share)?;
+    let metadata = &backup.metadata;
+    if (local_key.t, local_key.n, local_key.i)
+        != (metadata.threshold, metadata.parties, metadata.party_index)
+    {
+        return Err("local share parameters do not match the backup metadata".to_string());
+    }
+    if !local_key_address(&local_key).eq_ignore_ascii_case(&metadata.address) {
+        return Err(format!(
+            "local share does not belong to {}",
+            metadata.address
+        ));
+    }
+
+    Ok(local_share)
+}
+
+fn seal(
+    local_share: &str,
+    metadata: BackupMetadata,
+    kdf: KdfParams,
+    password: &[u8],
+) -> Result<ShareBackup, String> {
+    let key = derive_key(&kdf, password)?;
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(&key)
+        .encrypt(
+            &nonce,
+            Payload {
+                msg: local_share.as_bytes(),
+                aad: &metadata_aad(&metadata)?,
+            },
+        )
+        .map_err(|_| "error encrypting local share".to_string())?;
+
+    Ok(ShareBackup {
+        version: BACKUP_VERSION,
+        metadata,
+        kdf,
+        nonce: BASE64.encode(nonce),
+        ciphertext: BASE64.encode(ciphertext),
+    })
+}
+
+/// Decrypts a backup without looking at the share it holds.
+fn open(backup: &ShareBackup, password: &[u8]) -> Result<String, String> {
+    if backup.version != BACKUP_VERSION {
+        return Err(format!("unsupported backup version {}", backup.version));
+    }
+    let kdf = &backup.kdf;
+    if kdf.m_cost > MAX_M_COST || kdf.t_cost > MAX_T_COST || kdf.p_cost > MAX_P_COST {
+        return Err(format!(
+            "kdf params exceed m_cost {}, t_cost {} or p_cost {}",
+            MAX_M_COST, MAX_T_COST, MAX_P_COST
+        ));
+    }
+
+    let key = derive_key(kdf, password)?;
+    let nonce = BASE64
+        .decode(&backup.nonce)
+        .map_err(|e| format!("nonce is not base64: {}", e))?;
+    if nonce.len() != 12 {
+        return Err("nonce has the wrong length".to_string());
+    }
+    let ciphertext = BASE64
+        .decode(&backup.ciphertext)
+        .map_err(|e| format!("ciphertext is not base64: {}", e))?;
+    let local_share = Aes256Gcm::new(&key)
+        .decrypt(
+            Nonce::from_slice(&nonce),
+            Payload {
+                msg: &ciphertext,
+                aad: &metadata_aad(&backup.metadata)?,
+            },
+        )
+        .map_err(|_| "wrong password or corrupted backup".to_string())?;
+    String::from_utf8(local_share).map_err(|e| format!("local share is not utf-8: {}", e))
+}
+
+fn metadata_aad(metadata: &BackupMetadata) -> Result<Vec<u8>, String> {
+    serde_json::to_vec(metadata).map_err(|e| format!("error serializing metadata: {}", e))
+}
+
+fn derive_key(kdf: &KdfParams, password: &[u8]) -> Result<Key<Aes256Gcm>, String> {
+    if kdf.algorithm != "argon2id" {
+        return Err(format!("unsupported kdf {}", kdf.algorithm));
+    }
+    let salt = BASE64
+        .decode(&kdf.salt)
+        .map_err(|e| format!("salt is not base64: {}", e))?;
+    let params = Params::new(kdf.m_cost, kdf.t_cost, kdf.p_cost, Some(32))
+        .map_err(|e| format!("invalid kdf params: {}", e))?;
+
+    let mut key = Key::<Aes256Gcm>::default();
+    Argon2::new(Algorithm::Argon2id, Version::V0x13, params)
+        .hash_password_into(password, &salt, &mut key)
+        .map_err(|e| format!("error deriving key: {}", e))?;
+    Ok(key)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &[u8] = b"correct horse battery staple";
+    const LOCAL_SHARE: &str = r#"{"i":1,"t":1,"n":2}"#;
+
+    fn metadata() -> BackupMetadata {
+        BackupMetadata {
+            address: "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".to_string(),
+            key_id: 7,
+            threshold: 1,
+            parties: 2,
+            party_index: 1,
+        }
+    }
+
+    /// Cheap argon2 params, so that the tests do not spend seconds in the kdf.
+    fn kdf() -> KdfParams {
+        KdfParams {
+            algorithm: "argon2id".to_string(),
+            salt: BASE64.encode([1u8; 16]),
+            m_cost: 64,
+            t_cost: 1,
+            p_cost: 1,
+        }
+    }
+
+    #[test]
+    fn opens_sealed_backup() {
+        let backup = seal(LOCAL_SHARE, metadata(), kdf(), PASSWORD).unwrap();
+        let json = serde_json::to_string(&backup).unwrap();
+        let backup: ShareBackup = serde_json::from_str(&json).unwrap();
+        assert_eq!(backup.metadata, metadata());
+        assert_eq!(open(&backup, PASSWORD).unwrap(), LOCAL_SHARE);
+    }
+
+    #[test]
+    fn refuses_wrong_password() {
+        let backup = seal(LOCAL_SHARE, metadata(), kdf(), PASSWORD).unwrap();
+        assert_eq!(
+            open(&backup, b"wrong password").unwrap_err(),
+            "wrong password or corrupted backup"
+        );
+    }
+
+    #[test]
+    fn refuses_tampered_metadata() {
+        let mut backup = seal(LOCAL_SHARE, metadata(), kdf(), PASSWORD).unwrap();
+        backup.metadata.key_id = 8;
+        assert_eq!(
+            open(&backup, PASSWORD).unwrap_err(),
+            "wrong password or corrupted backup"
+        );
+
+        let mut backup = seal(LOCAL_SHARE, metadata(), kdf(), PASSWORD).unwrap();
+        backup.metadata.party_index = 2;
+        assert!(open(&backup, PASSWORD).is_err());
+    }
+
+    #[test]
+    fn refuses_kdf_params_above_limits() {
+        let limits = [
+            (MAX_M_COST + 1, 1, 1),
+            (64, MAX_T_COST + 1, 1),
+            (64, 1, MAX_P_COST + 1),
+        ];
+        for (m_cost, t_cost, p_cost) in limits {
+            let mut backup = seal(LOCAL_SHARE, metadata(), kdf(), PASSWORD).unwrap();
+            backup.kdf.m_cost = m_cost;
+            backup.kdf.t_cost = t_cost;
+            backup.kdf.p_cost = p_cost;
+            let err = open(&backup, PASSWORD).unwrap_err();
+            assert!(err.starts_with("kdf params exceed"), "{}", err);
+        }
+    }
+}
@@ -0,0 +1,77 @@
+//! Settings both servers read from the environment.
+
+use std::env;
+use std::time::Duration;
+
+use dotenv::dotenv;
+use lazy_static::lazy_static;
+use tss_sm_client::tls::ClientTls;
+
+use crate::envelope::MasterKey;
+
+lazy_static! {
+    pub static ref MASTER_KEY: MasterKey =
+        MasterKey::from_env("MASTER_KEY").expect("MASTER_KEY or MASTER_KEY_FILE should be set");
+}
+
+pub enum DatabaseUrl {
+    Postgres(String),
+    Sqlite(String),
+}
+
+/// `postgres://...` or `postgresql://...` selects Postgres, `sqlite://<path>`
+/// selects SQLite.
+pub fn database_url() -> DatabaseUrl {
+    dotenv().ok();
+
+    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
+    if database_url.starts_with("postgres://") || database_url.starts_with("postgresql://") {
+        DatabaseUrl::Postgres(database_url)
+    } else if let Some(path) = database_url.strip_prefix("sqlite://") {
+        DatabaseUrl::Sqlite(path.to_string())
+    } else {
+        panic!("unsupported DATABASE_URL scheme: {}", database_url)
+    }
+}
+
+/// `DATABASE_POOL_SIZE`, 10 by default.
+pub fn database_pool_size() -> u32 {
+    match env::var("DATABASE_POOL_SIZE") {
+        Ok(size) => size
+            .parse::<u32>()
+            .expect("DATABASE_POOL_SIZE should be a number"),
+        Err(_) => 10,
+    }
+}
+
+/// `HTTP_TIMEOUT_SECS`, 30 by default.
+pub fn http_timeout() -> Duration {
+    match env::var("HTTP_TIMEOUT_SECS") {
+        Ok(secs) => Duration::from_secs(
+            secs.parse::<u64>()
+                .expect("HTTP_TIMEOUT_SECS should be a number"),
+        ),
+        Err(_) => Duration::from_secs(30),
+    }
+}
+
+/// Presents the client certificate from `TLS_CLIENT_CERT` to the tx sender.
+/// Requests give up after `http_timeout`.
+pub fn http_client() -> reqwest::Client {
+    let mut builder = reqwest::Client::builder().timeout(http_timeout());
+    if let Some(tls) = ClientTls::from_env().expect("client TLS config should be valid") {
+        builder = builder.identity(
+            reqwest::Identity::from_pkcs8_pem(&tls.cert, &tls.key)
+                .expect("TLS_CLIENT_CERT and TLS_CLIENT_KEY should be PEM"),
+        );
+        if let Some(ca_cert) = &tls.ca_cert {
+            builder = builder.add_root_certificate(
+                reqwest::Certificate::from_pem(ca_cert).expect("TLS_CA_CERT should be PEM"),
+            );
+        }
+    }
+    builder.build().expect("error building http client")
+}
+
+/// Shared with the sm manager, which does not depend on this crate.
+pub use tss_sm_client::tls::with_tls;
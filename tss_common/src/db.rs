@@ -0,0 +1,230 @@
+//! Database setup shared by both servers. Each server runs its own queries
+//! through a `Storage` trait implemented for both connection types; this
+//! module connects, pools and migrates, and seals the shares of their keys.
+
+pub mod models;
+
+use diesel::connection::SimpleConnection;
+use diesel::pg::PgConnection;
+use diesel::prelude::*;
+use diesel::r2d2::{ConnectionManager, CustomizeConnection, Pool, PoolError};
+use diesel::result::DatabaseErrorKind::CheckViolation;
+use diesel::result::Error::{DatabaseError, DeserializationError};
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::{EmbeddedMigrations, MigrationHarness};
+use std::fmt;
+
+use self::models::{Key, KeyStatus};
+use crate::config::{database_pool_size, database_url, DatabaseUrl, MASTER_KEY};
+use crate::envelope::{self, MasterKey, SealedShare};
+
+pub enum DbConnection {
+    Postgres(PgConnection),
+    Sqlite(SqliteConnection),
+}
+
+/// A pooled connection lent to the closure passed to `run`.
+pub enum ConnectionMut<'a> {
+    Postgres(&'a mut PgConnection),
+    Sqlite(&'a mut SqliteConnection),
+}
+
+#[derive(Clone)]
+pub enum DbPool {
+    Postgres(Pool<ConnectionManager<PgConnection>>),
+    Sqlite(Pool<ConnectionManager<SqliteConnection>>),
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Pool(PoolError),
+    Query(diesel::result::Error),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Pool(e) => write!(f, "cannot get database connection: {}", e),
+            DbError::Query(e) => write!(f, "database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for DbError {}
+
+/// Waits on locks instead of failing with `SQLITE_BUSY` when pooled
+/// connections write concurrently.
+#[derive(Debug)]
+struct SqliteSetup;
+
+impl CustomizeConnection<SqliteConnection, diesel::r2d2::Error> for SqliteSetup {
+    fn on_acquire(&self, conn: &mut SqliteConnection) -> Result<(), diesel::r2d2::Error> {
+        conn.batch_execute("PRAGMA journal_mode = WAL; PRAGMA busy_timeout = 5000;")
+            .map_err(diesel::r2d2::Error::QueryError)
+    }
+}
+
+/// Connects to `DATABASE_URL`. SQLite has no migration tooling in production,
+/// so `sqlite_migrations` are applied on connect; Postgres migrations are run
+/// with the diesel CLI.
+pub fn establish_connection(sqlite_migrations: EmbeddedMigrations) -> DbConnection {
+    match database_url() {
+        DatabaseUrl::Postgres(database_url) => DbConnection::Postgres(
+            PgConnection::establish(&database_url)
+                .unwrap_or_else(|_| panic!("Error connecting to {}", database_url)),
+        ),
+        DatabaseUrl::Sqlite(path) => {
+            let mut conn = SqliteConnection::establish(&path)
+                .unwrap_or_else(|_| panic!("Error connecting to {}", path));
+            SqliteSetup
+                .on_acquire(&mut conn)
+                .unwrap_or_else(|e| panic!("Error setting up {}: {}", path, e));
+            conn.run_pending_migrations(sqlite_migrations)
+                .unwrap_or_else(|e| panic!("Error migrating {}: {}", path, e));
+            DbConnection::Sqlite(conn)
+        }
+    }
+}
+
+/// Builds the connection pool shared by every request, sized by
+/// `DATABASE_POOL_SIZE`.
+pub fn establish_pool(
+    sqlite_migrations: EmbeddedMigrations,
+) -> Result<DbPool, Box<dyn std::error::Error>> {
+    let pool_size = database_pool_size();
+
+    match database_url() {
+        DatabaseUrl::Postgres(database_url) => Ok(DbPool::Postgres(
+            Pool::builder()
+                .max_size(pool_size)
+                .build(ConnectionManager::new(database_url))?,
+        )),
+        DatabaseUrl::Sqlite(path) => {
+            let pool = Pool::builder()
+                .max_size(pool_size)
+                .connection_customizer(Box::new(SqliteSetup))
+                .build(ConnectionManager::new(path.to_owned()))?;
+            pool.get()?
+                .run_pending_migrations(sqlite_migrations)
+                .map_err(|e| format!("error migrating {}: {}", path, e))?;
+            Ok(DbPool::Sqlite(pool))
+        }
+    }
+}
+
+/// Runs `f` with a pooled connection on the blocking thread pool, so queries
+/// never block the async runtime.
+pub async fn run<T, F>(pool: &DbPool, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(ConnectionMut) -> QueryResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    let pool = pool.clone();
+    tokio::task::spawn_blocking(move || match pool {
+        DbPool::Postgres(pool) => {
+            let mut conn = pool.get().map_err(DbError::Pool)?;
+            f(ConnectionMut::Postgres(&mut conn)).map_err(DbError::Query)
+        }
+        DbPool::Sqlite(pool) => {
+            let mut conn = pool.get().map_err(DbError::Pool)?;
+            f(ConnectionMut::Sqlite(&mut conn)).map_err(DbError::Query)
+        }
+    })
+    .await
+    .expect("database task panicked")
+}
+
+/// Binds a sealed share to its row of `table`, so shares cannot be swapped.
+fn share_aad(table: &str, id: i32) -> Vec<u8> {
+    format!("{}/{}", table, id).into_bytes()
+}
+
+fn crypto_error(error: String) -> diesel::result::Error {
+    DeserializationError(error.into())
+}
+
+/// Seals the share of key `id` in `table` under `MASTER_KEY`.
+pub fn seal_local_share(table: &str, id: i32, local_share: &str) -> SealedShare {
+    envelope::seal(&MASTER_KEY, local_share, &share_aad(table, id))
+}
+
+pub fn open_local_share(table: &str, key: &Key) -> QueryResult<String> {
+    match (&key.data_key, &key.master_key_id) {
+        (Some(wrapped_key), Some(wrapped_by)) => {
+            if *wrapped_by != MASTER_KEY.id {
+                return Err(crypto_error(format!(
+                    "share {} is wrapped by master key {}, not {}",
+                    key.id, wrapped_by, MASTER_KEY.id
+                )));
+            }
+            envelope::open(
+                &MASTER_KEY,
+                &key.local_share,
+                wrapped_key,
+                &share_aad(table, key.id),
+            )
+            .map_err(crypto_error)
+        }
+        // written before encryption at rest, sealed by `rewrap-keys`
+        _ => Ok(key.local_share.to_owned()),
+    }
+}
+
+/// Only active keys may sign.
+pub fn open_active_local_share(table: &str, key: &Key) -> QueryResult<String> {
+    if key.status != KeyStatus::Active.as_str() {
+        return Err(DatabaseError(
+            CheckViolation,
+            Box::new(format!("key {} is {}", key.address, key.status)),
+        ));
+    }
+    open_local_share(table, key)
+}
+
+/// The share of `key` sealed under `MASTER_KEY`, or `None` if it already is.
+/// A data key wrapped by `previous` is re-wrapped, a share still stored in
+/// plaintext is sealed.
+pub fn reseal_local_share(
+    table: &str,
+    key: &Key,
+    previous: Option<&MasterKey>,
+) -> QueryResult<Option<SealedShare>> {
+    match (&key.data_key, &key.master_key_id) {
+        (Some(_), Some(wrapped_by)) if *wrapped_by == MASTER_KEY.id => Ok(None),
+        (Some(wrapped_key), Some(wrapped_by)) => {
+            let previous = previous.filter(|p| p.id == *wrapped_by).ok_or_else(|| {
+                crypto_error(format!(
+                    "share {} is wrapped by unknown master key {}",
+                    key.id, wrapped_by
+                ))
+            })?;
+            Ok(Some(SealedShare {
+                local_share: key.local_share.to_owned(),
+                data_key: MASTER_KEY
+                    .rewrap(previous, wrapped_key)
+                    .map_err(crypto_error)?,
+                master_key_id: MASTER_KEY.id.to_owned(),
+            }))
+        }
+        _ => Ok(Some(seal_local_share(table, key.id, &key.local_share))),
+    }
+}
+
+/// Statuses a key may move to `new_status` from, for filtering updates.
+pub fn allowed_from(new_status: KeyStatus) -> Vec<&'static str> {
+    new_status
+        .allowed_from()
+        .iter()
+        .map(KeyStatus::as_str)
+        .collect()
+}
+
+pub fn invalid_transition(key: &Key, new_status: KeyStatus) -> diesel::result::Error {
+    DatabaseError(
+        CheckViolation,
+        Box::new(format!(
+            "key {} cannot move from {} to {}",
+            key.id, key.status, new_status
+        )),
+    )
+}
@@ -0,0 +1,60 @@
+use chrono::NaiveDateTime;
+use diesel::prelude::*;
+
+/// A row of the client server's `keys` or share 2's `share2_keys`. The client
+/// server's `owner` column is not part of it.
+#[derive(Queryable, Debug)]
+pub struct Key {
+    pub id: i32,
+    pub address: String,
+    pub local_share: String,
+    pub data_key: Option<Vec<u8>>,
+    pub master_key_id: Option<String>,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub updated_at: NaiveDateTime,
+}
+
+/// Lifecycle of a key; transitions are enforced by `Storage::set_key_status`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyStatus {
+    /// Row reserved, keygen not started
+    Pending,
+    Generating,
+    Active,
+    Failed,
+    Revoked,
+    /// Replaced by a refreshed share of the same key
+    Refreshed,
+}
+
+impl KeyStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            KeyStatus::Pending => "pending",
+            KeyStatus::Generating => "generating",
+            KeyStatus::Active => "active",
+            KeyStatus::Failed => "failed",
+            KeyStatus::Revoked => "revoked",
+            KeyStatus::Refreshed => "refreshed",
+        }
+    }
+
+    /// States a key may move to this state from.
+    pub fn allowed_from(&self) -> &'static [KeyStatus] {
+        match self {
+            KeyStatus::Pending => &[],
+            KeyStatus::Generating => &[KeyStatus::Pending],
+            KeyStatus::Active => &[KeyStatus::Generating],
+            KeyStatus::Failed => &[KeyStatus::Pending, KeyStatus::Generating],
+            KeyStatus::Revoked => &[KeyStatus::Active],
+            KeyStatus::Refreshed => &[KeyStatus::Active],
+        }
+    }
+}
+
+impl std::fmt::Display for KeyStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
@@ -0,0 +1,169 @@
+//! Envelope encryption of local shares.
+//!
+//! Every share is encrypted with its own random data key (AES-256-GCM); the
+//! data key is stored next to it, wrapped by the master key. Rotating the
+//! master key only re-wraps data keys, shares are never re-encrypted.
+
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+
+const NONCE_LEN: usize = 12;
+
+pub struct MasterKey {
+    /// Fingerprint stored with every data key it wraps.
+    pub id: String,
+    key: Key<Aes256Gcm>,
+}
+
+/// A share encrypted under a fresh data key.
+pub struct SealedShare {
+    /// base64 of nonce || ciphertext
+    pub local_share: String,
+    /// nonce || data key encrypted under the master key
+    pub data_key: Vec<u8>,
+    pub master_key_id: String,
+}
+
+impl MasterKey {
+    pub fn from_hex(key_hex: &str) -> Result<MasterKey, String> {
+        let key =
+            hex::decode(key_hex.trim()).map_err(|e| format!("master key is not hex: {}", e))?;
+        if key.len() != 32 {
+            return Err(format!("master key must be 32 bytes, got {}", key.len()));
+        }
+
+        let mut hasher = Sha3::keccak256();
+        hasher.input(&key);
+        let id = hasher.result_str()[..16].to_string();
+
+        Ok(MasterKey {
+            id,
+            key: *Key::<Aes256Gcm>::from_slice(&key),
+        })
+    }
+
+    /// Loads the hex key from the file at `{var}_FILE`, or from `{var}` itself.
+    pub fn from_env(var: &str) -> Option<MasterKey> {
+        let key_hex = match std::env::var(format!("{}_FILE", var)) {
+            Ok(path) => std::fs::read_to_string(&path)
+                .unwrap_or_else(|e| panic!("cannot read {}_FILE {}: {}", var, path, e)),
+            Err(_) => std::env::var(var).ok()?,
+        };
+        Some(MasterKey::from_hex(&key_hex).unwrap_or_else(|e| panic!("invalid {}: {}", var, e)))
+    }
+
+    fn wrap(&self, data_key: &Key<Aes256Gcm>) -> Vec<u8> {
+        encrypt(&self.key, data_key.as_slice(), self.id.as_bytes())
+    }
+
+    fn unwrap(&self, wrapped: &[u8]) -> Result<Key<Aes256Gcm>, String> {
+        let data_key = decrypt(&self.key, wrapped, self.id.as_bytes())
+            .map_err(|_| "cannot unwrap data key".to_string())?;
+        if data_key.len() != 32 {
+            return Err("unwrapped data key has the wrong length".to_string());
+        }
+        Ok(*Key::<Aes256Gcm>::from_slice(&data_key))
+    }
+
+    /// Re-wraps a data key wrapped by `old` under this master key.
+    pub fn rewrap(&self, old: &MasterKey, wrapped: &[u8]) -> Result<Vec<u8>, String> {
+        Ok(self.wrap(&old.unwrap(wrapped)?))
+    }
+}
+
+/// `aad` binds the ciphertext to its row, so shares cannot be swapped.
+pub fn seal(master_key: &MasterKey, local_share: &str, aad: &[u8]) -> SealedShare {
+    let data_key = Aes256Gcm::generate_key(&mut OsRng);
+    SealedShare {
+        local_share: BASE64.encode(encrypt(&data_key, local_share.as_bytes(), aad)),
+        data_key: master_key.wrap(&data_key),
+        master_key_id: master_key.id.to_owned(),
+    }
+}
+
+pub fn open(
+    master_key: &MasterKey,
+    sealed_share: &str,
+    data_key: &[u8],
+    aad: &[u8],
+) -> Result<String, String> {
+    let data_key = master_key.unwrap(data_key)?;
+    let sealed_share = BASE64
+        .decode(sealed_share)
+        .map_err(|e| format!("sealed share is not base64: {}", e))?;
+    let local_share = decrypt(&data_key, &sealed_share, aad)
+        .map_err(|_| "cannot decrypt local share".to_string())?;
+    String::from_utf8(local_share).map_err(|e| format!("local share is not utf-8: {}", e))
+}
+
+fn encrypt(key: &Key<Aes256Gcm>, msg: &[u8], aad: &[u8]) -> Vec<u8> {
+    let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+    let ciphertext = Aes256Gcm::new(key)
+        .encrypt(&nonce, Payload { msg, aad })
+        .expect("AES-GCM encryption failed");
+    [nonce.as_slice(), &ciphertext].concat()
+}
+
+fn decrypt(key: &Key<Aes256Gcm>, sealed: &[u8], aad: &[u8]) -> Result<Vec<u8>, aes_gcm::Error> {
+    if sealed.len() < NONCE_LEN {
+        return Err(aes_gcm::Error);
+    }
+    let (nonce, ciphertext) = sealed.split_at(NONCE_LEN);
+    Aes256Gcm::new(key).decrypt(
+        Nonce::from_slice(nonce),
+        Payload {
+            msg: ciphertext,
+            aad,
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASTER_KEY_1: &str = "0101010101010101010101010101010101010101010101010101010101010101";
+    const MASTER_KEY_2: &str = "0202020202020202020202020202020202020202020202020202020202020202";
+    const LOCAL_SHARE: &str = r#"{"i":1,"t":1,"n":2}"#;
+    const AAD: &[u8] = b"key 7";
+
+    #[test]
+    fn opens_sealed_share() {
+        let master_key = MasterKey::from_hex(MASTER_KEY_1).unwrap();
+        let sealed = seal(&master_key, LOCAL_SHARE, AAD);
+        assert_eq!(sealed.master_key_id, master_key.id);
+        assert_eq!(
+            open(&master_key, &sealed.local_share, &sealed.data_key, AAD).unwrap(),
+            LOCAL_SHARE
+        );
+        assert!(open(&master_key, &sealed.local_share, &sealed.data_key, b"key 8").is_err());
+    }
+
+    #[test]
+    fn refuses_wrong_master_key() {
+        let master_key_1 = MasterKey::from_hex(MASTER_KEY_1).unwrap();
+        let master_key_2 = MasterKey::from_hex(MASTER_KEY_2).unwrap();
+        let sealed = seal(&master_key_1, LOCAL_SHARE, AAD);
+        assert_eq!(
+            open(&master_key_2, &sealed.local_share, &sealed.data_key, AAD).unwrap_err(),
+            "cannot unwrap data key"
+        );
+    }
+
+    #[test]
+    fn rewraps_data_key_under_new_master_key() {
+        let old = MasterKey::from_hex(MASTER_KEY_1).unwrap();
+        let new = MasterKey::from_hex(MASTER_KEY_2).unwrap();
+        let sealed = seal(&old, LOCAL_SHARE, AAD);
+
+        let data_key = new.rewrap(&old, &sealed.data_key).unwrap();
+        assert_eq!(
+            open(&new, &sealed.local_share, &data_key, AAD).unwrap(),
+            LOCAL_SHARE
+        );
+        assert!(open(&old, &sealed.local_share, &data_key, AAD).is_err());
+    }
+}
@@ -0,0 +1,10 @@
+//! Wallet logic shared by `tss_client_server` and `tss_share_2_server`: address
+//! derivation, local share (de)serialization, sealing and backup, the database
+//! setup with the key model, and the settings both read from the environment.
+
+pub mod address;
+pub mod backup;
+pub mod config;
+pub mod db;
+pub mod envelope;
+pub mod share;
@@ -0,0 +1,13 @@
+//! Local shares are stored, signed with and backed up as the JSON of the
+//! `LocalKey` keygen returns.
+
+use tss_sm_client::{LocalKey, Secp256k1};
+
+pub fn to_local_share(local_key: &LocalKey<Secp256k1>) -> Result<String, String> {
+    serde_json::to_string(local_key).map_err(|e| format!("error serializing local share: {}", e))
+}
+
+pub fn parse_local_share(local_share: &str) -> Result<LocalKey<Secp256k1>, String> {
+    serde_json::from_str::<LocalKey<Secp256k1>>(local_share)
+        .map_err(|e| format!("error parsing local share: {}", e))
+}
@@ -0,0 +1,302 @@
+//! Messages between the tx sender and share 2.
+//!
+//! Signals arrive on the RabbitMQ queues, or through share 2's HTTP signal
+//! API, in an [`Envelope`]:
+//!
+//! ```json
+//! {"version": 1, "requestId": "...", "signal": {...}}
+//! ```
+//!
+//! where `signal` is a [`KeygenSignal`] or a [`SignSignal`]. Messages without
+//! `version` are read in the original format, `{"data": "...", "requestId":
+//! "..."}`, whose `data` is the bare keygen id or the sign signal as a JSON
+//! string. A version newer than [`VERSION`] is refused rather than guessed at;
+//! fields added within a version are ignored by readers that predate them.
+//!
+//! Share 2 answers with a [`SignalResult`], and reports it to the tx sender as
+//! a [`SubmitTx`], [`RejectTx`] or [`PendingTx`].
+
+mod result;
+mod signal;
+
+use std::fmt;
+
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+
+pub use result::{PendingTx, RejectTx, SignalResult, SubmitTx};
+pub use signal::{KeygenSignal, SignSignal};
+
+/// Envelope version written by this crate.
+pub const VERSION: u32 = 1;
+
+#[derive(Debug)]
+pub enum MessageError {
+    /// Not JSON, or not the expected fields
+    Malformed(String),
+    UnsupportedVersion(u32),
+    /// Well-formed, but not a signal share 2 can act on
+    Invalid(String),
+}
+
+impl fmt::Display for MessageError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MessageError::Malformed(e) => write!(f, "malformed message: {}", e),
+            MessageError::UnsupportedVersion(version) => write!(
+                f,
+                "unsupported message version {}, expected at most {}",
+                version, VERSION
+            ),
+            MessageError::Invalid(e) => write!(f, "invalid signal: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for MessageError {}
+
+/// A signal that can be carried in an [`Envelope`].
+pub trait Signal: Serialize + DeserializeOwned {
+    /// Reads the `data` string of an unversioned message.
+    fn from_legacy(data: &str) -> Result<Self, MessageError>;
+
+    fn validate(&self) -> Result<(), MessageError>;
+}
+
+#[derive(Clone, Debug)]
+pub struct Envelope<T> {
+    /// 0 for unversioned messages
+    pub version: u32,
+    pub request_id: String,
+    pub signal: T,
+}
+
+/// Fields of every envelope version, to tell them apart.
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RawEnvelope {
+    version: Option<u32>,
+    request_id: String,
+    data: Option<String>,
+    signal: Option<serde_json::Value>,
+}
+
+impl<T: Signal> Envelope<T> {
+    pub fn new(request_id: impl Into<String>, signal: T) -> Self {
+        Envelope {
+            version: VERSION,
+            request_id: request_id.into(),
+            signal,
+        }
+    }
+
+    /// Reads and validates a message of any supported version.
+    pub fn decode(data: &[u8]) -> Result<Self, MessageError> {
+        let raw = serde_json::from_slice::<RawEnvelope>(data)
+            .map_err(|e| MessageError::Malformed(e.to_string()))?;
+        let signal = match (raw.version, raw.data, raw.signal) {
+            (None, Some(data), _) => T::from_legacy(&data)?,
+            (None, None, _) => return Err(MessageError::Malformed("missing field `data`".into())),
+            (Some(VERSION), _, Some(signal)) => serde_json::from_value(signal)
+                .map_err(|e| MessageError::Malformed(e.to_string()))?,
+            (Some(VERSION), _, None) => {
+                return Err(MessageError::Malformed("missing field `signal`".into()))
+            }
+            (Some(version), ..) => return Err(MessageError::UnsupportedVersion(version)),
+        };
+
+        if raw.request_id.is_empty() {
+            return Err(MessageError::Invalid("requestId is empty".into()));
+        }
+        signal.validate()?;
+        Ok(Envelope {
+            version: raw.version.unwrap_or(0),
+            request_id: raw.request_id,
+            signal,
+        })
+    }
+
+    /// Writes the message as the current version.
+    pub fn encode(&self) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "version": VERSION,
+            "requestId": self.request_id,
+            "signal": self.signal,
+        }))
+        .expect("error serializing envelope")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const ADDRESS: &str = "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf";
+    const MESSAGE: &str = "0xdaf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53";
+
+    fn sign_signal_json(from_address: &str, message: &str) -> serde_json::Value {
+        serde_json::json!({
+            "from_address": from_address,
+            "id": 4,
+            "message": message,
+            "tx": {
+                "type": "legacy",
+                "chain_id": "0x1",
+                "nonce": "0x9",
+                "gas_price": "0x4a817c800",
+                "gas_limit": "0x5208",
+                "to": "0x3535353535353535353535353535353535353535",
+                "value": "0xde0b6b3a7640000"
+            }
+        })
+    }
+
+    fn keygen_v1(threshold: u16, parties: u16, party_index: u16) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "version": 1,
+            "requestId": "r1",
+            "signal": {
+                "id": 12,
+                "threshold": threshold,
+                "parties": parties,
+                "party_index": party_index
+            }
+        }))
+        .unwrap()
+    }
+
+    fn sign_v1(from_address: &str, message: &str) -> Vec<u8> {
+        serde_json::to_vec(&serde_json::json!({
+            "version": 1,
+            "requestId": "r2",
+            "signal": sign_signal_json(from_address, message)
+        }))
+        .unwrap()
+    }
+
+    #[test]
+    fn decodes_legacy_keygen() {
+        let message =
+            Envelope::<KeygenSignal>::decode(br#"{"data": "12", "requestId": "r1"}"#).unwrap();
+        assert_eq!(message.version, 0);
+        assert_eq!(message.request_id, "r1");
+        assert_eq!(message.signal, KeygenSignal::two_party(12));
+    }
+
+    #[test]
+    fn refuses_legacy_keygen_without_id() {
+        let result = Envelope::<KeygenSignal>::decode(br#"{"data": "abc", "requestId": "r1"}"#);
+        assert!(matches!(result, Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn decodes_legacy_sign() {
+        let data = sign_signal_json(ADDRESS, MESSAGE).to_string();
+        let raw = serde_json::json!({"data": data, "requestId": "r2"}).to_string();
+        let message = Envelope::<SignSignal>::decode(raw.as_bytes()).unwrap();
+        assert_eq!(message.version, 0);
+        assert_eq!(message.request_id, "r2");
+        assert_eq!(message.signal.id, 4);
+        assert_eq!(message.signal.from_address, ADDRESS);
+        assert_eq!(message.signal.tx.value(), 1_000_000_000_000_000_000);
+    }
+
+    #[test]
+    fn refuses_legacy_message_without_data() {
+        let result = Envelope::<KeygenSignal>::decode(br#"{"requestId": "r1"}"#);
+        assert!(matches!(result, Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn decodes_v1_keygen() {
+        let message = Envelope::<KeygenSignal>::decode(&keygen_v1(1, 2, 1)).unwrap();
+        assert_eq!(message.version, 1);
+        assert_eq!(message.request_id, "r1");
+        assert_eq!(message.signal, KeygenSignal::two_party(12));
+    }
+
+    #[test]
+    fn decodes_v1_sign() {
+        let message = Envelope::<SignSignal>::decode(&sign_v1(ADDRESS, MESSAGE)).unwrap();
+        assert_eq!(message.version, 1);
+        assert_eq!(message.request_id, "r2");
+        assert_eq!(message.signal.message, MESSAGE);
+    }
+
+    #[test]
+    fn ignores_unknown_fields() {
+        let raw = br#"{"version": 1, "requestId": "r1", "priority": 3,
+            "signal": {"id": 12, "threshold": 1, "parties": 2, "party_index": 1, "label": "x"}}"#;
+        assert!(Envelope::<KeygenSignal>::decode(raw).is_ok());
+    }
+
+    #[test]
+    fn round_trips_encoded_messages() {
+        let message = Envelope::new("r1", KeygenSignal::two_party(12));
+        let decoded = Envelope::<KeygenSignal>::decode(&message.encode()).unwrap();
+        assert_eq!(decoded.version, VERSION);
+        assert_eq!(decoded.request_id, "r1");
+        assert_eq!(decoded.signal, message.signal);
+    }
+
+    #[test]
+    fn refuses_unknown_version() {
+        let raw = br#"{"version": 2, "requestId": "r1", "signal": {"id": 12}}"#;
+        let result = Envelope::<KeygenSignal>::decode(raw);
+        assert!(matches!(result, Err(MessageError::UnsupportedVersion(2))));
+    }
+
+    #[test]
+    fn refuses_v1_without_signal() {
+        let result = Envelope::<KeygenSignal>::decode(br#"{"version": 1, "requestId": "r1"}"#);
+        assert!(matches!(result, Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn refuses_non_json() {
+        let result = Envelope::<KeygenSignal>::decode(b"12");
+        assert!(matches!(result, Err(MessageError::Malformed(_))));
+    }
+
+    #[test]
+    fn refuses_empty_request_id() {
+        let result = Envelope::<KeygenSignal>::decode(br#"{"data": "12", "requestId": ""}"#);
+        assert!(matches!(result, Err(MessageError::Invalid(_))));
+    }
+
+    #[test]
+    fn refuses_invalid_keygen_parameters() {
+        // (threshold, parties, party_index)
+        for (threshold, parties, party_index) in [
+            (0, 1, 1), // fewer than 2 parties
+            (0, 2, 1), // threshold 0
+            (2, 2, 1), // threshold not below parties
+            (1, 2, 0), // party index 0
+            (1, 2, 3), // party index above parties
+            (1, 3, 1), // not 2-of-2
+            (1, 2, 2), // not party 1
+        ] {
+            let result =
+                Envelope::<KeygenSignal>::decode(&keygen_v1(threshold, parties, party_index));
+            assert!(
+                matches!(result, Err(MessageError::Invalid(_))),
+                "{}-of-{} as party {} was accepted",
+                threshold + 1,
+                parties,
+                party_index
+            );
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_from_address() {
+        let result = Envelope::<SignSignal>::decode(&sign_v1("0x7e5f", MESSAGE));
+        assert!(matches!(result, Err(MessageError::Invalid(_))));
+    }
+
+    #[test]
+    fn refuses_invalid_message() {
+        let result = Envelope::<SignSignal>::decode(&sign_v1(ADDRESS, "0xdaf5"));
+        assert!(matches!(result, Err(MessageError::Invalid(_))));
+    }
+}
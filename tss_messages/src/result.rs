@@ -0,0 +1,110 @@
+use std::fmt::Display;
+
+use chrono::NaiveDateTime;
+use serde::{Deserialize, Serialize, Serializer};
+
+/// What share 2 did with a signal. Stored with the handled request and
+/// returned by the HTTP signal API.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+#[serde(tag = "outcome", rename_all = "snake_case")]
+pub enum SignalResult {
+    Submitted {
+        id: usize,
+        signature: String,
+    },
+    Rejected {
+        id: usize,
+        reason: String,
+    },
+    PendingApproval {
+        id: usize,
+        reason: String,
+        expires_at: NaiveDateTime,
+    },
+    KeyGenerated {
+        id: i32,
+        address: String,
+    },
+}
+
+/// Body of the tx sender's `/submit-tx`.
+#[derive(Serialize, Clone, Debug)]
+pub struct SubmitTx {
+    #[serde(serialize_with = "as_string")]
+    pub id: usize,
+    pub signature: String,
+}
+
+/// Body of the tx sender's `/reject-tx`.
+#[derive(Serialize, Clone, Debug)]
+pub struct RejectTx {
+    #[serde(serialize_with = "as_string")]
+    pub id: usize,
+    pub reason: String,
+}
+
+/// Body of the tx sender's `/pending-tx`.
+#[derive(Serialize, Clone, Debug)]
+pub struct PendingTx {
+    #[serde(serialize_with = "as_string")]
+    pub id: usize,
+    pub reason: String,
+    #[serde(serialize_with = "as_string")]
+    pub expires_at: NaiveDateTime,
+}
+
+/// For fields the tx sender has always received as strings.
+fn as_string<T: Display, S: Serializer>(value: &T, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.collect_str(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_ids_and_deadlines_as_strings() {
+        let submit = SubmitTx {
+            id: 4,
+            signature: "0xsig".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&submit).unwrap(),
+            r#"{"id":"4","signature":"0xsig"}"#
+        );
+
+        let reject = RejectTx {
+            id: 4,
+            reason: "r".to_string(),
+        };
+        assert_eq!(
+            serde_json::to_string(&reject).unwrap(),
+            r#"{"id":"4","reason":"r"}"#
+        );
+
+        let pending = PendingTx {
+            id: 4,
+            reason: "r".to_string(),
+            expires_at: NaiveDateTime::parse_from_str("2026-10-19 05:00:00", "%Y-%m-%d %H:%M:%S")
+                .unwrap(),
+        };
+        assert_eq!(
+            serde_json::to_string(&pending).unwrap(),
+            r#"{"id":"4","reason":"r","expires_at":"2026-10-19 05:00:00"}"#
+        );
+    }
+
+    #[test]
+    fn round_trips_signal_results() {
+        let result = SignalResult::KeyGenerated {
+            id: 12,
+            address: "0x7E5F4552091A69125d5DfCb7b8C2659029395Bdf".to_string(),
+        };
+        let json = serde_json::to_string(&result).unwrap();
+        assert!(json.contains(r#""outcome":"key_generated""#));
+        assert!(matches!(
+            serde_json::from_str::<SignalResult>(&json).unwrap(),
+            SignalResult::KeyGenerated { id: 12, .. }
+        ));
+    }
+}
@@ -0,0 +1,105 @@
+use serde::{Deserialize, Serialize};
+use tss_sm_client::tx::{Address, Transaction, H256};
+
+use crate::{MessageError, Signal};
+
+/// Asks share 2 to join the keygen room of key `id`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct KeygenSignal {
+    pub id: i32,
+    /// Signing takes `threshold + 1` of the parties
+    pub threshold: u16,
+    pub parties: u16,
+    /// Share 2's index among the parties, from 1
+    pub party_index: u16,
+}
+
+impl KeygenSignal {
+    /// Share 2 as party 1 of the 2-of-2 key it shares with the client server.
+    pub fn two_party(id: i32) -> Self {
+        KeygenSignal {
+            id,
+            threshold: 1,
+            parties: 2,
+            party_index: 1,
+        }
+    }
+}
+
+impl Signal for KeygenSignal {
+    /// Unversioned keygen signals carry the key id alone.
+    fn from_legacy(data: &str) -> Result<Self, MessageError> {
+        let id = data
+            .parse::<i32>()
+            .map_err(|e| MessageError::Malformed(format!("error parsing id {}: {}", data, e)))?;
+        Ok(KeygenSignal::two_party(id))
+    }
+
+    fn validate(&self) -> Result<(), MessageError> {
+        if self.parties < 2 {
+            return Err(MessageError::Invalid(format!(
+                "keygen {} needs at least 2 parties, got {}",
+                self.id, self.parties
+            )));
+        }
+        if self.threshold == 0 || self.threshold >= self.parties {
+            return Err(MessageError::Invalid(format!(
+                "keygen {} threshold {} should be from 1 to {}",
+                self.id,
+                self.threshold,
+                self.parties - 1
+            )));
+        }
+        if self.party_index == 0 || self.party_index > self.parties {
+            return Err(MessageError::Invalid(format!(
+                "keygen {} party index {} should be from 1 to {}",
+                self.id, self.party_index, self.parties
+            )));
+        }
+        // share 2 neither stores the parameters nor signs with other parties yet
+        if *self != KeygenSignal::two_party(self.id) {
+            return Err(MessageError::Invalid(format!(
+                "keygen {} is {}-of-{} as party {}, only 2-of-2 keys as party 1 are supported",
+                self.id,
+                self.threshold + 1,
+                self.parties,
+                self.party_index
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Asks share 2 to sign `tx` with the key of `from_address`.
+#[derive(Serialize, Deserialize, Clone, Debug)]
+pub struct SignSignal {
+    pub from_address: String,
+    pub id: usize,
+    /// Signing hash of `tx` as hex, checked against `tx` before signing
+    pub message: String,
+    pub tx: Transaction,
+}
+
+impl Signal for SignSignal {
+    /// Unversioned sign signals carry this struct as a JSON string.
+    fn from_legacy(data: &str) -> Result<Self, MessageError> {
+        serde_json::from_str(data)
+            .map_err(|e| MessageError::Malformed(format!("error parsing sign signal: {}", e)))
+    }
+
+    fn validate(&self) -> Result<(), MessageError> {
+        self.from_address.parse::<Address>().map_err(|e| {
+            MessageError::Invalid(format!(
+                "sign signal {} from_address {}: {}",
+                self.id, self.from_address, e
+            ))
+        })?;
+        self.message.parse::<H256>().map_err(|e| {
+            MessageError::Invalid(format!(
+                "sign signal {} message {}: {}",
+                self.id, self.message, e
+            ))
+        })?;
+        Ok(())
+    }
+}
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE pending_approvals (
+    id INTEGER PRIMARY KEY,
+    from_address VARCHAR NOT NULL,
+    sign_signal TEXT NOT NULL,
+    reason VARCHAR NOT NULL,
+    status VARCHAR NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    decided_at TIMESTAMP
+)
@@ -0,0 +1,4 @@
+-- Your SQL goes here
+ALTER TABLE share2_keys
+    ADD COLUMN data_key BYTEA,
+    ADD COLUMN master_key_id VARCHAR
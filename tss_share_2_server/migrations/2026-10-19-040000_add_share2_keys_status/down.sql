@@ -0,0 +1,5 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE share2_keys
+    DROP COLUMN status,
+    DROP COLUMN created_at,
+    DROP COLUMN updated_at
@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE share2_keys
+    ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active',
+    ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc'),
+    ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT (NOW() AT TIME ZONE 'utc');
@@ -0,0 +1,15 @@
+-- Your SQL goes here
+CREATE TABLE submit_outbox (
+    id SERIAL PRIMARY KEY,
+    tx_id INTEGER NOT NULL,
+    signature TEXT NOT NULL,
+    -- pending, delivered or undeliverable
+    status VARCHAR NOT NULL,
+    attempts INTEGER NOT NULL DEFAULT 0,
+    last_error TEXT,
+    next_attempt_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP NOT NULL,
+    delivered_at TIMESTAMP
+);
+
+CREATE INDEX submit_outbox_status_idx ON submit_outbox (status, next_attempt_at)
@@ -0,0 +1,2 @@
+-- This file should undo anything in `up.sql`
+DROP INDEX submit_outbox_tx_id_idx
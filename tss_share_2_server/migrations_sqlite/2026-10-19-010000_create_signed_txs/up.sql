@@ -0,0 +1,8 @@
+-- Your SQL goes here
+CREATE TABLE signed_txs (
+    id INTEGER PRIMARY KEY,
+    tx_id INTEGER NOT NULL,
+    from_address VARCHAR NOT NULL,
+    value VARCHAR NOT NULL,
+    signed_at TIMESTAMP NOT NULL DEFAULT CURRENT_TIMESTAMP
+)
@@ -0,0 +1,3 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE share2_keys DROP COLUMN data_key;
+ALTER TABLE share2_keys DROP COLUMN master_key_id;
@@ -0,0 +1,4 @@
+-- This file should undo anything in `up.sql`
+ALTER TABLE share2_keys DROP COLUMN status;
+ALTER TABLE share2_keys DROP COLUMN created_at;
+ALTER TABLE share2_keys DROP COLUMN updated_at;
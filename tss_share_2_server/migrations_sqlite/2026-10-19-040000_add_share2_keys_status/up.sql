@@ -0,0 +1,5 @@
+-- Your SQL goes here
+ALTER TABLE share2_keys ADD COLUMN status VARCHAR NOT NULL DEFAULT 'active';
+ALTER TABLE share2_keys ADD COLUMN created_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+ALTER TABLE share2_keys ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT '1970-01-01 00:00:00';
+UPDATE share2_keys SET created_at = CURRENT_TIMESTAMP, updated_at = CURRENT_TIMESTAMP;
@@ -0,0 +1,11 @@
+-- Your SQL goes here
+CREATE TABLE processed_requests (
+    request_id VARCHAR PRIMARY KEY,
+    -- sign or keygen
+    signal VARCHAR NOT NULL,
+    signal_id VARCHAR NOT NULL,
+    -- set once the signal was handled
+    result TEXT,
+    created_at TIMESTAMP NOT NULL,
+    completed_at TIMESTAMP
+)
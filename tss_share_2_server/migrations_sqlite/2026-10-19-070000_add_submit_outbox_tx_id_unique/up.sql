@@ -0,0 +1,5 @@
+-- Your SQL goes here
+-- keeps the first item of a tx that was queued more than once
+DELETE FROM submit_outbox WHERE id NOT IN (SELECT MIN(id) FROM submit_outbox GROUP BY tx_id);
+
+CREATE UNIQUE INDEX submit_outbox_tx_id_idx ON submit_outbox (tx_id)
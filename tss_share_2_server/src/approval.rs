@@ -0,0 +1,207 @@
+//! Operator approval of sign signals parked by the policy's `approval` rule.
+
+use std::time::Duration;
+
+use chrono::NaiveDateTime;
+use diesel::result::Error::NotFound;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use tss_messages::{SignSignal, SignalResult};
+use tss_sm_client::tx::Transaction;
+
+use crate::auth::ApiKey;
+use crate::consumer::Limits;
+use crate::db::{self, DbError, DbPool};
+use crate::processed;
+use crate::{evaluate_policy, reject_tx, rejected, sign_accepted, SignRes};
+
+const REJECTED_BY_OPERATOR: &str = "rejected by operator";
+const APPROVAL_EXPIRED: &str = "approval expired";
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct ApprovalRes {
+    id: i32,
+    from_address: String,
+    reason: String,
+    tx: Transaction,
+    created_at: NaiveDateTime,
+    expires_at: NaiveDateTime,
+}
+
+#[rocket::get("/approvals")]
+pub(crate) async fn list_approvals(
+    _api_key: ApiKey,
+    pool: &State<DbPool>,
+) -> Result<Json<Vec<ApprovalRes>>, Status> {
+    let approvals = db::run(pool, |conn| conn.get_pending_approvals())
+        .await
+        .map_err(|e| {
+            println!("error getting pending approvals: {}", e);
+            Status::InternalServerError
+        })?;
+
+    let approvals = approvals
+        .into_iter()
+        .filter_map(|approval| {
+            let sign_data = serde_json::from_str::<SignSignal>(&approval.sign_signal).ok()?;
+            Some(ApprovalRes {
+                id: approval.id,
+                from_address: approval.from_address,
+                reason: approval.reason,
+                tx: sign_data.tx,
+                created_at: approval.created_at,
+                expires_at: approval.expires_at,
+            })
+        })
+        .collect();
+
+    Ok(Json(approvals))
+}
+
+/// The policy is evaluated again first, as the daily limit may have been used
+/// up or the time window closed since the signal was parked; a denied signal
+/// is rejected instead of signed. The sign requests that parked the signal
+/// report its final result from then on.
+#[rocket::post("/approvals/<id>/approve")]
+pub(crate) async fn approve(
+    _api_key: ApiKey,
+    pool: &State<DbPool>,
+    limits: &State<Limits>,
+    id: i32,
+) -> Json<SignRes> {
+    let approval = match db::run(pool, move |conn| conn.get_pending_approval(id)).await {
+        Ok(approval) => approval,
+        Err(e) => return Json(decision_error(id, e)),
+    };
+    let sign_data = match serde_json::from_str::<SignSignal>(&approval.sign_signal) {
+        Ok(sign_data) => sign_data,
+        Err(e) => {
+            return Json(SignRes {
+                success: false,
+                info: Some(format!("error on parsing parked sign signal: {}", e)),
+            })
+        }
+    };
+
+    let denied = match evaluate_policy(pool, &sign_data).await {
+        Ok(evaluated) => evaluated.err(),
+        Err(e) => {
+            return Json(SignRes {
+                success: false,
+                info: Some(format!("error evaluating policy: {}", e)),
+            })
+        }
+    };
+    let (decision, result) = match &denied {
+        Some(reason) => ("rejected", Some(rejected_result(id, reason))),
+        None => ("approved", None),
+    };
+    if let Err(e) = db::run(pool, move |conn| {
+        conn.decide_pending_approval(id, decision, result.as_deref())
+    })
+    .await
+    {
+        return Json(decision_error(id, e));
+    }
+
+    if let Some(reason) = denied {
+        println!("sign signal {} denied on approval: {}", id, reason);
+        reject_tx(id as usize, &reason).await;
+        return Json(SignRes {
+            success: false,
+            info: Some(format!("denied by policy: {}", reason)),
+        });
+    }
+
+    println!("sign signal {} approved", id);
+    let pool = pool.inner().clone();
+    // counts against SIGN_CONCURRENCY like signals from the queue
+    let sign_limit = limits.sign.clone();
+    tokio::task::spawn(async move {
+        let _permit = sign_limit
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let result = match sign_accepted(&pool, &sign_data).await {
+            Ok(result) => result,
+            Err(e) => {
+                println!("sign signal {} failed after approval: {}", id, e);
+                rejected(sign_data.id, "signing failed after approval").await
+            }
+        };
+        if let Err(e) = processed::complete_approved(&pool, id, &result).await {
+            println!("error storing result of sign signal {}: {}", id, e);
+        }
+    });
+
+    Json(SignRes {
+        success: true,
+        info: None,
+    })
+}
+
+#[rocket::post("/approvals/<id>/reject")]
+pub(crate) async fn reject(_api_key: ApiKey, pool: &State<DbPool>, id: i32) -> Json<SignRes> {
+    let result = rejected_result(id, REJECTED_BY_OPERATOR);
+    if let Err(e) = db::run(pool, move |conn| {
+        conn.decide_pending_approval(id, "rejected", Some(&result))
+    })
+    .await
+    {
+        return Json(decision_error(id, e));
+    }
+
+    println!("sign signal {} rejected by operator", id);
+    reject_tx(id as usize, REJECTED_BY_OPERATOR).await;
+
+    Json(SignRes {
+        success: true,
+        info: None,
+    })
+}
+
+/// The stored result of a sign signal rejected on its approval.
+fn rejected_result(id: i32, reason: &str) -> String {
+    serde_json::to_string(&SignalResult::Rejected {
+        id: id as usize,
+        reason: reason.to_string(),
+    })
+    .expect("error serializing result")
+}
+
+fn decision_error(id: i32, error: DbError) -> SignRes {
+    let info = match error {
+        DbError::Query(NotFound) => format!("no pending approval for sign signal {}", id),
+        e => format!("error deciding approval: {}", e),
+    };
+    SignRes {
+        success: false,
+        info: Some(info),
+    }
+}
+
+/// Expires approvals past their deadline and reports them as rejected.
+pub async fn expire_approvals(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(60));
+    loop {
+        interval.tick().await;
+
+        let expired = db::run(&pool, |conn| {
+            conn.expire_pending_approvals(&|approval| {
+                rejected_result(approval.id, APPROVAL_EXPIRED)
+            })
+        })
+        .await;
+        match expired {
+            Ok(expired) => {
+                for approval in expired {
+                    println!("approval for sign signal {} expired", approval.id);
+                    reject_tx(approval.id as usize, APPROVAL_EXPIRED).await;
+                }
+            }
+            Err(e) => println!("error expiring approvals: {}", e),
+        }
+    }
+}
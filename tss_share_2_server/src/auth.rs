@@ -0,0 +1,50 @@
+use rocket::http::Status;
+use rocket::request::{FromRequest, Outcome, Request};
+use subtle::ConstantTimeEq;
+
+use crate::{SHARE_2_API_KEY, SIGNAL_API_KEY};
+
+/// Request guard for operator endpoints, checked against header X-Api-Key
+pub struct ApiKey;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for ApiKey {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match request.headers().get_one("X-Api-Key") {
+            Some(api_key) if is_valid(api_key, &SHARE_2_API_KEY) => Outcome::Success(ApiKey),
+            Some(_) => Outcome::Failure((Status::Unauthorized, "api key is not valid")),
+            None => Outcome::Failure((Status::Unauthorized, "api key is missing")),
+        }
+    }
+}
+
+/// Request guard for the HTTP signal API, checked against header X-Api-Key.
+/// Every request fails while SIGNAL_API_KEY is not set.
+pub struct SignalApiKey;
+
+#[rocket::async_trait]
+impl<'r> FromRequest<'r> for SignalApiKey {
+    type Error = &'static str;
+
+    async fn from_request(request: &'r Request<'_>) -> Outcome<Self, Self::Error> {
+        match (
+            request.headers().get_one("X-Api-Key"),
+            SIGNAL_API_KEY.as_deref(),
+        ) {
+            (_, None) => Outcome::Failure((Status::Unauthorized, "signal api is disabled")),
+            (Some(api_key), Some(signal_api_key)) if is_valid(api_key, signal_api_key) => {
+                Outcome::Success(SignalApiKey)
+            }
+            (Some(_), _) => Outcome::Failure((Status::Unauthorized, "api key is not valid")),
+            (None, _) => Outcome::Failure((Status::Unauthorized, "api key is missing")),
+        }
+    }
+}
+
+/// Compares api keys in constant time, so that the response time does not
+/// reveal how much of a key was guessed.
+fn is_valid(api_key: &str, expected: &str) -> bool {
+    api_key.as_bytes().ct_eq(expected.as_bytes()).into()
+}
@@ -0,0 +1,76 @@
+//! Message broker the signal consumers take their deliveries from.
+//!
+//! `AmqpBroker` talks to RabbitMQ through lapin. `MemoryBroker` keeps its queues
+//! in the process; share 2 uses it when `RABBITMQ_HOST` is not set, and takes
+//! signals only from the HTTP signal API.
+
+pub mod amqp;
+pub mod memory;
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Duration;
+
+use futures::stream::BoxStream;
+
+/// Message headers; values of other types than strings and integers are
+/// dropped.
+pub type Headers = BTreeMap<String, String>;
+
+#[derive(Clone, Debug)]
+pub struct Message {
+    /// Identifies the delivery to `ack` and `nack`
+    pub tag: u64,
+    pub data: Vec<u8>,
+    pub headers: Headers,
+}
+
+#[derive(Debug)]
+pub struct BrokerError(pub String);
+
+impl fmt::Display for BrokerError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "broker error: {}", self.0)
+    }
+}
+
+impl std::error::Error for BrokerError {}
+
+impl From<lapin::Error> for BrokerError {
+    fn from(e: lapin::Error) -> Self {
+        BrokerError(e.to_string())
+    }
+}
+
+/// Deliveries from a queue; ends when the broker stops delivering.
+pub type Deliveries = BoxStream<'static, Result<Message, BrokerError>>;
+
+#[rocket::async_trait]
+pub trait Broker: Send + Sync {
+    /// Starts consuming `queue` with at most `prefetch` unacked deliveries.
+    async fn consume(&self, queue: &str, prefetch: u16) -> Result<Deliveries, BrokerError>;
+
+    async fn ack(&self, message: &Message) -> Result<(), BrokerError>;
+
+    async fn nack(&self, message: &Message, requeue: bool) -> Result<(), BrokerError>;
+
+    /// Publishes to `exchange`; the default exchange `""` routes to the queue
+    /// named `routing_key`.
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        headers: Headers,
+    ) -> Result<(), BrokerError>;
+
+    /// Publishes to `queue` once `delay` has passed. The broker holds the
+    /// message meanwhile, so the delivery it retries can be acked right away.
+    async fn publish_delayed(
+        &self,
+        queue: &str,
+        data: &[u8],
+        headers: Headers,
+        delay: Duration,
+    ) -> Result<(), BrokerError>;
+}
@@ -0,0 +1,253 @@
+use std::time::Duration;
+
+use futures::StreamExt;
+use lapin::message::Delivery;
+use lapin::options::{
+    BasicAckOptions, BasicConsumeOptions, BasicNackOptions, BasicPublishOptions, BasicQosOptions,
+    ConfirmSelectOptions, ExchangeDeclareOptions, QueueBindOptions, QueueDeclareOptions,
+};
+use lapin::publisher_confirm::{Confirmation, PublisherConfirm};
+use lapin::types::{AMQPValue, FieldTable};
+use lapin::{BasicProperties, Channel, Connection, ConnectionProperties, ExchangeKind};
+
+use super::{Broker, BrokerError, Deliveries, Headers, Message};
+use crate::{RABBITMQ_DEAD_LETTER_EXCHANGE, RABBITMQ_PORT};
+
+/// `delivery_mode` of messages written to disk by the broker
+const PERSISTENT: u8 = 2;
+
+/// RabbitMQ connection with one channel for consuming and publishing.
+pub struct AmqpBroker {
+    connection: Connection,
+    channel: Channel,
+}
+
+impl AmqpBroker {
+    /// Connects to `host` and declares `queues` (durable), the dead-letter
+    /// exchange and a `<queue>.dead-letter` queue bound to it for each, and a
+    /// `<queue>.retry` queue whose expired messages go back to the queue.
+    /// Publisher confirms are enabled on the channel, so a publish returns only
+    /// once the broker took the message.
+    pub async fn connect(host: &str, queues: &[&str]) -> Result<Self, BrokerError> {
+        let connection = Connection::connect(
+            &format!("amqp://{}:{}", host, *RABBITMQ_PORT),
+            ConnectionProperties::default(),
+        )
+        .await?;
+        let channel = connection.create_channel().await?;
+        channel
+            .confirm_select(ConfirmSelectOptions::default())
+            .await?;
+
+        let durable = QueueDeclareOptions {
+            durable: true,
+            ..Default::default()
+        };
+        channel
+            .exchange_declare(
+                &RABBITMQ_DEAD_LETTER_EXCHANGE,
+                ExchangeKind::Direct,
+                ExchangeDeclareOptions {
+                    durable: true,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        for queue in queues {
+            let dead_letter_queue = format!("{}.dead-letter", queue);
+            channel
+                .queue_declare(queue, durable, FieldTable::default())
+                .await?;
+            // messages expire into the default exchange, which routes them by name
+            let mut retry_arguments = FieldTable::default();
+            retry_arguments.insert(
+                "x-dead-letter-exchange".into(),
+                AMQPValue::LongString("".into()),
+            );
+            retry_arguments.insert(
+                "x-dead-letter-routing-key".into(),
+                AMQPValue::LongString(queue.to_string().into()),
+            );
+            channel
+                .queue_declare(&retry_queue(queue), durable, retry_arguments)
+                .await?;
+            channel
+                .queue_declare(&dead_letter_queue, durable, FieldTable::default())
+                .await?;
+            channel
+                .queue_bind(
+                    &dead_letter_queue,
+                    &RABBITMQ_DEAD_LETTER_EXCHANGE,
+                    queue,
+                    QueueBindOptions::default(),
+                    FieldTable::default(),
+                )
+                .await?;
+        }
+
+        Ok(AmqpBroker {
+            connection,
+            channel,
+        })
+    }
+
+    pub async fn close(&self) {
+        if let Err(e) = self.connection.close(0, "reconnecting").await {
+            println!("error closing RabbitMQ connection: {}", e);
+        }
+    }
+}
+
+#[rocket::async_trait]
+impl Broker for AmqpBroker {
+    /// The prefetch count applies to consumers started after it on the channel.
+    async fn consume(&self, queue: &str, prefetch: u16) -> Result<Deliveries, BrokerError> {
+        self.channel
+            .basic_qos(prefetch, BasicQosOptions::default())
+            .await?;
+        let consumer = self
+            .channel
+            .basic_consume(
+                queue,
+                &format!("share-2-server: {}", queue),
+                BasicConsumeOptions {
+                    no_ack: false,
+                    ..Default::default()
+                },
+                FieldTable::default(),
+            )
+            .await?;
+        Ok(consumer
+            .map(|delivery| delivery.map(message).map_err(BrokerError::from))
+            .boxed())
+    }
+
+    async fn ack(&self, message: &Message) -> Result<(), BrokerError> {
+        self.channel
+            .basic_ack(message.tag, BasicAckOptions::default())
+            .await?;
+        Ok(())
+    }
+
+    async fn nack(&self, message: &Message, requeue: bool) -> Result<(), BrokerError> {
+        self.channel
+            .basic_nack(
+                message.tag,
+                BasicNackOptions {
+                    requeue,
+                    ..Default::default()
+                },
+            )
+            .await?;
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        headers: Headers,
+    ) -> Result<(), BrokerError> {
+        let publisher_confirm = self
+            .channel
+            .basic_publish(
+                exchange,
+                routing_key,
+                mandatory(),
+                data,
+                properties(headers),
+            )
+            .await?;
+        confirmed(publisher_confirm).await
+    }
+
+    /// Waits out `delay` in `<queue>.retry`, as the message's expiration.
+    /// Expirations only apply at the head of that queue, so a message may wait
+    /// longer behind one with a longer delay, never shorter.
+    async fn publish_delayed(
+        &self,
+        queue: &str,
+        data: &[u8],
+        headers: Headers,
+        delay: Duration,
+    ) -> Result<(), BrokerError> {
+        let publisher_confirm = self
+            .channel
+            .basic_publish(
+                "",
+                &retry_queue(queue),
+                mandatory(),
+                data,
+                properties(headers).with_expiration(delay.as_millis().to_string().into()),
+            )
+            .await?;
+        confirmed(publisher_confirm).await
+    }
+}
+
+/// Publishes are mandatory, so the broker returns a message no queue took
+/// instead of dropping it.
+fn mandatory() -> BasicPublishOptions {
+    BasicPublishOptions {
+        mandatory: true,
+        ..Default::default()
+    }
+}
+
+/// Waits for the broker to confirm a publish. A nack, or a message returned
+/// as unroutable, is an error, so the delivery it copies is not acked.
+async fn confirmed(publisher_confirm: PublisherConfirm) -> Result<(), BrokerError> {
+    match publisher_confirm.await? {
+        Confirmation::Ack(None) => Ok(()),
+        Confirmation::Ack(Some(returned)) => Err(BrokerError(format!(
+            "message returned as unroutable: {}",
+            returned.reply_text
+        ))),
+        Confirmation::Nack(_) => Err(BrokerError("message nacked by the broker".to_string())),
+        Confirmation::NotRequested => Err(BrokerError(
+            "publisher confirms are not enabled on the channel".to_string(),
+        )),
+    }
+}
+
+fn retry_queue(queue: &str) -> String {
+    format!("{}.retry", queue)
+}
+
+/// Published messages are persistent, so retries and dead letters in the
+/// durable queues survive a broker restart, and JSON, like the signals they
+/// copy.
+fn properties(headers: Headers) -> BasicProperties {
+    let mut field_table = FieldTable::default();
+    for (name, value) in headers {
+        field_table.insert(name.into(), AMQPValue::LongString(value.into()));
+    }
+    BasicProperties::default()
+        .with_delivery_mode(PERSISTENT)
+        .with_content_type("application/json".into())
+        .with_headers(field_table)
+}
+
+fn message(delivery: Delivery) -> Message {
+    let mut headers = Headers::new();
+    if let Some(field_table) = delivery.properties.headers() {
+        for (name, value) in field_table.inner() {
+            let value = match value {
+                AMQPValue::LongString(value) => value.to_string(),
+                AMQPValue::ShortString(value) => value.to_string(),
+                AMQPValue::LongUInt(value) => value.to_string(),
+                AMQPValue::LongInt(value) => value.to_string(),
+                AMQPValue::LongLongInt(value) => value.to_string(),
+                _ => continue,
+            };
+            headers.insert(name.to_string(), value);
+        }
+    }
+    Message {
+        tag: delivery.delivery_tag,
+        data: delivery.data,
+        headers,
+    }
+}
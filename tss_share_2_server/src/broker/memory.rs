@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use futures::StreamExt;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use super::{Broker, BrokerError, Deliveries, Headers, Message};
+
+/// Queues kept in the process. Messages published to an exchange other than
+/// the default one reach the queues bound to it with `bind`, and are dropped
+/// otherwise. The prefetch count is not enforced.
+#[derive(Default)]
+pub struct MemoryBroker {
+    queues: Mutex<HashMap<String, Queue>>,
+    bindings: Mutex<HashMap<(String, String), String>>,
+    /// Published messages by tag, with their queue, until acked or nacked
+    unacked: Mutex<HashMap<u64, (String, Message)>>,
+    next_tag: AtomicU64,
+}
+
+struct Queue {
+    sender: UnboundedSender<Message>,
+    /// Taken by the consumer
+    receiver: Option<UnboundedReceiver<Message>>,
+}
+
+impl Queue {
+    fn new() -> Self {
+        let (sender, receiver) = unbounded_channel();
+        Queue {
+            sender,
+            receiver: Some(receiver),
+        }
+    }
+}
+
+impl MemoryBroker {
+    /// Routes messages published to `exchange` with `routing_key` to `queue`.
+    pub fn bind(&self, exchange: &str, routing_key: &str, queue: &str) {
+        self.bindings.lock().unwrap().insert(
+            (exchange.to_string(), routing_key.to_string()),
+            queue.to_string(),
+        );
+    }
+
+    /// Messages published and not yet acked or nacked.
+    pub fn unacked(&self) -> usize {
+        self.unacked.lock().unwrap().len()
+    }
+
+    fn sender(&self, queue: &str) -> UnboundedSender<Message> {
+        self.queues
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_insert_with(Queue::new)
+            .sender
+            .clone()
+    }
+
+    fn enqueue(&self, queue: &str, message: Message) {
+        // the receiver is held by the queue or its consumer, never dropped
+        let _ = self.sender(queue).send(message);
+    }
+
+    /// Records a message published to `queue` as unacked.
+    fn new_message(&self, queue: &str, data: &[u8], headers: Headers) -> Message {
+        let message = Message {
+            tag: self.next_tag.fetch_add(1, Ordering::SeqCst),
+            data: data.to_vec(),
+            headers,
+        };
+        self.unacked
+            .lock()
+            .unwrap()
+            .insert(message.tag, (queue.to_owned(), message.clone()));
+        message
+    }
+}
+
+#[rocket::async_trait]
+impl Broker for MemoryBroker {
+    async fn consume(&self, queue: &str, _prefetch: u16) -> Result<Deliveries, BrokerError> {
+        let receiver = self
+            .queues
+            .lock()
+            .unwrap()
+            .entry(queue.to_string())
+            .or_insert_with(Queue::new)
+            .receiver
+            .take()
+            .ok_or_else(|| BrokerError(format!("{} already has a consumer", queue)))?;
+
+        Ok(
+            futures::stream::unfold(receiver, |mut receiver| async move {
+                let message = receiver.recv().await?;
+                Some((Ok(message), receiver))
+            })
+            .boxed(),
+        )
+    }
+
+    async fn ack(&self, message: &Message) -> Result<(), BrokerError> {
+        self.unacked.lock().unwrap().remove(&message.tag);
+        Ok(())
+    }
+
+    async fn nack(&self, message: &Message, requeue: bool) -> Result<(), BrokerError> {
+        let unacked = self.unacked.lock().unwrap().remove(&message.tag);
+        if let (true, Some((queue, message))) = (requeue, unacked) {
+            self.enqueue(&queue, message);
+        }
+        Ok(())
+    }
+
+    async fn publish(
+        &self,
+        exchange: &str,
+        routing_key: &str,
+        data: &[u8],
+        headers: Headers,
+    ) -> Result<(), BrokerError> {
+        let queue = if exchange.is_empty() {
+            routing_key.to_string()
+        } else {
+            match self
+                .bindings
+                .lock()
+                .unwrap()
+                .get(&(exchange.to_string(), routing_key.to_string()))
+            {
+                Some(queue) => queue.to_owned(),
+                None => return Ok(()),
+            }
+        };
+        let message = self.new_message(&queue, data, headers);
+        self.enqueue(&queue, message);
+        Ok(())
+    }
+
+    async fn publish_delayed(
+        &self,
+        queue: &str,
+        data: &[u8],
+        headers: Headers,
+        delay: Duration,
+    ) -> Result<(), BrokerError> {
+        let message = self.new_message(queue, data, headers);
+        let sender = self.sender(queue);
+        tokio::task::spawn(async move {
+            tokio::time::sleep(delay).await;
+            let _ = sender.send(message);
+        });
+        Ok(())
+    }
+}
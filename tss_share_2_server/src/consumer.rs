@@ -0,0 +1,295 @@
+//! Supervision of the RabbitMQ connection and the signal consumers.
+//!
+//! The sign and keygen signal queues, and their retry and dead-letter queues,
+//! are declared and consumed on a fresh channel after every (re)connect. When
+//! the connection or either consumer fails, share 2 reconnects with
+//! exponential backoff from `RABBITMQ_RECONNECT_MIN_SECS` up to
+//! `RABBITMQ_RECONNECT_MAX_SECS`. `GET /health` reports whether the consumers
+//! are running. Without `RABBITMQ_HOST` the queues are kept in memory.
+//!
+//! At most `SIGN_CONCURRENCY` sign and `KEYGEN_CONCURRENCY` keygen signals are
+//! handled at once, counting those posted to the HTTP signal API; a consumer
+//! stops reading deliveries while its limit is reached, and the broker stops
+//! pushing once `RABBITMQ_SIGN_PREFETCH` or `RABBITMQ_KEYGEN_PREFETCH` of them
+//! are unacked.
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use futures::StreamExt;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use tokio::sync::Semaphore;
+
+use crate::broker::amqp::AmqpBroker;
+use crate::broker::memory::MemoryBroker;
+use crate::broker::{Broker, BrokerError, Deliveries, Message};
+use crate::db::DbPool;
+use crate::delivery;
+use crate::{
+    handle_keygen_signal, handle_sign_signal, KEYGEN_CONCURRENCY, RABBITMQ_DEAD_LETTER_EXCHANGE,
+    RABBITMQ_HOST, RABBITMQ_KEYGEN_PREFETCH, RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME,
+    RABBITMQ_RECONNECT_MAX_SECS, RABBITMQ_RECONNECT_MIN_SECS, RABBITMQ_SIGN_PREFETCH,
+    RABBITMQ_SIGN_SIGNAL_QUEUE_NAME, SIGN_CONCURRENCY,
+};
+
+/// Whether share 2 is connected to RabbitMQ and consuming signals
+#[derive(Clone, Default)]
+pub struct BrokerHealth(Arc<AtomicBool>);
+
+impl BrokerHealth {
+    pub fn is_connected(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+
+    fn set_connected(&self, connected: bool) {
+        self.0.store(connected, Ordering::SeqCst);
+    }
+}
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct HealthRes {
+    /// "rabbitmq", or "memory" without `RABBITMQ_HOST`
+    broker: &'static str,
+    /// null with the in-memory broker
+    rabbitmq: Option<bool>,
+}
+
+/// 503 while the consumers are down, for load balancer and orchestrator
+/// probes.
+#[rocket::get("/health")]
+pub(crate) async fn health(broker: &State<BrokerHealth>) -> (Status, Json<HealthRes>) {
+    let connected = broker.is_connected();
+    let status = if connected {
+        Status::Ok
+    } else {
+        Status::ServiceUnavailable
+    };
+    let res = match RABBITMQ_HOST.as_deref() {
+        Some(_) => HealthRes {
+            broker: "rabbitmq",
+            rabbitmq: Some(connected),
+        },
+        None => HealthRes {
+            broker: "memory",
+            rabbitmq: None,
+        },
+    };
+    (status, Json(res))
+}
+
+#[derive(Clone, Copy)]
+pub enum Signal {
+    Sign,
+    Keygen,
+}
+
+impl Signal {
+    fn queue(&self) -> &'static str {
+        match self {
+            Signal::Sign => &RABBITMQ_SIGN_SIGNAL_QUEUE_NAME,
+            Signal::Keygen => &RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME,
+        }
+    }
+
+    fn prefetch(&self) -> u16 {
+        match self {
+            Signal::Sign => *RABBITMQ_SIGN_PREFETCH,
+            Signal::Keygen => *RABBITMQ_KEYGEN_PREFETCH,
+        }
+    }
+}
+
+/// Signals being handled, shared with the HTTP signal API; kept across
+/// reconnects, as handlers started on a dropped connection run to the end.
+#[derive(Clone)]
+pub struct Limits {
+    pub(crate) sign: Arc<Semaphore>,
+    pub(crate) keygen: Arc<Semaphore>,
+}
+
+impl Limits {
+    pub fn from_env() -> Self {
+        Limits {
+            sign: Arc::new(Semaphore::new(*SIGN_CONCURRENCY)),
+            keygen: Arc::new(Semaphore::new(*KEYGEN_CONCURRENCY)),
+        }
+    }
+
+    fn of(&self, signal: Signal) -> &Arc<Semaphore> {
+        match signal {
+            Signal::Sign => &self.sign,
+            Signal::Keygen => &self.keygen,
+        }
+    }
+}
+
+/// Consumes the signal queues, reconnecting whenever the connection drops.
+/// Never returns.
+pub async fn run(pool: DbPool, limits: Limits, health: BrokerHealth) {
+    let host = match RABBITMQ_HOST.as_deref() {
+        Some(host) => host,
+        None => return run_in_memory(pool, limits, health).await,
+    };
+    let queues = [Signal::Sign.queue(), Signal::Keygen.queue()];
+    let min_backoff = Duration::from_secs(*RABBITMQ_RECONNECT_MIN_SECS);
+    let max_backoff = Duration::from_secs(*RABBITMQ_RECONNECT_MAX_SECS);
+    let mut backoff = min_backoff;
+    loop {
+        match AmqpBroker::connect(host, &queues).await {
+            Ok(broker) => {
+                let broker = Arc::new(broker);
+                let result = consume(broker.clone(), &pool, &limits, &health, || {
+                    backoff = min_backoff
+                })
+                .await;
+                // a consumer can end while the connection is still open
+                broker.close().await;
+                match result {
+                    Ok(()) => println!("RabbitMQ consumers stopped"),
+                    Err(e) => println!("RabbitMQ consumer failed: {}", e),
+                }
+            }
+            Err(e) => println!("RabbitMQ connection failed: {}", e),
+        }
+        health.set_connected(false);
+        println!("reconnecting to RabbitMQ in {}s", backoff.as_secs());
+        tokio::time::sleep(backoff).await;
+        backoff = (backoff * 2).min(max_backoff);
+    }
+}
+
+/// Only retries and dead letters reach the in-memory queues; signals come
+/// from the HTTP signal API.
+async fn run_in_memory(pool: DbPool, limits: Limits, health: BrokerHealth) {
+    println!("RABBITMQ_HOST is not set, keeping the signal queues in memory");
+    let broker = MemoryBroker::default();
+    for queue in [Signal::Sign.queue(), Signal::Keygen.queue()] {
+        broker.bind(
+            &RABBITMQ_DEAD_LETTER_EXCHANGE,
+            queue,
+            &format!("{}.dead-letter", queue),
+        );
+    }
+    if let Err(e) = consume(Arc::new(broker), &pool, &limits, &health, || {}).await {
+        println!("in-memory consumer failed: {}", e);
+    }
+}
+
+/// Consumes both signal queues until either consumer ends. `on_started` runs
+/// once both consumers are started.
+async fn consume(
+    broker: Arc<dyn Broker>,
+    pool: &DbPool,
+    limits: &Limits,
+    health: &BrokerHealth,
+    on_started: impl FnOnce(),
+) -> Result<(), BrokerError> {
+    let sign_deliveries = broker
+        .consume(Signal::Sign.queue(), Signal::Sign.prefetch())
+        .await?;
+    let keygen_deliveries = broker
+        .consume(Signal::Keygen.queue(), Signal::Keygen.prefetch())
+        .await?;
+
+    println!("consuming sign and keygen signals");
+    health.set_connected(true);
+    on_started();
+
+    tokio::select! {
+        result = consume_queue(&broker, pool, limits, sign_deliveries, Signal::Sign) => result,
+        result = consume_queue(&broker, pool, limits, keygen_deliveries, Signal::Keygen) => result,
+    }
+}
+
+/// Handles each delivery in its own task, waiting for a free slot before
+/// reading the next one; returns when the deliveries end.
+async fn consume_queue(
+    broker: &Arc<dyn Broker>,
+    pool: &DbPool,
+    limits: &Limits,
+    mut deliveries: Deliveries,
+    signal: Signal,
+) -> Result<(), BrokerError> {
+    loop {
+        let permit = limits
+            .of(signal)
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let message = match deliveries.next().await {
+            Some(message) => message?,
+            None => return Ok(()),
+        };
+        let (broker, pool) = (broker.clone(), pool.clone());
+        tokio::task::spawn(async move {
+            let result = process(&pool, signal, &message).await;
+            delivery::settle(&*broker, signal.queue(), &message, result).await;
+            drop(permit);
+        });
+    }
+}
+
+/// Handles one message from `signal`'s queue.
+pub async fn process(
+    pool: &DbPool,
+    signal: Signal,
+    message: &Message,
+) -> Result<(), delivery::DeliveryError> {
+    match signal {
+        Signal::Sign => handle_sign_signal(pool, &message.data).await,
+        Signal::Keygen => handle_keygen_signal(pool, &message.data).await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::r2d2::{ConnectionManager, Pool};
+
+    use super::*;
+
+    /// Never connected to; invalid signals are refused before any query.
+    fn pool() -> DbPool {
+        DbPool::Sqlite(Pool::builder().build_unchecked(ConnectionManager::new(":memory:")))
+    }
+
+    #[tokio::test]
+    async fn dead_letters_invalid_signals() {
+        let keygen_3_parties = br#"{"version": 1, "requestId": "r1",
+            "signal": {"id": 12, "threshold": 1, "parties": 3, "party_index": 1}}"#;
+        for (signal, data) in [
+            (Signal::Sign, &b"not json"[..]),
+            (
+                Signal::Keygen,
+                &br#"{"data": "abc", "requestId": "r1"}"#[..],
+            ),
+            (Signal::Keygen, &keygen_3_parties[..]),
+        ] {
+            let broker = MemoryBroker::default();
+            broker.bind(
+                &RABBITMQ_DEAD_LETTER_EXCHANGE,
+                "signals",
+                "signals.dead-letter",
+            );
+            let mut deliveries = broker.consume("signals", 1).await.unwrap();
+            let mut dead_letters = broker.consume("signals.dead-letter", 1).await.unwrap();
+            broker
+                .publish("", "signals", data, Default::default())
+                .await
+                .unwrap();
+            let message = deliveries.next().await.unwrap().unwrap();
+
+            let result = process(&pool(), signal, &message).await;
+            assert!(matches!(result, Err(delivery::DeliveryError::Permanent(_))));
+            delivery::settle(&broker, "signals", &message, result).await;
+
+            let dead_letter = dead_letters.next().await.unwrap().unwrap();
+            assert_eq!(dead_letter.data, data);
+            assert!(dead_letter.headers.contains_key("x-failure-reason"));
+        }
+    }
+}
@@ -1,43 +1,734 @@
 pub mod models;
 pub mod schema;
 
+use chrono::{NaiveDateTime, Utc};
 use diesel::pg::PgConnection;
 use diesel::prelude::*;
-use dotenv::dotenv;
-use std::env;
+use diesel::sqlite::SqliteConnection;
+use diesel_migrations::{embed_migrations, EmbeddedMigrations};
+use tss_common::db::{allowed_from, invalid_transition, ConnectionMut, DbConnection};
+use tss_common::envelope::MasterKey;
+
+pub use tss_common::db::{DbError, DbPool};
 
 use self::models::*;
-use self::schema::share2_keys::dsl::{share2_keys, id, address, local_share};
-use diesel::result::Error::{DatabaseError, NotFound};
+use self::schema::share2_keys::dsl::{
+    address, created_at, data_key, id, local_share, master_key_id, share2_keys, status, updated_at,
+};
 use diesel::result::DatabaseErrorKind::UniqueViolation;
+use diesel::result::Error::{DatabaseError, NotFound};
+
+/// Applied to SQLite databases on connect.
+pub(crate) const SQLITE_MIGRATIONS: EmbeddedMigrations = embed_migrations!("migrations_sqlite");
+
+/// Sealed shares are bound to their row of this table.
+const KEYS_TABLE: &str = "share2_keys";
+
+/// Repository over share 2's tables, implemented for Postgres and SQLite
+/// connections.
+pub trait Storage {
+    fn get_key_by_address(&mut self, query_address: &str) -> QueryResult<Key>;
+
+    /// Share 2 only stores keys once keygen has finished, so they start active.
+    fn insert_new_key(
+        &mut self,
+        id_data: i32,
+        adress_data: &str,
+        local_share_data: &str,
+    ) -> QueryResult<Key>;
+
+    /// Re-wraps every data key under `MASTER_KEY`, unwrapping with `previous`, and
+    /// seals shares still stored in plaintext. Returns the number of rows updated.
+    fn rewrap_local_shares(&mut self, previous: Option<&MasterKey>) -> QueryResult<usize>;
+
+    /// Sum of the value signed for `query_address` since `since` (UTC).
+    fn get_value_signed_since(
+        &mut self,
+        query_address: &str,
+        since: NaiveDateTime,
+    ) -> QueryResult<u128>;
+
+    /// Records `value_data` as signed for `from_address_data` if `check`
+    /// accepts the value already signed since `since`. Reservations of the
+    /// same address are serialized, so each one sees those before it. Returns
+    /// the reservation, or why `check` refused it.
+    fn reserve_signed_tx(
+        &mut self,
+        tx_id_data: i32,
+        from_address_data: &str,
+        value_data: u128,
+        since: NaiveDateTime,
+        check: &dyn Fn(u128) -> Result<(), String>,
+    ) -> QueryResult<Result<SignedTx, String>>;
+
+    /// Drops a reservation whose tx was not signed after all.
+    fn release_signed_tx(&mut self, id_data: i32) -> QueryResult<()>;
+
+    fn insert_pending_approval(
+        &mut self,
+        id_data: i32,
+        from_address_data: &str,
+        sign_signal_data: &str,
+        reason_data: &str,
+        expires_at_data: NaiveDateTime,
+    ) -> QueryResult<PendingApproval>;
+
+    fn get_pending_approvals(&mut self) -> QueryResult<Vec<PendingApproval>>;
+
+    /// A pending, unexpired approval; `NotFound` otherwise.
+    fn get_pending_approval(&mut self, id_data: i32) -> QueryResult<PendingApproval>;
+
+    /// Moves a pending, unexpired approval to `new_status` and stores
+    /// `result_data` for the sign requests it parked, as
+    /// `set_sign_request_result` does. Returns `NotFound` if it was already
+    /// decided or has expired.
+    fn decide_pending_approval(
+        &mut self,
+        id_data: i32,
+        new_status: &str,
+        result_data: Option<&str>,
+    ) -> QueryResult<PendingApproval>;
+
+    /// Marks every pending approval past its deadline as expired, stores
+    /// `result_for` each one for the sign requests it parked, and returns them.
+    fn expire_pending_approvals(
+        &mut self,
+        result_for: &dyn Fn(&PendingApproval) -> String,
+    ) -> QueryResult<Vec<PendingApproval>>;
+
+    /// Claims `request_id` for handling. Returns `None` if it was claimed now,
+    /// or the earlier claim of a duplicate delivery. An unfinished claim made
+    /// before `stale_before` was left by a crash and is taken over.
+    /// `claimed_at_data` identifies the claim when it is completed.
+    fn claim_processed_request(
+        &mut self,
+        request_id_data: &str,
+        signal_data: &str,
+        signal_id_data: &str,
+        claimed_at_data: NaiveDateTime,
+        stale_before: NaiveDateTime,
+    ) -> QueryResult<Option<ProcessedRequest>>;
+
+    fn get_processed_request(&mut self, request_id_data: &str) -> QueryResult<ProcessedRequest>;
+
+    /// Stores the result of the sign requests of tx `tx_id_data`, replacing
+    /// the one they reported while it was parked for approval. `None` claims
+    /// them again while the approved tx is signed.
+    fn set_sign_request_result(
+        &mut self,
+        tx_id_data: i32,
+        result_data: Option<&str>,
+    ) -> QueryResult<usize>;
+
+    /// Stores the result of a claimed request, or releases the claim if
+    /// handling failed. Fails with `NotFound` if the claim made at
+    /// `claimed_at_data` was taken over, leaving the new claim alone.
+    fn complete_processed_request(
+        &mut self,
+        request_id_data: &str,
+        claimed_at_data: NaiveDateTime,
+        result_data: Option<&str>,
+    ) -> QueryResult<()>;
+
+    /// Stores the result of a claimed sign request together with its signature
+    /// for `/submit-tx`, so that neither is kept without the other.
+    fn complete_submitted_request(
+        &mut self,
+        request_id_data: &str,
+        claimed_at_data: NaiveDateTime,
+        result_data: &str,
+        tx_id_data: i32,
+        signature_data: &str,
+        next_attempt_at_data: NaiveDateTime,
+    ) -> QueryResult<Option<OutboxItem>>;
+
+    /// Stores the result of an approved tx's sign requests together with its
+    /// signature for `/submit-tx`, like `complete_submitted_request`.
+    fn complete_approved_tx(
+        &mut self,
+        tx_id_data: i32,
+        result_data: &str,
+        signature_data: &str,
+        next_attempt_at_data: NaiveDateTime,
+    ) -> QueryResult<Option<OutboxItem>>;
+
+    /// Queues a signature for `/submit-tx`. Returns `None` if the tx was
+    /// queued before.
+    fn insert_outbox_item(
+        &mut self,
+        tx_id_data: i32,
+        signature_data: &str,
+        next_attempt_at_data: NaiveDateTime,
+    ) -> QueryResult<Option<OutboxItem>>;
 
-pub fn establish_connection() -> PgConnection {
-    dotenv().ok();
+    /// Pending items whose next attempt is due, oldest first.
+    fn get_due_outbox_items(&mut self, limit: i64) -> QueryResult<Vec<OutboxItem>>;
 
-    let database_url = env::var("DATABASE_URL").expect("DATABASE_URL must be set");
-    PgConnection::establish(&database_url)
-        .unwrap_or_else(|_| panic!("Error connecting to {}", database_url))
+    fn get_outbox_items(&mut self, status_data: &str) -> QueryResult<Vec<OutboxItem>>;
+
+    fn mark_outbox_delivered(&mut self, id_data: i32) -> QueryResult<OutboxItem>;
+
+    /// Records a failed attempt; the item is retried at `next_attempt_at_data`,
+    /// or marked undeliverable if it is `None`.
+    fn mark_outbox_failed(
+        &mut self,
+        id_data: i32,
+        error_data: &str,
+        next_attempt_at_data: Option<NaiveDateTime>,
+    ) -> QueryResult<OutboxItem>;
+
+    /// Moves an undeliverable item back to pending, due now and with its
+    /// attempts reset. Returns `NotFound` if it is not undeliverable.
+    fn retry_outbox_item(&mut self, id_data: i32) -> QueryResult<OutboxItem>;
+
+    /// Moves a key to `new_status`, failing with `CheckViolation` if its
+    /// current status does not allow it.
+    fn set_key_status(&mut self, id_data: i32, new_status: KeyStatus) -> QueryResult<Key>;
+
+    /// Only active keys may sign.
+    fn get_local_share(&mut self, query_address: &str) -> QueryResult<String> {
+        tss_common::db::open_active_local_share(
+            KEYS_TABLE,
+            &self.get_key_by_address(query_address)?,
+        )
+    }
 }
 
-pub fn get_local_share(conn: &mut PgConnection, query_address: &str) -> QueryResult<String> {
-    let result = share2_keys.filter(address.eq(query_address)).load::<Key>(conn)?;
+pub fn establish_connection() -> Box<dyn Storage> {
+    match tss_common::db::establish_connection(SQLITE_MIGRATIONS) {
+        DbConnection::Postgres(conn) => Box::new(conn),
+        DbConnection::Sqlite(conn) => Box::new(conn),
+    }
+}
+
+/// Builds the connection pool shared by every consumer and request.
+pub fn establish_pool() -> Result<DbPool, Box<dyn std::error::Error>> {
+    tss_common::db::establish_pool(SQLITE_MIGRATIONS)
+}
+
+/// Runs `f` with a pooled connection on the blocking thread pool, so queries
+/// never block the async runtime.
+pub async fn run<T, F>(pool: &DbPool, f: F) -> Result<T, DbError>
+where
+    F: FnOnce(&mut dyn Storage) -> QueryResult<T> + Send + 'static,
+    T: Send + 'static,
+{
+    tss_common::db::run(pool, move |conn| match conn {
+        ConnectionMut::Postgres(conn) => f(conn),
+        ConnectionMut::Sqlite(conn) => f(conn),
+    })
+    .await
+}
 
-    if result.len() == 0 {
-        return Err(NotFound);
-    } else if result.len() != 1 {
-        return Err(DatabaseError(
-            UniqueViolation,
-            Box::new("found multiple entries".to_string()),
-        ));
+pub fn open_local_share(key: &Key) -> QueryResult<String> {
+    tss_common::db::open_local_share(KEYS_TABLE, key)
+}
+
+/// Serializes reservations of an address's daily value.
+trait SpendingLock: Sized {
+    /// Runs `f` in a transaction holding the lock of `from_address`.
+    fn locked_for_spending<T>(
+        &mut self,
+        from_address: &str,
+        f: impl FnOnce(&mut Self) -> QueryResult<T>,
+    ) -> QueryResult<T>;
+}
+
+impl SpendingLock for PgConnection {
+    fn locked_for_spending<T>(
+        &mut self,
+        from_address: &str,
+        f: impl FnOnce(&mut Self) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        self.transaction(|conn| {
+            diesel::sql_query("SELECT pg_advisory_xact_lock(hashtext($1))")
+                .bind::<diesel::sql_types::Text, _>(from_address)
+                .execute(conn)?;
+            f(conn)
+        })
     }
+}
 
-    Ok(result[0].local_share.to_owned())
+impl SpendingLock for SqliteConnection {
+    // an immediate transaction takes the database's write lock up front
+    fn locked_for_spending<T>(
+        &mut self,
+        _from_address: &str,
+        f: impl FnOnce(&mut Self) -> QueryResult<T>,
+    ) -> QueryResult<T> {
+        self.immediate_transaction(f)
+    }
 }
 
-pub fn insert_new_key(conn: &mut PgConnection, id_data: i32, adress_data: &str, local_share_data: &str) -> QueryResult<Key> {
-    use crate::db::schema::share2_keys;
+/// The queries are the same for both backends, only the connection type differs.
+macro_rules! impl_storage {
+    ($connection:ty) => {
+        impl Storage for $connection {
+            fn get_key_by_address(&mut self, query_address: &str) -> QueryResult<Key> {
+                let mut result = share2_keys
+                    .filter(address.eq(query_address))
+                    .load::<Key>(self)?;
+
+                if result.len() == 0 {
+                    return Err(NotFound);
+                } else if result.len() != 1 {
+                    return Err(DatabaseError(
+                        UniqueViolation,
+                        Box::new("found multiple entries".to_string()),
+                    ));
+                }
+
+                Ok(result.remove(0))
+            }
+
+            fn insert_new_key(
+                &mut self,
+                id_data: i32,
+                adress_data: &str,
+                local_share_data: &str,
+            ) -> QueryResult<Key> {
+                use crate::db::schema::share2_keys;
+
+                let sealed =
+                    tss_common::db::seal_local_share(KEYS_TABLE, id_data, local_share_data);
+                let now = Utc::now().naive_utc();
+                diesel::insert_into(share2_keys::table)
+                    .values((
+                        id.eq(id_data),
+                        address.eq(adress_data),
+                        local_share.eq(sealed.local_share),
+                        data_key.eq(sealed.data_key),
+                        master_key_id.eq(sealed.master_key_id),
+                        status.eq(KeyStatus::Active.as_str()),
+                        created_at.eq(now),
+                        updated_at.eq(now),
+                    ))
+                    .get_result(self)
+            }
+
+            fn rewrap_local_shares(&mut self, previous: Option<&MasterKey>) -> QueryResult<usize> {
+                self.transaction(|conn| {
+                    let stored_keys = share2_keys.load::<Key>(conn)?;
+                    let mut updated = 0;
 
-    diesel::insert_into(share2_keys::table)
-        .values((id.eq(id_data), address.eq(adress_data), local_share.eq(local_share_data)))
-        .get_result(conn)
+                    for key in stored_keys {
+                        let sealed =
+                            match tss_common::db::reseal_local_share(KEYS_TABLE, &key, previous)? {
+                                Some(sealed) => sealed,
+                                None => continue,
+                            };
+                        diesel::update(share2_keys.find(key.id))
+                            .set((
+                                local_share.eq(sealed.local_share),
+                                data_key.eq(sealed.data_key),
+                                master_key_id.eq(sealed.master_key_id),
+                            ))
+                            .execute(conn)?;
+                        updated += 1;
+                    }
+
+                    Ok(updated)
+                })
+            }
+
+            fn set_key_status(&mut self, id_data: i32, new_status: KeyStatus) -> QueryResult<Key> {
+                let updated = diesel::update(
+                    share2_keys
+                        .find(id_data)
+                        .filter(status.eq_any(allowed_from(new_status))),
+                )
+                .set((
+                    status.eq(new_status.as_str()),
+                    updated_at.eq(Utc::now().naive_utc()),
+                ))
+                .get_result::<Key>(self)
+                .optional()?;
+
+                match updated {
+                    Some(key) => Ok(key),
+                    None => Err(invalid_transition(
+                        &share2_keys.find(id_data).first::<Key>(self)?,
+                        new_status,
+                    )),
+                }
+            }
+
+            fn claim_processed_request(
+                &mut self,
+                request_id_data: &str,
+                signal_data: &str,
+                signal_id_data: &str,
+                claimed_at_data: NaiveDateTime,
+                stale_before: NaiveDateTime,
+            ) -> QueryResult<Option<ProcessedRequest>> {
+                use crate::db::schema::processed_requests;
+
+                let inserted = diesel::insert_into(processed_requests::table)
+                    .values((
+                        processed_requests::request_id.eq(request_id_data),
+                        processed_requests::signal.eq(signal_data),
+                        processed_requests::signal_id.eq(signal_id_data),
+                        processed_requests::created_at.eq(claimed_at_data),
+                    ))
+                    .on_conflict_do_nothing()
+                    .execute(self)?;
+                if inserted == 1 {
+                    return Ok(None);
+                }
+
+                let taken_over = diesel::update(
+                    processed_requests::table
+                        .find(request_id_data)
+                        .filter(processed_requests::result.is_null())
+                        .filter(processed_requests::created_at.lt(stale_before)),
+                )
+                .set(processed_requests::created_at.eq(claimed_at_data))
+                .execute(self)?;
+                if taken_over == 1 {
+                    return Ok(None);
+                }
+
+                processed_requests::table
+                    .find(request_id_data)
+                    .first::<ProcessedRequest>(self)
+                    .map(Some)
+            }
+
+            fn get_processed_request(
+                &mut self,
+                request_id_data: &str,
+            ) -> QueryResult<ProcessedRequest> {
+                use crate::db::schema::processed_requests;
+
+                processed_requests::table
+                    .find(request_id_data)
+                    .first::<ProcessedRequest>(self)
+            }
+
+            fn set_sign_request_result(
+                &mut self,
+                tx_id_data: i32,
+                result_data: Option<&str>,
+            ) -> QueryResult<usize> {
+                use crate::db::schema::processed_requests;
+
+                let handled = processed_requests::table
+                    .filter(processed_requests::signal.eq("sign"))
+                    .filter(processed_requests::signal_id.eq(tx_id_data.to_string()));
+                let now = Utc::now().naive_utc();
+                match result_data {
+                    Some(result_data) => diesel::update(handled)
+                        .set((
+                            processed_requests::result.eq(result_data),
+                            processed_requests::completed_at.eq(now),
+                        ))
+                        .execute(self),
+                    None => diesel::update(handled)
+                        .set((
+                            processed_requests::result.eq(None::<String>),
+                            processed_requests::created_at.eq(now),
+                            processed_requests::completed_at.eq(None::<NaiveDateTime>),
+                        ))
+                        .execute(self),
+                }
+            }
+
+            fn complete_processed_request(
+                &mut self,
+                request_id_data: &str,
+                claimed_at_data: NaiveDateTime,
+                result_data: Option<&str>,
+            ) -> QueryResult<()> {
+                use crate::db::schema::processed_requests;
+
+                let claim = processed_requests::table
+                    .find(request_id_data)
+                    .filter(processed_requests::result.is_null())
+                    .filter(processed_requests::created_at.eq(claimed_at_data));
+                let completed = match result_data {
+                    Some(result_data) => diesel::update(claim)
+                        .set((
+                            processed_requests::result.eq(result_data),
+                            processed_requests::completed_at.eq(Utc::now().naive_utc()),
+                        ))
+                        .execute(self)?,
+                    None => diesel::delete(claim).execute(self)?,
+                };
+                match completed {
+                    0 => Err(diesel::result::Error::NotFound),
+                    _ => Ok(()),
+                }
+            }
+
+            fn complete_submitted_request(
+                &mut self,
+                request_id_data: &str,
+                claimed_at_data: NaiveDateTime,
+                result_data: &str,
+                tx_id_data: i32,
+                signature_data: &str,
+                next_attempt_at_data: NaiveDateTime,
+            ) -> QueryResult<Option<OutboxItem>> {
+                self.transaction(|conn| {
+                    conn.complete_processed_request(
+                        request_id_data,
+                        claimed_at_data,
+                        Some(result_data),
+                    )?;
+                    conn.insert_outbox_item(tx_id_data, signature_data, next_attempt_at_data)
+                })
+            }
+
+            fn complete_approved_tx(
+                &mut self,
+                tx_id_data: i32,
+                result_data: &str,
+                signature_data: &str,
+                next_attempt_at_data: NaiveDateTime,
+            ) -> QueryResult<Option<OutboxItem>> {
+                self.transaction(|conn| {
+                    conn.set_sign_request_result(tx_id_data, Some(result_data))?;
+                    conn.insert_outbox_item(tx_id_data, signature_data, next_attempt_at_data)
+                })
+            }
+
+            fn insert_outbox_item(
+                &mut self,
+                tx_id_data: i32,
+                signature_data: &str,
+                next_attempt_at_data: NaiveDateTime,
+            ) -> QueryResult<Option<OutboxItem>> {
+                use crate::db::schema::submit_outbox;
+
+                diesel::insert_into(submit_outbox::table)
+                    .values((
+                        submit_outbox::tx_id.eq(tx_id_data),
+                        submit_outbox::signature.eq(signature_data),
+                        submit_outbox::status.eq("pending"),
+                        submit_outbox::next_attempt_at.eq(next_attempt_at_data),
+                        submit_outbox::created_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .on_conflict_do_nothing()
+                    .get_result(self)
+                    .optional()
+            }
+
+            fn get_due_outbox_items(&mut self, limit: i64) -> QueryResult<Vec<OutboxItem>> {
+                use crate::db::schema::submit_outbox;
+
+                submit_outbox::table
+                    .filter(submit_outbox::status.eq("pending"))
+                    .filter(submit_outbox::next_attempt_at.le(Utc::now().naive_utc()))
+                    .order(submit_outbox::next_attempt_at.asc())
+                    .limit(limit)
+                    .load::<OutboxItem>(self)
+            }
+
+            fn get_outbox_items(&mut self, status_data: &str) -> QueryResult<Vec<OutboxItem>> {
+                use crate::db::schema::submit_outbox;
+
+                submit_outbox::table
+                    .filter(submit_outbox::status.eq(status_data))
+                    .order(submit_outbox::created_at.asc())
+                    .load::<OutboxItem>(self)
+            }
+
+            fn mark_outbox_delivered(&mut self, id_data: i32) -> QueryResult<OutboxItem> {
+                use crate::db::schema::submit_outbox;
+
+                diesel::update(submit_outbox::table.find(id_data))
+                    .set((
+                        submit_outbox::status.eq("delivered"),
+                        submit_outbox::attempts.eq(submit_outbox::attempts + 1),
+                        submit_outbox::delivered_at.eq(Utc::now().naive_utc()),
+                    ))
+                    .get_result(self)
+            }
+
+            fn mark_outbox_failed(
+                &mut self,
+                id_data: i32,
+                error_data: &str,
+                next_attempt_at_data: Option<NaiveDateTime>,
+            ) -> QueryResult<OutboxItem> {
+                use crate::db::schema::submit_outbox;
+
+                let (status_data, next_attempt_at_data) = match next_attempt_at_data {
+                    Some(next_attempt_at_data) => ("pending", next_attempt_at_data),
+                    None => ("undeliverable", Utc::now().naive_utc()),
+                };
+                diesel::update(submit_outbox::table.find(id_data))
+                    .set((
+                        submit_outbox::status.eq(status_data),
+                        submit_outbox::attempts.eq(submit_outbox::attempts + 1),
+                        submit_outbox::last_error.eq(error_data),
+                        submit_outbox::next_attempt_at.eq(next_attempt_at_data),
+                    ))
+                    .get_result(self)
+            }
+
+            fn retry_outbox_item(&mut self, id_data: i32) -> QueryResult<OutboxItem> {
+                use crate::db::schema::submit_outbox;
+
+                diesel::update(
+                    submit_outbox::table
+                        .filter(submit_outbox::id.eq(id_data))
+                        .filter(submit_outbox::status.eq("undeliverable")),
+                )
+                .set((
+                    submit_outbox::status.eq("pending"),
+                    submit_outbox::attempts.eq(0),
+                    submit_outbox::next_attempt_at.eq(Utc::now().naive_utc()),
+                ))
+                .get_result(self)
+            }
+
+            fn get_value_signed_since(
+                &mut self,
+                query_address: &str,
+                since: NaiveDateTime,
+            ) -> QueryResult<u128> {
+                use self::schema::signed_txs::dsl::{from_address, signed_at, signed_txs, value};
+
+                let values = signed_txs
+                    .filter(from_address.eq(query_address.to_lowercase()))
+                    .filter(signed_at.ge(since))
+                    .select(value)
+                    .load::<String>(self)?;
+
+                Ok(values
+                    .iter()
+                    .filter_map(|v| v.parse::<u128>().ok())
+                    .fold(0, u128::saturating_add))
+            }
+
+            fn reserve_signed_tx(
+                &mut self,
+                tx_id_data: i32,
+                from_address_data: &str,
+                value_data: u128,
+                since: NaiveDateTime,
+                check: &dyn Fn(u128) -> Result<(), String>,
+            ) -> QueryResult<Result<SignedTx, String>> {
+                use self::schema::signed_txs::dsl::{
+                    from_address, signed_at, signed_txs, tx_id, value,
+                };
+
+                let from_address_data = from_address_data.to_lowercase();
+                self.locked_for_spending(&from_address_data, |conn| {
+                    let spent = conn.get_value_signed_since(&from_address_data, since)?;
+                    if let Err(reason) = check(spent) {
+                        return Ok(Err(reason));
+                    }
+
+                    diesel::insert_into(signed_txs)
+                        .values((
+                            tx_id.eq(tx_id_data),
+                            from_address.eq(&from_address_data),
+                            value.eq(value_data.to_string()),
+                            signed_at.eq(Utc::now().naive_utc()),
+                        ))
+                        .get_result(conn)
+                        .map(Ok)
+                })
+            }
+
+            fn release_signed_tx(&mut self, id_data: i32) -> QueryResult<()> {
+                use self::schema::signed_txs::dsl::signed_txs;
+
+                diesel::delete(signed_txs.find(id_data)).execute(self)?;
+                Ok(())
+            }
+
+            fn insert_pending_approval(
+                &mut self,
+                id_data: i32,
+                from_address_data: &str,
+                sign_signal_data: &str,
+                reason_data: &str,
+                expires_at_data: NaiveDateTime,
+            ) -> QueryResult<PendingApproval> {
+                use self::schema::pending_approvals::dsl::*;
+
+                diesel::insert_into(pending_approvals)
+                    .values((
+                        id.eq(id_data),
+                        from_address.eq(from_address_data),
+                        sign_signal.eq(sign_signal_data),
+                        reason.eq(reason_data),
+                        status.eq("pending"),
+                        created_at.eq(Utc::now().naive_utc()),
+                        expires_at.eq(expires_at_data),
+                    ))
+                    .get_result(self)
+            }
+
+            fn get_pending_approvals(&mut self) -> QueryResult<Vec<PendingApproval>> {
+                use self::schema::pending_approvals::dsl::*;
+
+                pending_approvals
+                    .filter(status.eq("pending"))
+                    .order(created_at.asc())
+                    .load::<PendingApproval>(self)
+            }
+
+            fn get_pending_approval(&mut self, id_data: i32) -> QueryResult<PendingApproval> {
+                use self::schema::pending_approvals::dsl::*;
+
+                pending_approvals
+                    .filter(id.eq(id_data))
+                    .filter(status.eq("pending"))
+                    .filter(expires_at.gt(Utc::now().naive_utc()))
+                    .first::<PendingApproval>(self)
+            }
+
+            fn decide_pending_approval(
+                &mut self,
+                id_data: i32,
+                new_status: &str,
+                result_data: Option<&str>,
+            ) -> QueryResult<PendingApproval> {
+                use self::schema::pending_approvals::dsl::*;
+
+                self.transaction(|conn| {
+                    let now = Utc::now().naive_utc();
+                    let decided = diesel::update(
+                        pending_approvals
+                            .filter(id.eq(id_data))
+                            .filter(status.eq("pending"))
+                            .filter(expires_at.gt(now)),
+                    )
+                    .set((status.eq(new_status), decided_at.eq(now)))
+                    .get_result::<PendingApproval>(conn)?;
+                    conn.set_sign_request_result(id_data, result_data)?;
+                    Ok(decided)
+                })
+            }
+
+            fn expire_pending_approvals(
+                &mut self,
+                result_for: &dyn Fn(&PendingApproval) -> String,
+            ) -> QueryResult<Vec<PendingApproval>> {
+                use self::schema::pending_approvals::dsl::*;
+
+                self.transaction(|conn| {
+                    let now = Utc::now().naive_utc();
+                    let expired = diesel::update(
+                        pending_approvals
+                            .filter(status.eq("pending"))
+                            .filter(expires_at.le(now)),
+                    )
+                    .set((status.eq("expired"), decided_at.eq(now)))
+                    .get_results::<PendingApproval>(conn)?;
+                    for approval in &expired {
+                        conn.set_sign_request_result(approval.id, Some(&result_for(approval)))?;
+                    }
+                    Ok(expired)
+                })
+            }
+        }
+    };
 }
+
+impl_storage!(PgConnection);
+impl_storage!(SqliteConnection);
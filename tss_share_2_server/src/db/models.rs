@@ -1,8 +1,49 @@
+use chrono::NaiveDateTime;
 use diesel::prelude::*;
 
+pub use tss_common::db::models::{Key, KeyStatus};
+
+#[derive(Queryable, Debug)]
+pub struct SignedTx {
+    pub id: i32,
+    pub tx_id: i32,
+    pub from_address: String,
+    pub value: String,
+    pub signed_at: NaiveDateTime,
+}
+
+#[derive(Queryable, Debug)]
+pub struct PendingApproval {
+    pub id: i32,
+    pub from_address: String,
+    pub sign_signal: String,
+    pub reason: String,
+    pub status: String,
+    pub created_at: NaiveDateTime,
+    pub expires_at: NaiveDateTime,
+    pub decided_at: Option<NaiveDateTime>,
+}
+
+#[derive(Queryable, Debug)]
+pub struct ProcessedRequest {
+    pub request_id: String,
+    pub signal: String,
+    pub signal_id: String,
+    /// JSON of the result, `None` while the signal is being handled
+    pub result: Option<String>,
+    pub created_at: NaiveDateTime,
+    pub completed_at: Option<NaiveDateTime>,
+}
+
 #[derive(Queryable, Debug)]
-pub struct Key {
+pub struct OutboxItem {
     pub id: i32,
-    pub address: String,
-    pub local_share: String,
+    pub tx_id: i32,
+    pub signature: String,
+    pub status: String,
+    pub attempts: i32,
+    pub last_error: Option<String>,
+    pub next_attempt_at: NaiveDateTime,
+    pub created_at: NaiveDateTime,
+    pub delivered_at: Option<NaiveDateTime>,
 }
@@ -5,6 +5,35 @@ diesel::table! {
         id -> Int4,
         address -> Nullable<Varchar>,
         local_share -> Nullable<Text>,
+        data_key -> Nullable<Bytea>,
+        master_key_id -> Nullable<Varchar>,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    pending_approvals (id) {
+        id -> Int4,
+        from_address -> Varchar,
+        sign_signal -> Text,
+        reason -> Varchar,
+        status -> Varchar,
+        created_at -> Timestamp,
+        expires_at -> Timestamp,
+        decided_at -> Nullable<Timestamp>,
+    }
+}
+
+diesel::table! {
+    processed_requests (request_id) {
+        request_id -> Varchar,
+        signal -> Varchar,
+        signal_id -> Varchar,
+        result -> Nullable<Text>,
+        created_at -> Timestamp,
+        completed_at -> Nullable<Timestamp>,
     }
 }
 
@@ -13,10 +42,43 @@ diesel::table! {
         id -> Int4,
         address -> Varchar,
         local_share -> Text,
+        data_key -> Nullable<Bytea>,
+        master_key_id -> Nullable<Varchar>,
+        status -> Varchar,
+        created_at -> Timestamp,
+        updated_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    signed_txs (id) {
+        id -> Int4,
+        tx_id -> Int4,
+        from_address -> Varchar,
+        value -> Varchar,
+        signed_at -> Timestamp,
+    }
+}
+
+diesel::table! {
+    submit_outbox (id) {
+        id -> Int4,
+        tx_id -> Int4,
+        signature -> Text,
+        status -> Varchar,
+        attempts -> Int4,
+        last_error -> Nullable<Text>,
+        next_attempt_at -> Timestamp,
+        created_at -> Timestamp,
+        delivered_at -> Nullable<Timestamp>,
     }
 }
 
 diesel::allow_tables_to_appear_in_same_query!(
     keys,
+    pending_approvals,
+    processed_requests,
     share2_keys,
+    signed_txs,
+    submit_outbox,
 );
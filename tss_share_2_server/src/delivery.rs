@@ -0,0 +1,361 @@
+//! Settling of sign and keygen signal deliveries.
+//!
+//! A handled delivery is acked. A transient failure is published again to its
+//! queue with the `x-retry-count` header incremented, up to
+//! `RABBITMQ_MAX_RETRIES` times. The broker holds the copy for a delay growing
+//! with the count, so the original is acked right away and neither holds a
+//! prefetch slot nor loses its count on a restart. A permanent failure, or
+//! a transient one out of retries, is published to the dead-letter exchange
+//! with the reason in `x-failure-reason` and the queue as routing key; it ends
+//! up in `<queue>.dead-letter`. If the broker refuses or does not confirm
+//! either publish the delivery is nacked and requeued as is.
+
+use std::fmt;
+use std::time::Duration;
+
+use diesel::result::{DatabaseErrorKind, Error as QueryError};
+use tss_messages::MessageError;
+
+use crate::broker::{Broker, BrokerError, Message};
+use crate::db::DbError;
+use crate::{RABBITMQ_DEAD_LETTER_EXCHANGE, RABBITMQ_MAX_RETRIES, RABBITMQ_RETRY_DELAY_SECS};
+
+const RETRY_COUNT_HEADER: &str = "x-retry-count";
+const FAILURE_REASON_HEADER: &str = "x-failure-reason";
+
+#[derive(Debug)]
+pub enum DeliveryError {
+    /// Worth retrying, e.g. the database is unreachable
+    Transient(String),
+    /// Retrying cannot help, e.g. the message cannot be parsed
+    Permanent(String),
+}
+
+impl fmt::Display for DeliveryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeliveryError::Transient(reason) => write!(f, "transient: {}", reason),
+            DeliveryError::Permanent(reason) => write!(f, "permanent: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DeliveryError {}
+
+/// Constraint violations and missing rows are permanent: the same signal
+/// fails the same way again. Anything else, e.g. a dropped connection, a
+/// serialization failure or a deadlock, may pass on a retry.
+impl From<DbError> for DeliveryError {
+    fn from(e: DbError) -> Self {
+        match e {
+            DbError::Query(QueryError::NotFound)
+            | DbError::Query(QueryError::DatabaseError(
+                DatabaseErrorKind::UniqueViolation
+                | DatabaseErrorKind::ForeignKeyViolation
+                | DatabaseErrorKind::NotNullViolation
+                | DatabaseErrorKind::CheckViolation,
+                _,
+            )) => DeliveryError::Permanent(e.to_string()),
+            _ => DeliveryError::Transient(e.to_string()),
+        }
+    }
+}
+
+impl From<MessageError> for DeliveryError {
+    fn from(e: MessageError) -> Self {
+        DeliveryError::Permanent(e.to_string())
+    }
+}
+
+/// Acks, retries or dead-letters `message` from `queue` by the `result` of
+/// handling it.
+pub async fn settle(
+    broker: &dyn Broker,
+    queue: &str,
+    message: &Message,
+    result: Result<(), DeliveryError>,
+) {
+    let retry_count = retry_count(message);
+    let settled = match result {
+        Ok(()) => broker.ack(message).await,
+        Err(DeliveryError::Transient(reason)) if retry_count < *RABBITMQ_MAX_RETRIES => {
+            println!(
+                "delivery {} from {} failed, retry {} of {}: {}",
+                message.tag,
+                queue,
+                retry_count + 1,
+                *RABBITMQ_MAX_RETRIES,
+                reason
+            );
+            let mut headers = message.headers.clone();
+            headers.insert(
+                RETRY_COUNT_HEADER.to_string(),
+                (retry_count + 1).to_string(),
+            );
+            let delay = Duration::from_secs(*RABBITMQ_RETRY_DELAY_SECS * (retry_count as u64 + 1));
+            let published = broker
+                .publish_delayed(queue, &message.data, headers, delay)
+                .await;
+            ack_or_requeue(broker, message, published).await
+        }
+        Err(e) => {
+            println!(
+                "dead-lettering delivery {} from {} after {} retries: {}",
+                message.tag, queue, retry_count, e
+            );
+            let mut headers = message.headers.clone();
+            headers.insert(FAILURE_REASON_HEADER.to_string(), e.to_string());
+            let published = broker
+                .publish(
+                    &RABBITMQ_DEAD_LETTER_EXCHANGE,
+                    queue,
+                    &message.data,
+                    headers,
+                )
+                .await;
+            ack_or_requeue(broker, message, published).await
+        }
+    };
+    if let Err(e) = settled {
+        println!(
+            "error settling delivery {} from {}: {}",
+            message.tag, queue, e
+        );
+    }
+}
+
+/// Acks `message` once its copy is `published`, or requeues it if the copy
+/// could not be published.
+async fn ack_or_requeue(
+    broker: &dyn Broker,
+    message: &Message,
+    published: Result<(), BrokerError>,
+) -> Result<(), BrokerError> {
+    match published {
+        Ok(()) => broker.ack(message).await,
+        Err(e) => {
+            println!(
+                "error republishing delivery {}, requeueing it: {}",
+                message.tag, e
+            );
+            broker.nack(message, true).await
+        }
+    }
+}
+
+fn retry_count(message: &Message) -> u32 {
+    message
+        .headers
+        .get(RETRY_COUNT_HEADER)
+        .and_then(|count| count.parse::<u32>().ok())
+        .unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use futures::StreamExt;
+
+    use super::*;
+    use crate::broker::memory::MemoryBroker;
+    use crate::broker::{Deliveries, Headers};
+
+    const QUEUE: &str = "signals";
+    const DEAD_LETTER_QUEUE: &str = "signals.dead-letter";
+
+    fn broker() -> MemoryBroker {
+        let broker = MemoryBroker::default();
+        broker.bind(&RABBITMQ_DEAD_LETTER_EXCHANGE, QUEUE, DEAD_LETTER_QUEUE);
+        broker
+    }
+
+    /// Publishes a message to `QUEUE` and takes its delivery.
+    async fn deliver(broker: &dyn Broker, headers: Headers) -> (Deliveries, Message) {
+        let mut deliveries = broker.consume(QUEUE, 1).await.unwrap();
+        broker.publish("", QUEUE, b"{}", headers).await.unwrap();
+        let message = deliveries.next().await.unwrap().unwrap();
+        (deliveries, message)
+    }
+
+    fn retried(count: u32) -> Headers {
+        let mut headers = Headers::new();
+        headers.insert(RETRY_COUNT_HEADER.to_string(), count.to_string());
+        headers
+    }
+
+    fn database_error(kind: DatabaseErrorKind) -> DbError {
+        DbError::Query(QueryError::DatabaseError(kind, Box::new(String::new())))
+    }
+
+    #[test]
+    fn splits_database_errors_by_kind() {
+        for e in [
+            database_error(DatabaseErrorKind::SerializationFailure),
+            database_error(DatabaseErrorKind::ClosedConnection),
+            database_error(DatabaseErrorKind::UnableToSendCommand),
+            database_error(DatabaseErrorKind::Unknown),
+        ] {
+            assert!(matches!(
+                DeliveryError::from(e),
+                DeliveryError::Transient(_)
+            ));
+        }
+        for e in [
+            database_error(DatabaseErrorKind::UniqueViolation),
+            database_error(DatabaseErrorKind::ForeignKeyViolation),
+            database_error(DatabaseErrorKind::NotNullViolation),
+            database_error(DatabaseErrorKind::CheckViolation),
+            DbError::Query(QueryError::NotFound),
+        ] {
+            assert!(matches!(
+                DeliveryError::from(e),
+                DeliveryError::Permanent(_)
+            ));
+        }
+    }
+
+    #[tokio::test]
+    async fn acks_handled_delivery() {
+        let broker = broker();
+        let (_deliveries, message) = deliver(&broker, Headers::new()).await;
+
+        settle(&broker, QUEUE, &message, Ok(())).await;
+        assert_eq!(broker.unacked(), 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retries_transient_failure_with_count() {
+        let broker = broker();
+        let (mut deliveries, message) = deliver(&broker, retried(1)).await;
+
+        let failure = Err(DeliveryError::Transient("database down".to_string()));
+        settle(&broker, QUEUE, &message, failure).await;
+
+        // the copy is published and the original acked
+        assert_eq!(broker.unacked(), 1);
+        let started = tokio::time::Instant::now();
+        let retry = deliveries.next().await.unwrap().unwrap();
+        assert_ne!(retry.tag, message.tag);
+        assert_eq!(retry.data, message.data);
+        assert_eq!(retry_count(&retry), 2);
+        assert!(started.elapsed() >= Duration::from_secs(*RABBITMQ_RETRY_DELAY_SECS * 2));
+    }
+
+    #[tokio::test]
+    async fn dead_letters_transient_failure_out_of_retries() {
+        let broker = broker();
+        let mut dead_letters = broker.consume(DEAD_LETTER_QUEUE, 1).await.unwrap();
+        let (_deliveries, message) = deliver(&broker, retried(*RABBITMQ_MAX_RETRIES)).await;
+
+        let failure = Err(DeliveryError::Transient("database down".to_string()));
+        settle(&broker, QUEUE, &message, failure).await;
+
+        let dead_letter = dead_letters.next().await.unwrap().unwrap();
+        assert_eq!(dead_letter.data, message.data);
+        assert_eq!(
+            dead_letter.headers.get(FAILURE_REASON_HEADER).unwrap(),
+            "transient: database down"
+        );
+        assert_eq!(broker.unacked(), 1);
+    }
+
+    #[tokio::test]
+    async fn dead_letters_permanent_failure() {
+        let broker = broker();
+        let mut dead_letters = broker.consume(DEAD_LETTER_QUEUE, 1).await.unwrap();
+        let (_deliveries, message) = deliver(&broker, Headers::new()).await;
+
+        let failure = Err(DeliveryError::Permanent("malformed".to_string()));
+        settle(&broker, QUEUE, &message, failure).await;
+
+        let dead_letter = dead_letters.next().await.unwrap().unwrap();
+        assert_eq!(retry_count(&dead_letter), 0);
+        assert_eq!(
+            dead_letter.headers.get(FAILURE_REASON_HEADER).unwrap(),
+            "permanent: malformed"
+        );
+    }
+
+    /// Refuses every publish after the first.
+    struct RefusingBroker {
+        broker: MemoryBroker,
+        published: std::sync::atomic::AtomicBool,
+    }
+
+    #[rocket::async_trait]
+    impl Broker for RefusingBroker {
+        async fn consume(&self, queue: &str, prefetch: u16) -> Result<Deliveries, BrokerError> {
+            self.broker.consume(queue, prefetch).await
+        }
+
+        async fn ack(&self, message: &Message) -> Result<(), BrokerError> {
+            self.broker.ack(message).await
+        }
+
+        async fn nack(&self, message: &Message, requeue: bool) -> Result<(), BrokerError> {
+            self.broker.nack(message, requeue).await
+        }
+
+        async fn publish(
+            &self,
+            exchange: &str,
+            routing_key: &str,
+            data: &[u8],
+            headers: Headers,
+        ) -> Result<(), BrokerError> {
+            if self
+                .published
+                .swap(true, std::sync::atomic::Ordering::SeqCst)
+            {
+                return Err(BrokerError("refused".to_string()));
+            }
+            self.broker
+                .publish(exchange, routing_key, data, headers)
+                .await
+        }
+
+        async fn publish_delayed(
+            &self,
+            _queue: &str,
+            _data: &[u8],
+            _headers: Headers,
+            _delay: Duration,
+        ) -> Result<(), BrokerError> {
+            Err(BrokerError("refused".to_string()))
+        }
+    }
+
+    #[tokio::test]
+    async fn requeues_delivery_when_retry_is_refused() {
+        let broker = RefusingBroker {
+            broker: broker(),
+            published: Default::default(),
+        };
+        let (mut deliveries, message) = deliver(&broker, Headers::new()).await;
+
+        let failure = Err(DeliveryError::Transient("database down".to_string()));
+        settle(&broker, QUEUE, &message, failure).await;
+
+        // the original comes back as is
+        let requeued = deliveries.next().await.unwrap().unwrap();
+        assert_eq!(requeued.data, message.data);
+        assert_eq!(retry_count(&requeued), 0);
+    }
+
+    #[tokio::test]
+    async fn requeues_delivery_when_dead_letter_is_refused() {
+        let broker = RefusingBroker {
+            broker: broker(),
+            published: Default::default(),
+        };
+        let (mut deliveries, message) = deliver(&broker, Headers::new()).await;
+
+        let failure = Err(DeliveryError::Permanent("malformed".to_string()));
+        settle(&broker, QUEUE, &message, failure).await;
+
+        let requeued = deliveries.next().await.unwrap().unwrap();
+        assert_eq!(requeued.data, message.data);
+        assert!(!requeued.headers.contains_key(FAILURE_REASON_HEADER));
+    }
+}
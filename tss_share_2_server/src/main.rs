@@ -1,17 +1,32 @@
+pub mod approval;
+pub mod auth;
+pub mod broker;
+pub mod consumer;
 pub mod db;
+pub mod delivery;
+pub mod outbox;
+pub mod policy;
+pub mod processed;
+pub mod signals;
 
-use crypto::digest::Digest;
-use crypto::sha3::Sha3;
+use chrono::{NaiveDateTime, Utc};
+use db::models::{KeyStatus, SignedTx};
+use db::{DbError, DbPool};
+use delivery::DeliveryError;
 use dotenv::dotenv;
-use futures::StreamExt;
-use lapin::{options::*, types::FieldTable, Connection, ConnectionProperties};
-use lazy_static::{lazy_static, __Deref};
-use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use lazy_static::lazy_static;
+use policy::Policy;
+use serde::Serialize;
+use std::path::PathBuf;
+use std::time::Duration;
+use structopt::StructOpt;
+use tss_common::{address, backup, config, envelope, share};
+use tss_messages::{
+    Envelope, KeygenSignal, PendingTx, RejectTx, SignSignal, SignalResult, SubmitTx,
+};
 
 lazy_static! {
-    static ref RABBITMQ_HOST: String =
-        std::env::var("RABBITMQ_HOST").expect("RABBITMQ_HOST should be set");
+    static ref RABBITMQ_HOST: Option<String> = std::env::var("RABBITMQ_HOST").ok();
     static ref RABBITMQ_PORT: String =
         std::env::var("RABBITMQ_PORT").expect("RABBITMQ_PORT should be set");
     static ref RABBITMQ_SIGN_SIGNAL_QUEUE_NAME: String =
@@ -24,158 +39,607 @@ lazy_static! {
     static ref RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME: String =
         std::env::var("RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME")
             .expect("RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME should be set");
+    static ref RABBITMQ_DEAD_LETTER_EXCHANGE: String =
+        std::env::var("RABBITMQ_DEAD_LETTER_EXCHANGE")
+            .unwrap_or_else(|_| "share-2-server-dead-letter".to_string());
+    static ref RABBITMQ_MAX_RETRIES: u32 = std::env::var("RABBITMQ_MAX_RETRIES")
+        .map(|retries| retries
+            .parse::<u32>()
+            .expect("RABBITMQ_MAX_RETRIES should be a number"))
+        .unwrap_or(5);
+    // a limit of 0 would never handle a signal
+    static ref SIGN_CONCURRENCY: usize = std::env::var("SIGN_CONCURRENCY")
+        .map(|limit| limit
+            .parse::<usize>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .expect("SIGN_CONCURRENCY should be a positive number"))
+        .unwrap_or(4);
+    static ref KEYGEN_CONCURRENCY: usize = std::env::var("KEYGEN_CONCURRENCY")
+        .map(|limit| limit
+            .parse::<usize>()
+            .ok()
+            .filter(|limit| *limit > 0)
+            .expect("KEYGEN_CONCURRENCY should be a positive number"))
+        .unwrap_or(2);
+    // a prefetch of 0 lets the broker push without limit
+    static ref RABBITMQ_SIGN_PREFETCH: u16 = std::env::var("RABBITMQ_SIGN_PREFETCH")
+        .map(|count| count
+            .parse::<u16>()
+            .ok()
+            .filter(|count| *count > 0)
+            .expect("RABBITMQ_SIGN_PREFETCH should be a positive number"))
+        .unwrap_or_else(|_| u16::try_from(*SIGN_CONCURRENCY)
+            .expect("SIGN_CONCURRENCY should fit RABBITMQ_SIGN_PREFETCH, at most 65535"));
+    static ref RABBITMQ_KEYGEN_PREFETCH: u16 = std::env::var("RABBITMQ_KEYGEN_PREFETCH")
+        .map(|count| count
+            .parse::<u16>()
+            .ok()
+            .filter(|count| *count > 0)
+            .expect("RABBITMQ_KEYGEN_PREFETCH should be a positive number"))
+        .unwrap_or_else(|_| u16::try_from(*KEYGEN_CONCURRENCY)
+            .expect("KEYGEN_CONCURRENCY should fit RABBITMQ_KEYGEN_PREFETCH, at most 65535"));
+    static ref RABBITMQ_RECONNECT_MIN_SECS: u64 = std::env::var("RABBITMQ_RECONNECT_MIN_SECS")
+        .map(|secs| secs
+            .parse::<u64>()
+            .expect("RABBITMQ_RECONNECT_MIN_SECS should be a number"))
+        .unwrap_or(1);
+    static ref RABBITMQ_RECONNECT_MAX_SECS: u64 = std::env::var("RABBITMQ_RECONNECT_MAX_SECS")
+        .map(|secs| secs
+            .parse::<u64>()
+            .expect("RABBITMQ_RECONNECT_MAX_SECS should be a number"))
+        .unwrap_or(60);
+    static ref RABBITMQ_RETRY_DELAY_SECS: u64 = std::env::var("RABBITMQ_RETRY_DELAY_SECS")
+        .map(|delay| delay
+            .parse::<u64>()
+            .expect("RABBITMQ_RETRY_DELAY_SECS should be a number"))
+        .unwrap_or(5);
+    static ref MPC_TIMEOUT_SECS: u64 = std::env::var("MPC_TIMEOUT_SECS")
+        .map(|secs| secs
+            .parse::<u64>()
+            .expect("MPC_TIMEOUT_SECS should be a number"))
+        .unwrap_or(120);
+    static ref PROCESSED_CLAIM_TIMEOUT_SECS: i64 = std::env::var("PROCESSED_CLAIM_TIMEOUT_SECS")
+        .map(|secs| secs
+            .parse::<i64>()
+            .expect("PROCESSED_CLAIM_TIMEOUT_SECS should be a number"))
+        .unwrap_or(600);
+    static ref PORT: String = std::env::var("PORT").expect("PORT should be set");
+    static ref HTTP_CLIENT: reqwest::Client = config::http_client();
+    static ref SHARE_2_API_KEY: String =
+        std::env::var("SHARE_2_API_KEY").expect("SHARE_2_API_KEY should be set");
+    static ref SIGNAL_API_KEY: Option<String> = std::env::var("SIGNAL_API_KEY").ok();
+    static ref APPROVAL_TTL_SECS: i64 = std::env::var("APPROVAL_TTL_SECS")
+        .map(|ttl| ttl.parse::<i64>().expect("APPROVAL_TTL_SECS should be a number"))
+        .unwrap_or(3600);
+    static ref OUTBOX_MAX_ATTEMPTS: i32 = std::env::var("OUTBOX_MAX_ATTEMPTS")
+        .map(|attempts| attempts
+            .parse::<i32>()
+            .expect("OUTBOX_MAX_ATTEMPTS should be a number"))
+        .unwrap_or(20);
+    static ref OUTBOX_RETRY_MIN_SECS: u64 = std::env::var("OUTBOX_RETRY_MIN_SECS")
+        .map(|secs| secs
+            .parse::<u64>()
+            .expect("OUTBOX_RETRY_MIN_SECS should be a number"))
+        .unwrap_or(5);
+    static ref OUTBOX_RETRY_MAX_SECS: u64 = std::env::var("OUTBOX_RETRY_MAX_SECS")
+        .map(|secs| secs
+            .parse::<u64>()
+            .expect("OUTBOX_RETRY_MAX_SECS should be a number"))
+        .unwrap_or(600);
+    static ref POLICY: Policy = match std::env::var("POLICY_PATH") {
+        Ok(path) => Policy::load(&path),
+        Err(_) => Policy::default(),
+    };
 }
 
-#[derive(Serialize, Deserialize, Debug)]
-struct SignSignal {
-    from_address: String,
-    id: usize,
-    message: String,
-}
-
-#[derive(Serialize, Deserialize, Debug)]
-#[allow(non_snake_case)]
-struct RabbitMQDelivery {
-    data: String,
-    requestId: String,
-}
+/// Share 2's index in the 2-party keys it holds with the client server.
+const PARTY_INDEX: u16 = 1;
 
 #[derive(Serialize)]
 #[serde(crate = "rocket::serde")]
 struct SignRes {
     success: bool,
+    info: Option<String>,
 }
 
-fn pubkey_to_address(uncompressed_pubkey: Vec<u8>) -> String {
-    let mut hasher = Sha3::keccak256();
-    hasher.input(&uncompressed_pubkey[1..]);
-    let hash_result = hasher.result_str();
-    let address = format!("0x{}", &hash_result[24..]);
-    address
+async fn reject_tx(id: usize, reason: &str) {
+    let client = &*HTTP_CLIENT;
+    let body = RejectTx {
+        id,
+        reason: reason.to_string(),
+    };
+
+    if let Err(error) = client
+        .post(format!("{}/reject-tx", *TX_SENDER_URL))
+        .json(&body)
+        .send()
+        .await
+    {
+        println!("error on calling tx sender: {:?}", error);
+    }
 }
 
-#[tokio::main]
-async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    dotenv().ok();
-    let conn = Connection::connect(
-        &format!("amqp://{}:{}", *RABBITMQ_HOST, *RABBITMQ_PORT),
-        ConnectionProperties::default(),
-    )
-    .await?;
-
-    let channel = conn.create_channel().await?;
-    let mut consume_options = BasicConsumeOptions::default();
-    consume_options.no_ack = false;
-    let mut sign_tx_consumer = channel
-        .basic_consume(
-            &*RABBITMQ_SIGN_SIGNAL_QUEUE_NAME,
-            "sign-signal-consumer",
-            consume_options,
-            FieldTable::default(),
-        )
-        .await?;
-
-    let mut keygen_consumer = channel
-        .basic_consume(
-            &*RABBITMQ_KEYGEN_SIGNAL_QUEUE_NAME,
-            "keygen-signal-consumer",
-            consume_options,
-            FieldTable::default(),
+/// Rejects a sign signal at the tx sender.
+async fn rejected(id: usize, reason: &str) -> SignalResult {
+    reject_tx(id, reason).await;
+    SignalResult::Rejected {
+        id,
+        reason: reason.to_string(),
+    }
+}
+
+async fn submit_tx(id: usize, signature: &str) -> reqwest::Result<()> {
+    let client = &*HTTP_CLIENT;
+    let body = SubmitTx {
+        id,
+        signature: signature.to_string(),
+    };
+
+    client
+        .post(format!("{}/submit-tx", *TX_SENDER_URL))
+        .json(&body)
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+async fn notify_pending_tx(id: usize, reason: &str, expires_at: NaiveDateTime) {
+    let client = &*HTTP_CLIENT;
+    let body = PendingTx {
+        id,
+        reason: reason.to_string(),
+        expires_at,
+    };
+
+    if let Err(error) = client
+        .post(format!("{}/pending-tx", *TX_SENDER_URL))
+        .json(&body)
+        .send()
+        .await
+    {
+        println!("error on calling tx sender: {:?}", error);
+    }
+}
+
+/// Bounds a keygen or signing run with the other party by `MPC_TIMEOUT_SECS`,
+/// so a party that never joins does not hold a concurrency slot forever.
+async fn with_mpc_timeout<T, E: std::fmt::Debug>(
+    protocol: impl std::future::Future<Output = Result<T, E>>,
+) -> Result<T, String> {
+    match tokio::time::timeout(Duration::from_secs(*MPC_TIMEOUT_SECS), protocol).await {
+        Ok(Ok(output)) => Ok(output),
+        Ok(Err(e)) => Err(format!("{:?}", e)),
+        Err(_) => Err(format!("timed out after {}s", *MPC_TIMEOUT_SECS)),
+    }
+}
+
+/// The id of a tx as stored, refusing ids the tables cannot hold rather
+/// than writing them under another tx's id.
+fn stored_tx_id(id: usize) -> Result<i32, DeliveryError> {
+    i32::try_from(id).map_err(|_| DeliveryError::Permanent(format!("tx id {} is out of range", id)))
+}
+
+/// Parks a sign signal until an operator approves or rejects it.
+async fn park_for_approval(
+    pool: &DbPool,
+    sign_data: &SignSignal,
+    reason: &str,
+) -> Result<SignalResult, DeliveryError> {
+    let expires_at = (Utc::now() + chrono::Duration::seconds(*APPROVAL_TTL_SECS)).naive_utc();
+    let (id, from_address, reason_data) = (
+        stored_tx_id(sign_data.id)?,
+        sign_data.from_address.to_owned(),
+        reason.to_owned(),
+    );
+    let sign_signal = serde_json::to_string(sign_data).expect("error serializing sign signal");
+    let parked = db::run(pool, move |conn| {
+        conn.insert_pending_approval(id, &from_address, &sign_signal, &reason_data, expires_at)
+    })
+    .await;
+    match parked {
+        Ok(_) => {
+            println!(
+                "sign signal {} waits for approval: {}",
+                sign_data.id, reason
+            );
+            notify_pending_tx(sign_data.id, reason, expires_at).await;
+            Ok(SignalResult::PendingApproval {
+                id: sign_data.id,
+                reason: reason.to_string(),
+                expires_at,
+            })
+        }
+        Err(e) => {
+            println!("error parking sign signal {}: {}", sign_data.id, e);
+            Ok(rejected(sign_data.id, "cannot park tx for approval").await)
+        }
+    }
+}
+
+/// Reserves the value of a sign signal against its address's daily limit,
+/// evaluating the policy again under the address's lock so that concurrent
+/// signals cannot overspend it together. Returns why the policy denies the tx,
+/// if it does.
+async fn reserve_value(
+    pool: &DbPool,
+    sign_data: &SignSignal,
+) -> Result<Result<SignedTx, String>, DeliveryError> {
+    let now = Utc::now();
+    let start_of_day = now.date_naive().and_hms_opt(0, 0, 0).unwrap();
+    let (id, from_address, tx) = (
+        stored_tx_id(sign_data.id)?,
+        sign_data.from_address.to_owned(),
+        sign_data.tx.clone(),
+    );
+    match db::run(pool, move |conn| {
+        let rule = POLICY.rule_for(&from_address);
+        conn.reserve_signed_tx(
+            id,
+            &from_address,
+            tx.value(),
+            start_of_day,
+            &|spent_today| rule.evaluate(&tx, spent_today, now),
         )
-        .await?;
-
-    let sign_consume_task = tokio::task::spawn(async move {
-        while let Some(delivery) = sign_tx_consumer.next().await {
-            tokio::task::spawn(async {
-                let delivery = delivery.expect("error in consuming message");
-                let delivery_str = std::str::from_utf8(&delivery.data)
-                    .expect("cannot get data field from RabbitMQ message");
-                let data = serde_json::from_str::<RabbitMQDelivery>(delivery_str)
-                    .expect("error on parsing RabbitMQ message")
-                    .data;
-                let sign_data = serde_json::from_str::<SignSignal>(&data)
-                    .expect("error on parsing sign signal");
-
-                let db_conn = &mut db::establish_connection();
-                let local_share = match db::get_local_share(db_conn, &sign_data.from_address) {
-                    Ok(result) => result,
-                    Err(e) => format!("error getting local share: {}", e),
-                };
-
-                let sign_result = match tss_sm_client::sign(
-                    sign_data.message.to_string(),
-                    local_share.to_string(),
-                    vec![1, 2],
-                    surf::Url::parse(&*SM_MANAGER_URL).unwrap(),
-                    sign_data.id.to_string(),
-                )
-                .await
-                {
-                    Ok(result) => result,
-                    Err(error) => format!("error in sign {:?}", error),
-                };
-
-                // send req to tx sender
-                let client = reqwest::Client::new();
-                println!("sign_result: {}", &sign_result);
-                let mut body_json = HashMap::new();
-                body_json.insert("id", sign_data.id.to_string());
-                body_json.insert("signature", sign_result);
-
-                let _res = client
-                    .post(format!("{}/submit-tx", *TX_SENDER_URL))
-                    .json(&body_json)
-                    .send()
-                    .await
-                    .expect("error on calling tx sender");
-
-                delivery.ack(BasicAckOptions::default()).await.expect("ack");
-            });
+    })
+    .await
+    {
+        Ok(reservation) => Ok(reservation),
+        Err(e @ DbError::Pool(_)) => Err(e.into()),
+        Err(e) => {
+            println!(
+                "error reserving value of sign signal {}: {}",
+                sign_data.id, e
+            );
+            Ok(Err("cannot get value signed today".to_string()))
+        }
+    }
+}
+
+/// Joins the signing room for an accepted sign signal. The signature is queued
+/// for `/submit-tx` by `processed::once`, or by the approval.
+async fn sign_accepted(
+    pool: &DbPool,
+    sign_data: &SignSignal,
+) -> Result<SignalResult, DeliveryError> {
+    let from_address = sign_data.from_address.to_owned();
+    let local_share = match db::run(pool, move |conn| conn.get_local_share(&from_address)).await {
+        Ok(result) => result,
+        Err(e @ DbError::Pool(_)) => return Err(e.into()),
+        Err(e) => {
+            println!("rejecting sign signal {}: {}", sign_data.id, e);
+            return Ok(rejected(sign_data.id, "no active key for from address").await);
+        }
+    };
+
+    let reservation = match reserve_value(pool, sign_data).await? {
+        Ok(reservation) => reservation,
+        Err(reason) => {
+            println!("rejecting sign signal {}: {}", sign_data.id, reason);
+            return Ok(rejected(sign_data.id, &reason).await);
+        }
+    };
+
+    let sign_result = match with_mpc_timeout(tss_sm_client::sign(
+        hex::encode(sign_data.tx.signing_hash()),
+        local_share,
+        vec![1, 2],
+        surf::Url::parse(&SM_MANAGER_URL).unwrap(),
+        sign_data.id.to_string(),
+    ))
+    .await
+    {
+        Ok(result) => result,
+        Err(error) => {
+            println!("error in sign {}: {}", sign_data.id, error);
+            let reservation_id = reservation.id;
+            if let Err(e) = db::run(pool, move |conn| conn.release_signed_tx(reservation_id)).await
+            {
+                println!(
+                    "error releasing value of sign signal {}: {}",
+                    sign_data.id, e
+                );
+            }
+            return Ok(rejected(sign_data.id, "signing failed").await);
+        }
+    };
+
+    println!("sign_result: {}", &sign_result);
+    Ok(SignalResult::Submitted {
+        id: sign_data.id,
+        signature: sign_result,
+    })
+}
+
+async fn handle_sign_signal(pool: &DbPool, data: &[u8]) -> Result<(), DeliveryError> {
+    sign_signal(pool, &Envelope::decode(data)?)
+        .await
+        .map(|_| ())
+}
+
+async fn sign_signal(
+    pool: &DbPool,
+    message: &Envelope<SignSignal>,
+) -> Result<SignalResult, DeliveryError> {
+    let sign_data = &message.signal;
+    processed::once(
+        pool,
+        &message.request_id,
+        "sign",
+        &sign_data.id.to_string(),
+        check_and_sign(pool, sign_data),
+    )
+    .await
+}
+
+/// Evaluates the policy for a sign signal with the value signed so far today.
+/// Returns why the policy denies the tx, if it does. `reserve_value` evaluates
+/// it again under the address's lock right before signing.
+async fn evaluate_policy(
+    pool: &DbPool,
+    sign_data: &SignSignal,
+) -> Result<Result<(), String>, DeliveryError> {
+    let now = Utc::now();
+    let start_of_day = now.date_naive().and_hms_opt(0, 0, 0).unwrap();
+    let from_address = sign_data.from_address.to_owned();
+    let spent_today = match db::run(pool, move |conn| {
+        conn.get_value_signed_since(&from_address, start_of_day)
+    })
+    .await
+    {
+        Ok(spent_today) => spent_today,
+        Err(e @ DbError::Pool(_)) => return Err(e.into()),
+        Err(e) => {
+            println!(
+                "error getting value signed today for {}: {}",
+                sign_data.id, e
+            );
+            return Ok(Err("cannot get value signed today".to_string()));
+        }
+    };
+    Ok(POLICY
+        .rule_for(&sign_data.from_address)
+        .evaluate(&sign_data.tx, spent_today, now))
+}
+
+/// Checks a sign signal against the policy, then signs it, parks it for
+/// approval or rejects it.
+async fn check_and_sign(
+    pool: &DbPool,
+    sign_data: &SignSignal,
+) -> Result<SignalResult, DeliveryError> {
+    println!(
+        "sign signal {} from {}: {}",
+        sign_data.id,
+        sign_data.from_address,
+        serde_json::to_string(&sign_data.tx).expect("error serializing tx")
+    );
+
+    stored_tx_id(sign_data.id)?;
+
+    // never sign a message we cannot derive from the tx ourselves
+    let signing_hash = hex::encode(sign_data.tx.signing_hash());
+    if sign_data.message.trim_start_matches("0x").to_lowercase() != signing_hash {
+        println!(
+            "rejecting sign signal {}: message {} does not match tx signing hash {}",
+            sign_data.id, sign_data.message, signing_hash
+        );
+        return Ok(rejected(sign_data.id, "message does not match tx signing hash").await);
+    }
+
+    if let Err(reason) = evaluate_policy(pool, sign_data).await? {
+        println!("rejecting sign signal {}: {}", sign_data.id, reason);
+        return Ok(rejected(sign_data.id, &reason).await);
+    }
+
+    let rule = POLICY.rule_for(&sign_data.from_address);
+    match rule.approval_reason(&sign_data.tx) {
+        Some(reason) => park_for_approval(pool, sign_data, &reason).await,
+        None => sign_accepted(pool, sign_data).await,
+    }
+}
+
+async fn handle_keygen_signal(pool: &DbPool, data: &[u8]) -> Result<(), DeliveryError> {
+    keygen_signal(pool, &Envelope::decode(data)?)
+        .await
+        .map(|_| ())
+}
+
+async fn keygen_signal(
+    pool: &DbPool,
+    message: &Envelope<KeygenSignal>,
+) -> Result<SignalResult, DeliveryError> {
+    processed::once(
+        pool,
+        &message.request_id,
+        "keygen",
+        &message.signal.id.to_string(),
+        keygen(pool, &message.signal),
+    )
+    .await
+}
+
+/// Joins the keygen room for a keygen signal and stores the local share.
+async fn keygen(pool: &DbPool, keygen_data: &KeygenSignal) -> Result<SignalResult, DeliveryError> {
+    let (key_id, id) = (keygen_data.id, keygen_data.id.to_string());
+    let local_key = with_mpc_timeout(tss_sm_client::keygen(
+        surf::Url::parse(&SM_MANAGER_URL).unwrap(),
+        id.to_owned(),
+        keygen_data.party_index,
+        keygen_data.threshold,
+        keygen_data.parties,
+    ))
+    .await
+    .map_err(|e| DeliveryError::Permanent(format!("error in keygen {}: {}", id, e)))?;
+
+    let address = address::local_key_address(&local_key);
+    let local_share = share::to_local_share(&local_key).map_err(DeliveryError::Permanent)?;
+    let address_data = address.to_owned();
+    // the other party has left the room, a retry would not rejoin it
+    db::run(pool, move |conn| {
+        conn.insert_new_key(key_id, &address_data, &local_share)
+    })
+    .await
+    .map_err(|e| DeliveryError::Permanent(format!("error inserting key {}: {}", id, e)))?;
+    println!("key {} generated", id);
+    Ok(SignalResult::KeyGenerated {
+        id: key_id,
+        address,
+    })
+}
+
+#[derive(StructOpt)]
+struct Cli {
+    #[structopt(subcommand)]
+    cmd: Option<Cmd>,
+}
+
+#[derive(StructOpt)]
+enum Cmd {
+    /// Re-wrap stored shares under MASTER_KEY after rotating from PREVIOUS_MASTER_KEY
+    RewrapKeys,
+    /// Export the local share of a key as a password-encrypted backup file
+    ExportKey {
+        #[structopt(long)]
+        address: String,
+        #[structopt(long, parse(from_os_str))]
+        out: PathBuf,
+        /// Read the password from this file instead of env BACKUP_PASSWORD
+        #[structopt(long, parse(from_os_str))]
+        password_file: Option<PathBuf>,
+    },
+    /// Revoke a key so share 2 no longer signs with it
+    RevokeKey {
+        #[structopt(long)]
+        address: String,
+    },
+    /// Restore a local share from a backup file written by export-key
+    ImportKey {
+        #[structopt(long, parse(from_os_str))]
+        file: PathBuf,
+        /// Read the password from this file instead of env BACKUP_PASSWORD
+        #[structopt(long, parse(from_os_str))]
+        password_file: Option<PathBuf>,
+    },
+}
+
+fn run_command(cmd: Cmd) -> Result<(), Box<dyn std::error::Error>> {
+    let db_conn = &mut *db::establish_connection();
+    match cmd {
+        Cmd::RewrapKeys => {
+            let previous = envelope::MasterKey::from_env("PREVIOUS_MASTER_KEY");
+            let updated = db_conn.rewrap_local_shares(previous.as_ref())?;
+            println!(
+                "re-wrapped {} shares under master key {}",
+                updated,
+                config::MASTER_KEY.id
+            );
+        }
+        Cmd::ExportKey {
+            address,
+            out,
+            password_file,
+        } => {
+            let password = backup::read_password(password_file)?;
+            let key = db_conn.get_key_by_address(&address)?;
+            let local_share = db::open_local_share(&key)?;
+            let share_backup = backup::export(&local_share, key.id, &key.address, &password)?;
+            std::fs::write(&out, serde_json::to_string_pretty(&share_backup)?)?;
+            println!(
+                "exported key {} ({}) to {}",
+                key.id,
+                key.address,
+                out.display()
+            );
         }
-    });
-
-    let keygen_consume_task = tokio::task::spawn(async move {
-        while let Some(delivery) = keygen_consumer.next().await {
-            tokio::task::spawn(async {
-                let delivery = delivery.expect("error in consuming message");
-                let delivery_str = std::str::from_utf8(&delivery.data)
-                    .expect("cannot get data field from RabbitMQ message");
-                let id = serde_json::from_str::<RabbitMQDelivery>(delivery_str)
-                    .expect("error on parsing RabbitMQ message")
-                    .data;
-
-                let _keygen_result = match tss_sm_client::keygen(
-                    surf::Url::parse(&*SM_MANAGER_URL).unwrap(),
-                    id.to_owned(),
-                    1,
-                    1,
-                    2,
+        Cmd::RevokeKey { address } => {
+            let key = db_conn.get_key_by_address(&address)?;
+            db_conn.set_key_status(key.id, KeyStatus::Revoked)?;
+            println!("revoked key {} ({})", key.id, key.address);
+        }
+        Cmd::ImportKey {
+            file,
+            password_file,
+        } => {
+            let password = backup::read_password(password_file)?;
+            let share_backup =
+                serde_json::from_str::<backup::ShareBackup>(&std::fs::read_to_string(&file)?)?;
+            let local_share = backup::import(&share_backup, &password)?;
+            let metadata = &share_backup.metadata;
+            if metadata.party_index != PARTY_INDEX {
+                return Err(format!(
+                    "backup is a share of party {}, share 2 is party {}",
+                    metadata.party_index, PARTY_INDEX
                 )
-                .await
-                {
-                    Ok(local_key) => {
-                        let address =
-                            pubkey_to_address(local_key.y_sum_s.to_bytes(false).deref().to_vec());
-                        let address = eth_checksum::checksum(&address);
-
-                        let db_conn = &mut db::establish_connection();
-                        let key_inserted = db::insert_new_key(
-                            db_conn,
-                            id.as_str().parse::<i32>().expect("error parsing id"),
-                            &address,
-                            &serde_json::to_string(&local_key).expect("error parsing local_key"),
-                        );
-                        format!("result of key insertion: {:?}", key_inserted)
-                    }
-                    Err(error) => format!("error in keygen {:?}", error),
-                };
-
-                delivery.ack(BasicAckOptions::default()).await.expect("ack");
-            });
+                .into());
+            }
+            match db_conn.get_key_by_address(&metadata.address) {
+                Err(diesel::result::Error::NotFound) => {}
+                Ok(_) => {
+                    return Err(format!("a key for {} already exists", metadata.address).into())
+                }
+                Err(e) => return Err(e.into()),
+            }
+            db_conn.insert_new_key(metadata.key_id, &metadata.address, &local_share)?;
+            println!("imported key {} ({})", metadata.key_id, metadata.address);
         }
-    });
+    }
+    Ok(())
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn std::error::Error>> {
+    dotenv().ok();
+    lazy_static::initialize(&config::MASTER_KEY);
+
+    if let Some(cmd) = Cli::from_args().cmd {
+        return run_command(cmd);
+    }
+
+    lazy_static::initialize(&POLICY);
+    lazy_static::initialize(&RABBITMQ_SIGN_PREFETCH);
+    lazy_static::initialize(&RABBITMQ_KEYGEN_PREFETCH);
+    // a signal still running its protocol must not have its claim taken over
+    if *PROCESSED_CLAIM_TIMEOUT_SECS <= *MPC_TIMEOUT_SECS as i64 {
+        panic!("PROCESSED_CLAIM_TIMEOUT_SECS should be greater than MPC_TIMEOUT_SECS");
+    }
+    let pool = db::establish_pool()?;
+    let broker_health = consumer::BrokerHealth::default();
+    let limits = consumer::Limits::from_env();
+    let consume_task = tokio::task::spawn(consumer::run(
+        pool.clone(),
+        limits.clone(),
+        broker_health.clone(),
+    ));
+
+    let figment = rocket::Config::figment()
+        .merge(("port", PORT.parse::<u16>().unwrap()))
+        .merge(("address", "0.0.0.0"));
+    let rocket_task = rocket::custom(config::with_tls(figment))
+        .manage(pool.clone())
+        .manage(broker_health)
+        .manage(limits)
+        .mount(
+            "/",
+            rocket::routes![
+                consumer::health,
+                approval::list_approvals,
+                approval::approve,
+                approval::reject,
+                outbox::list_outbox,
+                outbox::retry,
+                signals::keygen_signal,
+                signals::sign_signal,
+                signals::signal_result
+            ],
+        )
+        .launch();
+    let expire_task = tokio::task::spawn(approval::expire_approvals(pool.clone()));
+    let outbox_task = tokio::task::spawn(outbox::dispatch(pool));
 
-    let (_task_result, _req_result) = tokio::join!(sign_consume_task, keygen_consume_task);
+    let (_consume_result, _rocket_result, _expire_result, _outbox_result) =
+        tokio::join!(consume_task, rocket_task, expire_task, outbox_task);
 
     Ok(())
 }
@@ -0,0 +1,186 @@
+//! Outbox for signatures submitted to the tx sender's `/submit-tx`.
+//!
+//! A signature is stored in `submit_outbox` before it is posted, and stays
+//! pending until the tx sender answers with a 2xx. `dispatch` retries pending
+//! items with exponential backoff from `OUTBOX_RETRY_MIN_SECS` up to
+//! `OUTBOX_RETRY_MAX_SECS`. After `OUTBOX_MAX_ATTEMPTS` attempts, or on a 4xx
+//! answer, an item is marked undeliverable; operators can list it and retry it.
+//!
+//! A tx has at most one item; a duplicate signal leaves the existing one to
+//! the dispatcher. A new item is due only after its first attempt has timed
+//! out, so the dispatcher never posts it at the same time.
+
+use std::time::Duration;
+
+use chrono::{NaiveDateTime, Utc};
+use diesel::result::Error::NotFound;
+use rocket::http::Status;
+use rocket::serde::{json::Json, Serialize};
+use rocket::State;
+use tss_common::config;
+
+use crate::auth::ApiKey;
+use crate::db::models::OutboxItem;
+use crate::db::{self, DbError, DbPool};
+use crate::delivery::DeliveryError;
+use crate::{
+    stored_tx_id, submit_tx, SignRes, OUTBOX_MAX_ATTEMPTS, OUTBOX_RETRY_MAX_SECS,
+    OUTBOX_RETRY_MIN_SECS,
+};
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct OutboxRes {
+    id: i32,
+    tx_id: i32,
+    signature: String,
+    status: String,
+    attempts: i32,
+    last_error: Option<String>,
+    next_attempt_at: NaiveDateTime,
+    created_at: NaiveDateTime,
+    delivered_at: Option<NaiveDateTime>,
+}
+
+/// Lists outbox items with `status`, undeliverable ones by default.
+#[rocket::get("/outbox?<status>")]
+pub(crate) async fn list_outbox(
+    _api_key: ApiKey,
+    pool: &State<DbPool>,
+    status: Option<String>,
+) -> Result<Json<Vec<OutboxRes>>, Status> {
+    let status = status.unwrap_or_else(|| "undeliverable".to_string());
+    let items = db::run(pool, move |conn| conn.get_outbox_items(&status))
+        .await
+        .map_err(|e| {
+            println!("error getting outbox items: {}", e);
+            Status::InternalServerError
+        })?;
+
+    Ok(Json(
+        items
+            .into_iter()
+            .map(|item| OutboxRes {
+                id: item.id,
+                tx_id: item.tx_id,
+                signature: item.signature,
+                status: item.status,
+                attempts: item.attempts,
+                last_error: item.last_error,
+                next_attempt_at: item.next_attempt_at,
+                created_at: item.created_at,
+                delivered_at: item.delivered_at,
+            })
+            .collect(),
+    ))
+}
+
+#[rocket::post("/outbox/<id>/retry")]
+pub(crate) async fn retry(_api_key: ApiKey, pool: &State<DbPool>, id: i32) -> Json<SignRes> {
+    match db::run(pool, move |conn| conn.retry_outbox_item(id)).await {
+        Ok(_) => {
+            println!("outbox item {} requeued by operator", id);
+            Json(SignRes {
+                success: true,
+                info: None,
+            })
+        }
+        Err(e) => {
+            let info = match e {
+                DbError::Query(NotFound) => format!("no undeliverable outbox item {}", id),
+                e => format!("error retrying outbox item: {}", e),
+            };
+            Json(SignRes {
+                success: false,
+                info: Some(info),
+            })
+        }
+    }
+}
+
+/// Stores a signature for `/submit-tx` and makes the first attempt.
+pub async fn enqueue(pool: &DbPool, tx_id: usize, signature: &str) -> Result<(), DeliveryError> {
+    let next_attempt_at = first_attempt_at();
+    let (stored_id, signature_data) = (stored_tx_id(tx_id)?, signature.to_owned());
+    let item = db::run(pool, move |conn| {
+        conn.insert_outbox_item(stored_id, &signature_data, next_attempt_at)
+    })
+    .await?;
+    submit_new(pool, tx_id, item).await;
+    Ok(())
+}
+
+/// When a new item is due for the dispatcher: after its first attempt has
+/// timed out.
+pub fn first_attempt_at() -> NaiveDateTime {
+    let timeout =
+        chrono::Duration::from_std(config::http_timeout()).expect("HTTP timeout out of range");
+    Utc::now().naive_utc() + timeout + backoff(1)
+}
+
+/// Makes the first attempt of a newly stored item, `None` if the tx was
+/// queued before.
+pub async fn submit_new(pool: &DbPool, tx_id: usize, item: Option<OutboxItem>) {
+    match item {
+        Some(item) => deliver(pool, item).await,
+        None => println!("tx {} is already in the outbox", tx_id),
+    }
+}
+
+/// Retries due outbox items every few seconds.
+pub async fn dispatch(pool: DbPool) {
+    let mut interval = tokio::time::interval(Duration::from_secs(5));
+    loop {
+        interval.tick().await;
+
+        match db::run(&pool, |conn| conn.get_due_outbox_items(100)).await {
+            Ok(items) => {
+                for item in items {
+                    deliver(&pool, item).await;
+                }
+            }
+            Err(e) => println!("error getting due outbox items: {}", e),
+        }
+    }
+}
+
+async fn deliver(pool: &DbPool, item: OutboxItem) {
+    let (id, tx_id) = (item.id, item.tx_id);
+    let marked = match submit_tx(tx_id as usize, &item.signature).await {
+        Ok(()) => {
+            println!("submitted tx {}", tx_id);
+            db::run(pool, move |conn| conn.mark_outbox_delivered(id)).await
+        }
+        Err(e) => {
+            let attempts = item.attempts + 1;
+            let rejected = matches!(e.status(), Some(status) if status.is_client_error());
+            let next_attempt_at = if rejected || attempts >= *OUTBOX_MAX_ATTEMPTS {
+                println!(
+                    "tx {} is undeliverable after {} attempts: {}",
+                    tx_id, attempts, e
+                );
+                None
+            } else {
+                println!("error submitting tx {}, attempt {}: {}", tx_id, attempts, e);
+                Some(Utc::now().naive_utc() + backoff(attempts))
+            };
+            let error = e.to_string();
+            db::run(pool, move |conn| {
+                conn.mark_outbox_failed(id, &error, next_attempt_at)
+            })
+            .await
+        }
+    };
+    if let Err(e) = marked {
+        println!("error updating outbox item {}: {}", id, e);
+    }
+}
+
+/// Wait after the `attempts`th failed attempt.
+fn backoff(attempts: i32) -> chrono::Duration {
+    let factor = 1u64 << (attempts - 1).clamp(0, 20);
+    let secs = OUTBOX_RETRY_MIN_SECS
+        .saturating_mul(factor)
+        .min(*OUTBOX_RETRY_MAX_SECS);
+    chrono::Duration::seconds(secs as i64)
+}
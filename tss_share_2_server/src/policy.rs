@@ -0,0 +1,373 @@
+//! Co-signing policy for share 2.
+//!
+//! Loaded from the JSON file at `POLICY_PATH`. The `default` rule applies to
+//! every address without an entry in `addresses`; an address entry replaces the
+//! default rule as a whole. Without `POLICY_PATH` every transaction is allowed.
+//!
+//! ```json
+//! {
+//!   "default": {
+//!     "daily_value_limit": "1000000000000000000",
+//!     "max_gas_limit": 500000,
+//!     "max_fee_per_gas": "200000000000",
+//!     "denied_recipients": ["0x..."],
+//!     "allowed_methods": { "0x<token contract>": ["0xa9059cbb"] },
+//!     "time_window": { "start_hour": 8, "end_hour": 20, "weekdays": ["Mon", "Tue"] },
+//!     "approval": { "value_above": "100000000000000000", "contract_calls": true }
+//!   },
+//!   "addresses": { "0x<wallet>": { "allowed_recipients": ["0x..."] } }
+//! }
+//! ```
+
+use std::collections::HashMap;
+
+use chrono::{DateTime, Datelike, Timelike, Utc, Weekday};
+use serde::{Deserialize, Deserializer};
+use tss_sm_client::tx::{quantity, Address, FixedBytes, Transaction};
+
+pub type Selector = FixedBytes<4>;
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Policy {
+    #[serde(default)]
+    pub default: Rule,
+    #[serde(default)]
+    pub addresses: HashMap<Address, Rule>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct Rule {
+    /// Total value in wei the address may sign for per UTC day.
+    #[serde(default, deserialize_with = "optional_quantity")]
+    pub daily_value_limit: Option<u128>,
+    #[serde(default, deserialize_with = "optional_quantity")]
+    pub max_gas_limit: Option<u128>,
+    /// Cap on `gas_price`, or `max_fee_per_gas` for EIP-1559 transactions.
+    #[serde(default, deserialize_with = "optional_quantity")]
+    pub max_fee_per_gas: Option<u128>,
+    /// When set, only these recipients may be sent to.
+    pub allowed_recipients: Option<Vec<Address>>,
+    #[serde(default)]
+    pub denied_recipients: Vec<Address>,
+    /// When set, calldata is only allowed for these contracts and selectors,
+    /// and contract creation is denied.
+    pub allowed_methods: Option<HashMap<Address, Vec<Selector>>>,
+    pub time_window: Option<TimeWindow>,
+    /// Allowed transactions matching these criteria wait for an operator's
+    /// approval before share 2 joins the signing room.
+    pub approval: Option<ApprovalRule>,
+}
+
+/// A transaction needs approval if it matches any of the criteria.
+#[derive(Deserialize, Debug, Default)]
+#[serde(deny_unknown_fields)]
+pub struct ApprovalRule {
+    #[serde(default, deserialize_with = "optional_quantity")]
+    pub value_above: Option<u128>,
+    #[serde(default)]
+    pub contract_calls: bool,
+    #[serde(default)]
+    pub recipients: Vec<Address>,
+}
+
+/// Hours in UTC from 0 to 23, `end_hour` excluded; `start_hour` greater than
+/// `end_hour` wraps past midnight.
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+pub struct TimeWindow {
+    pub start_hour: u32,
+    pub end_hour: u32,
+    #[serde(default)]
+    pub weekdays: Vec<Weekday>,
+}
+
+impl Policy {
+    pub fn load(path: &str) -> Policy {
+        let policy_str = std::fs::read_to_string(path)
+            .unwrap_or_else(|e| panic!("cannot read policy file {}: {}", path, e));
+        Policy::parse(&policy_str)
+            .unwrap_or_else(|e| panic!("error on parsing policy file {}: {}", path, e))
+    }
+
+    /// Parses a policy and refuses rules that could never allow a tx.
+    pub fn parse(policy_str: &str) -> Result<Policy, String> {
+        let policy = serde_json::from_str::<Policy>(policy_str).map_err(|e| e.to_string())?;
+        policy.default.validate("default")?;
+        for (address, rule) in &policy.addresses {
+            rule.validate(&address.to_string())?;
+        }
+        Ok(policy)
+    }
+
+    pub fn rule_for(&self, from_address: &str) -> &Rule {
+        from_address
+            .parse::<Address>()
+            .ok()
+            .and_then(|address| self.addresses.get(&address))
+            .unwrap_or(&self.default)
+    }
+}
+
+impl Rule {
+    fn validate(&self, name: &str) -> Result<(), String> {
+        if let Some(time_window) = &self.time_window {
+            time_window
+                .validate()
+                .map_err(|e| format!("time_window of rule {}: {}", name, e))?;
+        }
+        Ok(())
+    }
+    /// Returns the reason the transaction is denied, if it is.
+    pub fn evaluate(
+        &self,
+        tx: &Transaction,
+        spent_today: u128,
+        now: DateTime<Utc>,
+    ) -> Result<(), String> {
+        if let Some(limit) = self.daily_value_limit {
+            if spent_today.saturating_add(tx.value()) > limit {
+                return Err(format!(
+                    "daily value limit {} exceeded: {} already signed today, tx value {}",
+                    limit,
+                    spent_today,
+                    tx.value()
+                ));
+            }
+        }
+
+        if let Some(max_gas_limit) = self.max_gas_limit {
+            if u128::from(tx.gas_limit()) > max_gas_limit {
+                return Err(format!(
+                    "gas limit {} above cap {}",
+                    tx.gas_limit(),
+                    max_gas_limit
+                ));
+            }
+        }
+
+        if let Some(max_fee_per_gas) = self.max_fee_per_gas {
+            if tx.max_fee_per_gas() > max_fee_per_gas {
+                return Err(format!(
+                    "fee per gas {} above cap {}",
+                    tx.max_fee_per_gas(),
+                    max_fee_per_gas
+                ));
+            }
+        }
+
+        if let Some(to) = tx.to() {
+            if self.denied_recipients.contains(&to) {
+                return Err(format!("recipient {} is denied", to));
+            }
+            if let Some(allowed_recipients) = &self.allowed_recipients {
+                if !allowed_recipients.contains(&to) {
+                    return Err(format!("recipient {} is not allowed", to));
+                }
+            }
+        }
+
+        if let Some(allowed_methods) = &self.allowed_methods {
+            check_method(allowed_methods, tx)?;
+        }
+
+        if let Some(time_window) = &self.time_window {
+            if !time_window.contains(now) {
+                return Err(format!("outside signing window at {}", now));
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Returns why the transaction needs approval, if it does.
+    pub fn approval_reason(&self, tx: &Transaction) -> Option<String> {
+        let approval = self.approval.as_ref()?;
+        if let Some(value_above) = approval.value_above {
+            if tx.value() > value_above {
+                return Some(format!("value {} above {}", tx.value(), value_above));
+            }
+        }
+        if approval.contract_calls && (!tx.data().is_empty() || tx.to().is_none()) {
+            return Some("contract interaction".to_string());
+        }
+        match tx.to() {
+            Some(to) if approval.recipients.contains(&to) => {
+                Some(format!("recipient {} requires approval", to))
+            }
+            _ => None,
+        }
+    }
+}
+
+fn check_method(
+    allowed_methods: &HashMap<Address, Vec<Selector>>,
+    tx: &Transaction,
+) -> Result<(), String> {
+    let data = tx.data();
+    let to = match tx.to() {
+        Some(to) => to,
+        None => return Err("contract creation is not allowed".to_string()),
+    };
+    if data.is_empty() {
+        return Ok(());
+    }
+    if data.len() < 4 {
+        return Err(format!("calldata to {} is shorter than a selector", to));
+    }
+
+    let mut selector = [0u8; 4];
+    selector.copy_from_slice(&data[..4]);
+    let selector = FixedBytes(selector);
+    match allowed_methods.get(&to) {
+        Some(selectors) if selectors.contains(&selector) => Ok(()),
+        Some(_) => Err(format!("method {} is not allowed on {}", selector, to)),
+        None => Err(format!("contract calls to {} are not allowed", to)),
+    }
+}
+
+impl TimeWindow {
+    fn validate(&self) -> Result<(), String> {
+        if self.start_hour > 23 || self.end_hour > 23 {
+            return Err("hours should be from 0 to 23".to_string());
+        }
+        if self.start_hour == self.end_hour {
+            return Err("start_hour and end_hour should differ".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn contains(&self, now: DateTime<Utc>) -> bool {
+        if !self.weekdays.is_empty() && !self.weekdays.contains(&now.weekday()) {
+            return false;
+        }
+        let hour = now.hour();
+        if self.start_hour <= self.end_hour {
+            self.start_hour <= hour && hour < self.end_hour
+        } else {
+            hour >= self.start_hour || hour < self.end_hour
+        }
+    }
+}
+
+fn optional_quantity<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<u128>, D::Error> {
+    #[derive(Deserialize)]
+    struct Quantity(#[serde(with = "quantity")] u128);
+
+    Ok(Option::<Quantity>::deserialize(deserializer)?.map(|Quantity(value)| value))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RECIPIENT: &str = "0x3535353535353535353535353535353535353535";
+    const TOKEN: &str = "0x1111111111111111111111111111111111111111";
+    // transfer(address,uint256)
+    const TRANSFER: &str = "a9059cbb";
+
+    fn rule(rule: serde_json::Value) -> Policy {
+        Policy::parse(&serde_json::json!({ "default": rule }).to_string()).unwrap()
+    }
+
+    fn tx(to: &str, value: u128, data: &str) -> Transaction {
+        serde_json::from_value(serde_json::json!({
+            "type": "legacy",
+            "chain_id": "0x1",
+            "nonce": "0x0",
+            "gas_price": "0x4a817c800",
+            "gas_limit": "0x5208",
+            "to": to,
+            "value": format!("{:#x}", value),
+            "data": format!("0x{}", data),
+        }))
+        .unwrap()
+    }
+
+    /// 2024-01-01 is a Monday.
+    fn at(time: &str) -> DateTime<Utc> {
+        format!("2024-01-01T{}Z", time).parse().unwrap()
+    }
+
+    #[test]
+    fn enforces_daily_value_limit() {
+        let policy = rule(serde_json::json!({ "daily_value_limit": "1000" }));
+        let rule = policy.rule_for(RECIPIENT);
+
+        assert!(rule
+            .evaluate(&tx(RECIPIENT, 400, ""), 600, at("12:00:00"))
+            .is_ok());
+        assert!(rule
+            .evaluate(&tx(RECIPIENT, 401, ""), 600, at("12:00:00"))
+            .is_err());
+        assert!(rule
+            .evaluate(&tx(RECIPIENT, 1, ""), u128::MAX, at("12:00:00"))
+            .is_err());
+    }
+
+    #[test]
+    fn allows_listed_methods_only() {
+        let policy = rule(serde_json::json!({
+            "allowed_methods": { TOKEN: [format!("0x{}", TRANSFER)] }
+        }));
+        let rule = policy.rule_for(RECIPIENT);
+        let now = at("12:00:00");
+
+        assert!(rule.evaluate(&tx(TOKEN, 0, TRANSFER), 0, now).is_ok());
+        // plain transfers carry no calldata
+        assert!(rule.evaluate(&tx(RECIPIENT, 1, ""), 0, now).is_ok());
+        assert!(rule.evaluate(&tx(TOKEN, 0, "095ea7b3"), 0, now).is_err());
+        assert!(rule.evaluate(&tx(RECIPIENT, 0, TRANSFER), 0, now).is_err());
+        assert!(rule.evaluate(&tx(TOKEN, 0, "a905"), 0, now).is_err());
+    }
+
+    #[test]
+    fn wraps_time_window_past_midnight() {
+        let policy = rule(serde_json::json!({
+            "time_window": { "start_hour": 22, "end_hour": 6 }
+        }));
+        let rule = policy.rule_for(RECIPIENT);
+        let transfer = tx(RECIPIENT, 1, "");
+
+        for time in ["22:00:00", "23:59:59", "00:00:00", "05:59:59"] {
+            assert!(rule.evaluate(&transfer, 0, at(time)).is_ok(), "{}", time);
+        }
+        for time in ["06:00:00", "12:00:00", "21:59:59"] {
+            assert!(rule.evaluate(&transfer, 0, at(time)).is_err(), "{}", time);
+        }
+    }
+
+    #[test]
+    fn limits_time_window_to_weekdays() {
+        let policy = rule(serde_json::json!({
+            "time_window": { "start_hour": 8, "end_hour": 20, "weekdays": ["Mon", "Tue"] }
+        }));
+        let rule = policy.rule_for(RECIPIENT);
+        let transfer = tx(RECIPIENT, 1, "");
+
+        assert!(rule.evaluate(&transfer, 0, at("08:00:00")).is_ok());
+        assert!(rule.evaluate(&transfer, 0, at("20:00:00")).is_err());
+        let wednesday = "2024-01-03T12:00:00Z".parse().unwrap();
+        assert!(rule.evaluate(&transfer, 0, wednesday).is_err());
+    }
+
+    #[test]
+    fn refuses_time_windows_that_never_open() {
+        for (start_hour, end_hour) in [(8, 8), (8, 24), (25, 6)] {
+            let policy = serde_json::json!({
+                "addresses": {
+                    RECIPIENT: {
+                        "time_window": { "start_hour": start_hour, "end_hour": end_hour }
+                    }
+                }
+            });
+            assert!(
+                Policy::parse(&policy.to_string()).is_err(),
+                "{}-{}",
+                start_hour,
+                end_hour
+            );
+        }
+    }
+}
@@ -0,0 +1,298 @@
+//! At-most-once handling of sign and keygen signals.
+//!
+//! RabbitMQ delivers at least once, so the same `requestId` can arrive again
+//! after share 2 handled it. The first delivery claims the request id in
+//! `processed_requests` before joining a room and stores what was reported to
+//! the tx sender; a duplicate gets that result reported again instead. A
+//! failed delivery releases its claim so its retry can run. A signature is
+//! stored in the outbox in the same transaction as its result.
+//!
+//! A sign signal parked for approval is handled with `PendingApproval`. The
+//! decision replaces that result: a rejection or expiry stores `Rejected`,
+//! and an approval claims the requests again until the tx is signed, when
+//! `complete_approved` stores the final result.
+//!
+//! A claim without a result after `PROCESSED_CLAIM_TIMEOUT_SECS` was left by
+//! share 2 stopping mid-signal, as handling is bounded by `MPC_TIMEOUT_SECS`;
+//! the next delivery takes it over and runs the signal again. A claim is
+//! completed only by the delivery that made it, so a handler still running
+//! after its claim was taken over cannot overwrite the new result. Operators can
+//! release a stuck claim sooner by deleting its row:
+//!
+//! ```sql
+//! DELETE FROM processed_requests WHERE request_id = '<requestId>' AND result IS NULL;
+//! ```
+
+use std::future::Future;
+
+use chrono::{NaiveDateTime, SubsecRound, Utc};
+use tss_messages::SignalResult;
+
+use crate::db::{self, DbPool};
+use crate::delivery::DeliveryError;
+use crate::outbox;
+use crate::{notify_pending_tx, reject_tx, stored_tx_id, PROCESSED_CLAIM_TIMEOUT_SECS};
+
+/// Outcome of claiming a request id.
+pub enum Claim {
+    /// The caller handles the request and passes its result, with the time
+    /// of the claim, to `complete`
+    Claimed(NaiveDateTime),
+    /// Another delivery is handling it
+    Running,
+    /// Handled before; the result was reported again
+    Handled(SignalResult),
+}
+
+/// Runs `handle` unless `request_id` was handled before, in which case its
+/// result is reported again. Returns the result either way.
+pub async fn once<F>(
+    pool: &DbPool,
+    request_id: &str,
+    signal: &'static str,
+    signal_id: &str,
+    handle: F,
+) -> Result<SignalResult, DeliveryError>
+where
+    F: Future<Output = Result<SignalResult, DeliveryError>>,
+{
+    match claim(pool, request_id, signal, signal_id).await? {
+        Claim::Claimed(claimed_at) => complete(pool, request_id, claimed_at, handle).await,
+        // still running, or share 2 stopped while handling it less than
+        // PROCESSED_CLAIM_TIMEOUT_SECS ago
+        Claim::Running => Err(DeliveryError::Transient(format!(
+            "request {} is still being handled",
+            request_id
+        ))),
+        Claim::Handled(result) => Ok(result),
+    }
+}
+
+/// Claims `request_id` for handling, reporting the result again if it was
+/// handled before.
+pub async fn claim(
+    pool: &DbPool,
+    request_id: &str,
+    signal: &'static str,
+    signal_id: &str,
+) -> Result<Claim, DeliveryError> {
+    let (request_id_data, signal_id_data) = (request_id.to_owned(), signal_id.to_owned());
+    // microseconds, as stored by postgres, so that the claim can be matched
+    let claimed_at = Utc::now().naive_utc().trunc_subsecs(6);
+    let stale_before =
+        (Utc::now() - chrono::Duration::seconds(*PROCESSED_CLAIM_TIMEOUT_SECS)).naive_utc();
+    let claimed = db::run(pool, move |conn| {
+        conn.claim_processed_request(
+            &request_id_data,
+            signal,
+            &signal_id_data,
+            claimed_at,
+            stale_before,
+        )
+    })
+    .await?;
+
+    let result = match claimed.map(|claimed| claimed.result) {
+        None => return Ok(Claim::Claimed(claimed_at)),
+        Some(None) => return Ok(Claim::Running),
+        Some(Some(result)) => result,
+    };
+    let result = serde_json::from_str::<SignalResult>(&result)
+        .map_err(|e| DeliveryError::Permanent(format!("cannot read stored result: {}", e)))?;
+    println!(
+        "{} signal {} of request {} was handled before, reporting it again",
+        signal, signal_id, request_id
+    );
+    report(pool, &result).await?;
+    Ok(Claim::Handled(result))
+}
+
+/// Runs `handle` for a claimed request and stores its result, or releases the
+/// claim if it failed. Nothing is stored if the claim was taken over.
+pub async fn complete<F>(
+    pool: &DbPool,
+    request_id: &str,
+    claimed_at: NaiveDateTime,
+    handle: F,
+) -> Result<SignalResult, DeliveryError>
+where
+    F: Future<Output = Result<SignalResult, DeliveryError>>,
+{
+    let result = handle.await;
+    if let Ok(submitted @ SignalResult::Submitted { id, signature }) = &result {
+        submit(pool, request_id, claimed_at, submitted, *id, signature).await?;
+        return result;
+    }
+
+    let stored = result
+        .as_ref()
+        .ok()
+        .map(|result| serde_json::to_string(result).expect("error serializing result"));
+    let request_id_data = request_id.to_owned();
+    if let Err(e) = db::run(pool, move |conn| {
+        conn.complete_processed_request(&request_id_data, claimed_at, stored.as_deref())
+    })
+    .await
+    {
+        println!("error storing result of request {}: {}", request_id, e);
+    }
+    result
+}
+
+/// Stores the result of a signed request with its outbox item, then posts it.
+async fn submit(
+    pool: &DbPool,
+    request_id: &str,
+    claimed_at: NaiveDateTime,
+    submitted: &SignalResult,
+    tx_id: usize,
+    signature: &str,
+) -> Result<(), DeliveryError> {
+    let stored = serde_json::to_string(submitted).expect("error serializing result");
+    let stored_id = stored_tx_id(tx_id)?;
+    let (request_id_data, signature_data) = (request_id.to_owned(), signature.to_owned());
+    let next_attempt_at = outbox::first_attempt_at();
+    let item = db::run(pool, move |conn| {
+        conn.complete_submitted_request(
+            &request_id_data,
+            claimed_at,
+            &stored,
+            stored_id,
+            &signature_data,
+            next_attempt_at,
+        )
+    })
+    .await
+    .map_err(|e| {
+        // the claim is kept, so the signal is not signed again before
+        // PROCESSED_CLAIM_TIMEOUT_SECS; the signature is only in this log
+        println!(
+            "tx {} is signed but cannot be stored for submission, signature {}: {}",
+            tx_id, signature, e
+        );
+        DeliveryError::Transient(format!("cannot store signature of tx {}: {}", tx_id, e))
+    })?;
+    outbox::submit_new(pool, tx_id, item).await;
+    Ok(())
+}
+
+/// Stores the result of an approved tx for the sign requests that parked it,
+/// with its signature in the outbox, then posts it.
+pub async fn complete_approved(
+    pool: &DbPool,
+    tx_id: i32,
+    result: &SignalResult,
+) -> Result<(), DeliveryError> {
+    let stored = serde_json::to_string(result).expect("error serializing result");
+    match result {
+        SignalResult::Submitted { id, signature } => {
+            let signature_data = signature.to_owned();
+            let next_attempt_at = outbox::first_attempt_at();
+            let item = db::run(pool, move |conn| {
+                conn.complete_approved_tx(tx_id, &stored, &signature_data, next_attempt_at)
+            })
+            .await
+            .map_err(|e| {
+                println!(
+                    "tx {} is signed but cannot be stored for submission, signature {}: {}",
+                    tx_id, signature, e
+                );
+                DeliveryError::from(e)
+            })?;
+            outbox::submit_new(pool, *id, item).await;
+        }
+        _ => {
+            db::run(pool, move |conn| {
+                conn.set_sign_request_result(tx_id, Some(&stored))
+            })
+            .await?;
+        }
+    }
+    Ok(())
+}
+
+async fn report(pool: &DbPool, result: &SignalResult) -> Result<(), DeliveryError> {
+    match result {
+        SignalResult::Submitted { id, signature } => outbox::enqueue(pool, *id, signature).await,
+        SignalResult::Rejected { id, reason } => {
+            reject_tx(*id, reason).await;
+            Ok(())
+        }
+        SignalResult::PendingApproval {
+            id,
+            reason,
+            expires_at,
+        } => {
+            notify_pending_tx(*id, reason, *expires_at).await;
+            Ok(())
+        }
+        // keygen results are not reported to the tx sender
+        SignalResult::KeyGenerated { id, address } => {
+            println!("key {} was generated before: {}", id, address);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use diesel::sqlite::SqliteConnection;
+    use diesel_migrations::MigrationHarness;
+
+    use super::*;
+
+    const REQUEST_ID: &str = "r1";
+
+    fn pool() -> DbPool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+            .unwrap();
+        pool.get()
+            .unwrap()
+            .run_pending_migrations(db::SQLITE_MIGRATIONS)
+            .unwrap();
+        DbPool::Sqlite(pool)
+    }
+
+    fn rejected(reason: &str) -> SignalResult {
+        SignalResult::Rejected {
+            id: 7,
+            reason: reason.to_string(),
+        }
+    }
+
+    #[tokio::test]
+    async fn taken_over_claim_cannot_store_result() {
+        let pool = pool();
+        let first = match claim(&pool, REQUEST_ID, "sign", "7").await.unwrap() {
+            Claim::Claimed(claimed_at) => claimed_at,
+            _ => panic!("request should be claimed"),
+        };
+
+        // the first claim is taken to be stale
+        let second = first + chrono::Duration::seconds(1);
+        let taken_over = db::run(&pool, move |conn| {
+            conn.claim_processed_request(REQUEST_ID, "sign", "7", second, second)
+        })
+        .await
+        .unwrap();
+        assert!(taken_over.is_none());
+
+        complete(&pool, REQUEST_ID, second, async { Ok(rejected("second")) })
+            .await
+            .unwrap();
+        complete(&pool, REQUEST_ID, first, async { Ok(rejected("first")) })
+            .await
+            .unwrap();
+
+        let stored = db::run(&pool, |conn| conn.get_processed_request(REQUEST_ID))
+            .await
+            .unwrap();
+        match serde_json::from_str(&stored.result.unwrap()).unwrap() {
+            SignalResult::Rejected { reason, .. } => assert_eq!(reason, "second"),
+            result => panic!("unexpected result {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,357 @@
+//! HTTP alternative to the RabbitMQ signal queues, for deployments and tests
+//! without a broker.
+//!
+//! `POST /signals/keygen` and `POST /signals/sign` take the same message as
+//! the queues, a `tss_messages::Envelope` as a JSON body of at most Rocket's
+//! `json` limit (1 MiB by default), and handle it like a delivery:
+//! duplicates by `requestId` are reported again, results go to the tx sender,
+//! and the concurrency limits apply. The request returns 202 once the request
+//! id is claimed and the signal runs in the background; `GET
+//! /signals/<requestId>` answers its result once it is handled. A full
+//! concurrency limit or a transient failure answers 503 so the caller retries,
+//! as the queue would; a permanent one answers 422.
+
+use std::future::Future;
+use std::sync::Arc;
+
+use diesel::result::Error::NotFound;
+use rocket::http::Status;
+use rocket::serde::json::{Json, Value};
+use rocket::serde::Serialize;
+use rocket::State;
+use tokio::sync::Semaphore;
+use tss_messages::{Envelope, KeygenSignal, SignSignal, SignalResult};
+
+use crate::auth::SignalApiKey;
+use crate::consumer::Limits;
+use crate::db::{self, DbError, DbPool};
+use crate::delivery::DeliveryError;
+use crate::processed::{self, Claim};
+use crate::{check_and_sign, keygen};
+
+#[derive(Serialize)]
+#[serde(crate = "rocket::serde")]
+pub struct SignalRes {
+    success: bool,
+    result: Option<SignalResult>,
+    info: Option<String>,
+}
+
+#[rocket::post("/signals/keygen", data = "<message>")]
+pub(crate) async fn keygen_signal(
+    _api_key: SignalApiKey,
+    pool: &State<DbPool>,
+    limits: &State<Limits>,
+    message: Json<Value>,
+) -> (Status, Json<SignalRes>) {
+    let message = match Envelope::<KeygenSignal>::decode(message.to_string().as_bytes()) {
+        Ok(message) => message,
+        Err(e) => return signal_res(Err(e.into())),
+    };
+    let signal_id = message.signal.id.to_string();
+    accept(
+        pool,
+        &limits.keygen,
+        message,
+        "keygen",
+        signal_id,
+        |pool, keygen_data| async move { keygen(&pool, &keygen_data).await },
+    )
+    .await
+}
+
+#[rocket::post("/signals/sign", data = "<message>")]
+pub(crate) async fn sign_signal(
+    _api_key: SignalApiKey,
+    pool: &State<DbPool>,
+    limits: &State<Limits>,
+    message: Json<Value>,
+) -> (Status, Json<SignalRes>) {
+    let message = match Envelope::<SignSignal>::decode(message.to_string().as_bytes()) {
+        Ok(message) => message,
+        Err(e) => return signal_res(Err(e.into())),
+    };
+    let signal_id = message.signal.id.to_string();
+    accept(
+        pool,
+        &limits.sign,
+        message,
+        "sign",
+        signal_id,
+        |pool, sign_data| async move { check_and_sign(&pool, &sign_data).await },
+    )
+    .await
+}
+
+/// The result of a posted signal: 200 once handled, 202 while it runs, 404 if
+/// the request id is unknown or its signal failed and may be posted again.
+#[rocket::get("/signals/<request_id>")]
+pub(crate) async fn signal_result(
+    _api_key: SignalApiKey,
+    pool: &State<DbPool>,
+    request_id: String,
+) -> (Status, Json<SignalRes>) {
+    let lookup = request_id.to_owned();
+    let processed = match db::run(pool, move |conn| conn.get_processed_request(&lookup)).await {
+        Ok(processed) => processed,
+        Err(DbError::Query(NotFound)) => {
+            return (
+                Status::NotFound,
+                Json(SignalRes {
+                    success: false,
+                    result: None,
+                    info: Some(format!("no result for request {}", request_id)),
+                }),
+            )
+        }
+        Err(e) => return signal_res(Err(DeliveryError::Transient(e.to_string()))),
+    };
+
+    match processed.result {
+        Some(result) => signal_res(
+            serde_json::from_str::<SignalResult>(&result)
+                .map_err(|e| DeliveryError::Permanent(format!("cannot read stored result: {}", e))),
+        ),
+        None => accepted(&request_id),
+    }
+}
+
+/// Claims the request id of a signal and runs `handle` in the background,
+/// holding a permit of `limit`.
+async fn accept<S, F, Fut>(
+    pool: &DbPool,
+    limit: &Arc<Semaphore>,
+    message: Envelope<S>,
+    signal: &'static str,
+    signal_id: String,
+    handle: F,
+) -> (Status, Json<SignalRes>)
+where
+    S: Send + 'static,
+    F: FnOnce(DbPool, S) -> Fut + Send + 'static,
+    Fut: Future<Output = Result<SignalResult, DeliveryError>> + Send,
+{
+    // a full limit is answered instead of waited on, so that the claim is
+    // only made once the signal can run
+    let permit = match limit.clone().try_acquire_owned() {
+        Ok(permit) => permit,
+        Err(_) => {
+            return signal_res(Err(DeliveryError::Transient(format!(
+                "too many {} signals being handled",
+                signal
+            ))))
+        }
+    };
+
+    let request_id = message.request_id;
+    let claimed_at = match processed::claim(pool, &request_id, signal, &signal_id).await {
+        Ok(Claim::Claimed(claimed_at)) => claimed_at,
+        Ok(Claim::Running) => return accepted(&request_id),
+        Ok(Claim::Handled(result)) => return signal_res(Ok(result)),
+        Err(e) => return signal_res(Err(e)),
+    };
+
+    let pool = pool.clone();
+    let background_request_id = request_id.to_owned();
+    tokio::task::spawn(async move {
+        let _permit = permit;
+        let signal_data = message.signal;
+        let result = processed::complete(
+            &pool,
+            &background_request_id,
+            claimed_at,
+            handle(pool.clone(), signal_data),
+        )
+        .await;
+        if let Err(e) = result {
+            println!(
+                "error handling {} signal {} of request {}: {}",
+                signal, signal_id, background_request_id, e
+            );
+        }
+    });
+
+    accepted(&request_id)
+}
+
+fn accepted(request_id: &str) -> (Status, Json<SignalRes>) {
+    (
+        Status::Accepted,
+        Json(SignalRes {
+            success: true,
+            result: None,
+            info: Some(format!(
+                "request {} is being handled, see GET /signals/{}",
+                request_id, request_id
+            )),
+        }),
+    )
+}
+
+fn signal_res(result: Result<SignalResult, DeliveryError>) -> (Status, Json<SignalRes>) {
+    let (status, result, info) = match result {
+        Ok(result) => (Status::Ok, Some(result), None),
+        Err(e) => {
+            println!("error handling signal: {}", e);
+            let status = match e {
+                DeliveryError::Transient(_) => Status::ServiceUnavailable,
+                DeliveryError::Permanent(_) => Status::UnprocessableEntity,
+            };
+            (status, None, Some(e.to_string()))
+        }
+    };
+    (
+        status,
+        Json(SignalRes {
+            success: result.is_some(),
+            result,
+            info,
+        }),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use chrono::Utc;
+    use diesel::r2d2::{ConnectionManager, Pool};
+    use diesel::sqlite::SqliteConnection;
+    use diesel_migrations::MigrationHarness;
+    use rocket::http::Header;
+    use rocket::local::asynchronous::Client;
+    use rocket::serde::json::serde_json;
+
+    use super::*;
+    use crate::approval;
+
+    const OPERATOR_KEY: &str = "operator-key";
+    const SIGNAL_KEY: &str = "signal-key";
+    const REQUEST_ID: &str = "r1";
+    const TX_ID: i32 = 7;
+
+    /// One in-memory database, kept by the pool's only connection.
+    fn pool() -> DbPool {
+        let pool = Pool::builder()
+            .max_size(1)
+            .build(ConnectionManager::<SqliteConnection>::new(":memory:"))
+            .unwrap();
+        pool.get()
+            .unwrap()
+            .run_pending_migrations(db::SQLITE_MIGRATIONS)
+            .unwrap();
+        DbPool::Sqlite(pool)
+    }
+
+    async fn client(pool: &DbPool) -> Client {
+        std::env::set_var("SHARE_2_API_KEY", OPERATOR_KEY);
+        std::env::set_var("SIGNAL_API_KEY", SIGNAL_KEY);
+        // nothing listens there, so reports to the tx sender fail right away
+        std::env::set_var("TX_SENDER_URL", "http://127.0.0.1:1");
+        let rocket = rocket::build()
+            .manage(pool.clone())
+            .manage(Limits::from_env())
+            .mount(
+                "/",
+                rocket::routes![approval::approve, approval::reject, signal_result],
+            );
+        Client::untracked(rocket).await.unwrap()
+    }
+
+    /// Stores a sign request handled by parking its tx for approval.
+    async fn park(pool: &DbPool) {
+        let sign_signal = serde_json::json!({
+            "from_address": "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+            "id": TX_ID,
+            "message": "0xdaf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53",
+            "tx": {
+                "type": "legacy",
+                "chain_id": "0x1",
+                "nonce": "0x9",
+                "gas_price": "0x4a817c800",
+                "gas_limit": "0x5208",
+                "to": "0x3535353535353535353535353535353535353535",
+                "value": "0xde0b6b3a7640000"
+            }
+        })
+        .to_string();
+        let expires_at = (Utc::now() + chrono::Duration::hours(1)).naive_utc();
+        let pending = serde_json::to_string(&SignalResult::PendingApproval {
+            id: TX_ID as usize,
+            reason: "value above approval threshold".to_string(),
+            expires_at,
+        })
+        .unwrap();
+        db::run(pool, move |conn| {
+            let now = Utc::now().naive_utc();
+            conn.claim_processed_request(REQUEST_ID, "sign", &TX_ID.to_string(), now, now)?;
+            conn.complete_processed_request(REQUEST_ID, now, Some(&pending))?;
+            conn.insert_pending_approval(
+                TX_ID,
+                "0x7e5f4552091a69125d5dfcb7b8c2659029395bdf",
+                &sign_signal,
+                "value above approval threshold",
+                expires_at,
+            )?;
+            Ok(())
+        })
+        .await
+        .unwrap();
+    }
+
+    /// Polls `GET /signals/<REQUEST_ID>` until the signal is no longer running.
+    async fn signal_outcome(client: &Client) -> serde_json::Value {
+        for _ in 0..100 {
+            let res = client
+                .get(format!("/signals/{}", REQUEST_ID))
+                .header(Header::new("X-Api-Key", SIGNAL_KEY))
+                .dispatch()
+                .await;
+            if res.status() != Status::Accepted {
+                assert_eq!(res.status(), Status::Ok);
+                let body = res.into_json::<serde_json::Value>().await.unwrap();
+                return body["result"].clone();
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        panic!("request {} is still running", REQUEST_ID);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_result_of_approved_signal() {
+        let pool = pool();
+        park(&pool).await;
+        let client = client(&pool).await;
+        assert_eq!(signal_outcome(&client).await["outcome"], "pending_approval");
+
+        let res = client
+            .post(format!("/approvals/{}/approve", TX_ID))
+            .header(Header::new("X-Api-Key", OPERATOR_KEY))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+
+        // share 2 holds no key for the address, so signing rejects the tx
+        let result = signal_outcome(&client).await;
+        assert_eq!(result["outcome"], "rejected");
+        assert_eq!(result["id"], TX_ID);
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn reports_result_of_rejected_signal() {
+        let pool = pool();
+        park(&pool).await;
+        let client = client(&pool).await;
+
+        let res = client
+            .post(format!("/approvals/{}/reject", TX_ID))
+            .header(Header::new("X-Api-Key", OPERATOR_KEY))
+            .dispatch()
+            .await;
+        assert_eq!(res.status(), Status::Ok);
+
+        let result = signal_outcome(&client).await;
+        assert_eq!(result["outcome"], "rejected");
+        assert_eq!(result["reason"], "rejected by operator");
+    }
+}
@@ -1,4 +1,5 @@
 use std::convert::TryInto;
+use std::sync::Arc;
 
 use anyhow::{Context, Result};
 use futures::{Sink, Stream, StreamExt, TryStreamExt};
@@ -6,6 +7,8 @@ use serde::{de::DeserializeOwned, Deserialize, Serialize};
 
 use round_based::Msg;
 
+use crate::tls::ClientTls;
+
 pub async fn join_computation<M>(
     address: surf::Url,
     room_id: &str,
@@ -57,9 +60,12 @@ pub struct SmClient {
 
 impl SmClient {
     pub fn new(address: surf::Url, room_id: &str) -> Result<Self> {
-        let config = surf::Config::new()
+        let mut config = surf::Config::new()
             .set_base_url(address.join(&format!("rooms/{}/", room_id))?)
             .set_timeout(None);
+        if let Some(tls) = ClientTls::from_env().context("load client TLS config")? {
+            config = config.set_tls_config(Some(Arc::new(tls.connector()?)));
+        }
         Ok(Self {
             http_client: config.try_into()?,
         })
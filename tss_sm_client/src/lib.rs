@@ -12,9 +12,11 @@ use round_based::async_runtime::AsyncProtocol;
 use round_based::Msg;
 
 mod gg20_sm_client;
-use curv::elliptic::curves::secp256_k1::Secp256k1;
+pub mod tls;
+pub mod tx;
+pub use curv::elliptic::curves::secp256_k1::Secp256k1;
 use gg20_sm_client::join_computation;
-use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
+pub use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::state_machine::keygen::LocalKey;
 
 pub async fn sign(
     data_to_sign: String,
@@ -0,0 +1,70 @@
+//! Mutual TLS between the services.
+//!
+//! The identity presented to the sm manager, the tx sender and the other
+//! servers is read from PEM files at `TLS_CLIENT_CERT` and `TLS_CLIENT_KEY`
+//! (PKCS#8); `TLS_CA_CERT` adds the CA that signed their server certificates
+//! to the trusted roots. `with_tls` configures the server side of every
+//! Rocket api.
+
+use anyhow::{bail, Context, Result};
+use figment::Figment;
+
+pub struct ClientTls {
+    pub cert: Vec<u8>,
+    pub key: Vec<u8>,
+    pub ca_cert: Option<Vec<u8>>,
+}
+
+impl ClientTls {
+    /// `None` if no client certificate is configured.
+    pub fn from_env() -> Result<Option<Self>> {
+        let ca_cert = read_env_file("TLS_CA_CERT")?;
+        match (
+            read_env_file("TLS_CLIENT_CERT")?,
+            read_env_file("TLS_CLIENT_KEY")?,
+        ) {
+            (Some(cert), Some(key)) => Ok(Some(ClientTls { cert, key, ca_cert })),
+            (None, None) if ca_cert.is_none() => Ok(None),
+            (None, None) => bail!("TLS_CA_CERT is set without TLS_CLIENT_CERT and TLS_CLIENT_KEY"),
+            _ => bail!("TLS_CLIENT_CERT and TLS_CLIENT_KEY should be set together"),
+        }
+    }
+
+    pub fn connector(&self) -> Result<async_native_tls::TlsConnector> {
+        let identity = native_tls::Identity::from_pkcs8(&self.cert, &self.key)
+            .context("parse client certificate")?;
+        let mut connector = async_native_tls::TlsConnector::new().identity(identity);
+        if let Some(ca_cert) = &self.ca_cert {
+            connector = connector.add_root_certificate(
+                native_tls::Certificate::from_pem(ca_cert).context("parse CA certificate")?,
+            );
+        }
+        Ok(connector)
+    }
+}
+
+/// Serves over TLS when TLS_CERT and TLS_KEY are set; TLS_CLIENT_CA also
+/// requires clients to present a certificate signed by it.
+pub fn with_tls(figment: Figment) -> Figment {
+    let client_ca = std::env::var("TLS_CLIENT_CA").ok();
+    let figment = match (std::env::var("TLS_CERT"), std::env::var("TLS_KEY")) {
+        (Ok(certs), Ok(key)) => figment.merge(("tls.certs", certs)).merge(("tls.key", key)),
+        (Err(_), Err(_)) if client_ca.is_none() => return figment,
+        _ => panic!("TLS_CERT and TLS_KEY should both be set, TLS_CLIENT_CA requires them"),
+    };
+    match client_ca {
+        Some(ca_certs) => figment
+            .merge(("tls.mutual.ca_certs", ca_certs))
+            .merge(("tls.mutual.mandatory", true)),
+        None => figment,
+    }
+}
+
+fn read_env_file(name: &str) -> Result<Option<Vec<u8>>> {
+    match std::env::var(name) {
+        Ok(path) => std::fs::read(&path)
+            .with_context(|| format!("read {} from {}", name, path))
+            .map(Some),
+        Err(_) => Ok(None),
+    }
+}
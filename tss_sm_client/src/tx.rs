@@ -0,0 +1,570 @@
+//! Ethereum transaction building and encoding.
+//!
+//! Both parties derive the hash they contribute a signature share to from the
+//! same structured transaction, instead of trusting an opaque `message_to_sign`.
+
+use std::fmt;
+use std::str::FromStr;
+
+use anyhow::{bail, Context, Result};
+use crypto::digest::Digest;
+use crypto::sha3::Sha3;
+use rlp::RlpStream;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+
+use multi_party_ecdsa::protocols::multi_party_ecdsa::gg_2020::party_i::SignatureRecid;
+
+/// Fixed-size byte string, (de)serialized as `0x`-prefixed hex.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FixedBytes<const N: usize>(pub [u8; N]);
+
+pub type Address = FixedBytes<20>;
+pub type H256 = FixedBytes<32>;
+
+impl<const N: usize> FromStr for FixedBytes<N> {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let bytes = decode_hex(s)?;
+        if bytes.len() != N {
+            bail!("expected {} bytes, got {}", N, bytes.len());
+        }
+        let mut out = [0u8; N];
+        out.copy_from_slice(&bytes);
+        Ok(Self(out))
+    }
+}
+
+impl<const N: usize> fmt::Display for FixedBytes<N> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "0x{}", hex::encode(self.0))
+    }
+}
+
+impl<const N: usize> Serialize for FixedBytes<N> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de, const N: usize> Deserialize<'de> for FixedBytes<N> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        s.parse().map_err(de::Error::custom)
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AccessListItem {
+    pub address: Address,
+    #[serde(default)]
+    pub storage_keys: Vec<H256>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct LegacyTransaction {
+    #[serde(with = "quantity")]
+    pub chain_id: u64,
+    #[serde(with = "quantity")]
+    pub nonce: u64,
+    #[serde(with = "quantity")]
+    pub gas_price: u128,
+    #[serde(with = "quantity")]
+    pub gas_limit: u64,
+    /// `None` for contract creation.
+    #[serde(default)]
+    pub to: Option<Address>,
+    #[serde(with = "quantity")]
+    pub value: u128,
+    #[serde(default, with = "data")]
+    pub data: Vec<u8>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eip2930Transaction {
+    #[serde(with = "quantity")]
+    pub chain_id: u64,
+    #[serde(with = "quantity")]
+    pub nonce: u64,
+    #[serde(with = "quantity")]
+    pub gas_price: u128,
+    #[serde(with = "quantity")]
+    pub gas_limit: u64,
+    #[serde(default)]
+    pub to: Option<Address>,
+    #[serde(with = "quantity")]
+    pub value: u128,
+    #[serde(default, with = "data")]
+    pub data: Vec<u8>,
+    #[serde(default)]
+    pub access_list: Vec<AccessListItem>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Eip1559Transaction {
+    #[serde(with = "quantity")]
+    pub chain_id: u64,
+    #[serde(with = "quantity")]
+    pub nonce: u64,
+    #[serde(with = "quantity")]
+    pub max_priority_fee_per_gas: u128,
+    #[serde(with = "quantity")]
+    pub max_fee_per_gas: u128,
+    #[serde(with = "quantity")]
+    pub gas_limit: u64,
+    #[serde(default)]
+    pub to: Option<Address>,
+    #[serde(with = "quantity")]
+    pub value: u128,
+    #[serde(default, with = "data")]
+    pub data: Vec<u8>,
+    #[serde(default)]
+    pub access_list: Vec<AccessListItem>,
+}
+
+/// An unsigned transaction of any supported type, tagged by `"type"` in JSON.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "lowercase")]
+pub enum Transaction {
+    Legacy(LegacyTransaction),
+    Eip2930(Eip2930Transaction),
+    Eip1559(Eip1559Transaction),
+}
+
+/// ECDSA signature over a transaction's signing hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Signature {
+    pub r: [u8; 32],
+    pub s: [u8; 32],
+    pub recid: u8,
+}
+
+impl Signature {
+    /// Parses the serialized signature returned by [`crate::sign`].
+    pub fn from_sign_output(signature: &str) -> Result<Self> {
+        let signature =
+            serde_json::from_str::<SignatureRecid>(signature).context("parse signature")?;
+        let mut r = [0u8; 32];
+        let mut s = [0u8; 32];
+        r.copy_from_slice(&signature.r.to_bytes());
+        s.copy_from_slice(&signature.s.to_bytes());
+        if signature.recid > 1 {
+            bail!("unsupported recovery id {}", signature.recid);
+        }
+        Ok(Self {
+            r,
+            s,
+            recid: signature.recid,
+        })
+    }
+}
+
+impl Transaction {
+    pub fn chain_id(&self) -> u64 {
+        match self {
+            Transaction::Legacy(tx) => tx.chain_id,
+            Transaction::Eip2930(tx) => tx.chain_id,
+            Transaction::Eip1559(tx) => tx.chain_id,
+        }
+    }
+
+    pub fn to(&self) -> Option<Address> {
+        match self {
+            Transaction::Legacy(tx) => tx.to,
+            Transaction::Eip2930(tx) => tx.to,
+            Transaction::Eip1559(tx) => tx.to,
+        }
+    }
+
+    pub fn value(&self) -> u128 {
+        match self {
+            Transaction::Legacy(tx) => tx.value,
+            Transaction::Eip2930(tx) => tx.value,
+            Transaction::Eip1559(tx) => tx.value,
+        }
+    }
+
+    pub fn gas_limit(&self) -> u64 {
+        match self {
+            Transaction::Legacy(tx) => tx.gas_limit,
+            Transaction::Eip2930(tx) => tx.gas_limit,
+            Transaction::Eip1559(tx) => tx.gas_limit,
+        }
+    }
+
+    /// Highest price per gas the sender may pay.
+    pub fn max_fee_per_gas(&self) -> u128 {
+        match self {
+            Transaction::Legacy(tx) => tx.gas_price,
+            Transaction::Eip2930(tx) => tx.gas_price,
+            Transaction::Eip1559(tx) => tx.max_fee_per_gas,
+        }
+    }
+
+    pub fn data(&self) -> &[u8] {
+        match self {
+            Transaction::Legacy(tx) => &tx.data,
+            Transaction::Eip2930(tx) => &tx.data,
+            Transaction::Eip1559(tx) => &tx.data,
+        }
+    }
+
+    /// Payload whose keccak256 is signed (EIP-155 form for legacy transactions).
+    pub fn encode_unsigned(&self) -> Vec<u8> {
+        self.encode(None)
+    }
+
+    /// Raw transaction ready for `eth_sendRawTransaction`.
+    pub fn encode_signed(&self, signature: &Signature) -> Vec<u8> {
+        self.encode(Some(signature))
+    }
+
+    pub fn signing_hash(&self) -> [u8; 32] {
+        keccak256(&self.encode_unsigned())
+    }
+
+    fn encode(&self, signature: Option<&Signature>) -> Vec<u8> {
+        let mut stream = RlpStream::new();
+        match self {
+            Transaction::Legacy(tx) => {
+                stream.begin_list(9);
+                stream.append(&tx.nonce);
+                stream.append(&tx.gas_price);
+                stream.append(&tx.gas_limit);
+                append_to(&mut stream, &tx.to);
+                stream.append(&tx.value);
+                stream.append(&tx.data);
+                match signature {
+                    Some(signature) => {
+                        stream.append(&(tx.chain_id * 2 + 35 + u64::from(signature.recid)));
+                        append_signature_scalars(&mut stream, signature);
+                    }
+                    None => {
+                        stream.append(&tx.chain_id);
+                        stream.append_empty_data();
+                        stream.append_empty_data();
+                    }
+                }
+                stream.out().to_vec()
+            }
+            Transaction::Eip2930(tx) => {
+                stream.begin_list(if signature.is_some() { 11 } else { 8 });
+                stream.append(&tx.chain_id);
+                stream.append(&tx.nonce);
+                stream.append(&tx.gas_price);
+                stream.append(&tx.gas_limit);
+                append_to(&mut stream, &tx.to);
+                stream.append(&tx.value);
+                stream.append(&tx.data);
+                append_access_list(&mut stream, &tx.access_list);
+                if let Some(signature) = signature {
+                    stream.append(&signature.recid);
+                    append_signature_scalars(&mut stream, signature);
+                }
+                [&[0x01], stream.as_raw()].concat()
+            }
+            Transaction::Eip1559(tx) => {
+                stream.begin_list(if signature.is_some() { 12 } else { 9 });
+                stream.append(&tx.chain_id);
+                stream.append(&tx.nonce);
+                stream.append(&tx.max_priority_fee_per_gas);
+                stream.append(&tx.max_fee_per_gas);
+                stream.append(&tx.gas_limit);
+                append_to(&mut stream, &tx.to);
+                stream.append(&tx.value);
+                stream.append(&tx.data);
+                append_access_list(&mut stream, &tx.access_list);
+                if let Some(signature) = signature {
+                    stream.append(&signature.recid);
+                    append_signature_scalars(&mut stream, signature);
+                }
+                [&[0x02], stream.as_raw()].concat()
+            }
+        }
+    }
+}
+
+pub fn keccak256(data: &[u8]) -> [u8; 32] {
+    let mut hasher = Sha3::keccak256();
+    hasher.input(data);
+    let mut out = [0u8; 32];
+    hasher.result(&mut out);
+    out
+}
+
+fn append_to(stream: &mut RlpStream, to: &Option<Address>) {
+    match to {
+        Some(address) => stream.append(&&address.0[..]),
+        None => stream.append_empty_data(),
+    };
+}
+
+fn append_access_list(stream: &mut RlpStream, access_list: &[AccessListItem]) {
+    stream.begin_list(access_list.len());
+    for item in access_list {
+        stream.begin_list(2);
+        stream.append(&&item.address.0[..]);
+        stream.begin_list(item.storage_keys.len());
+        for key in &item.storage_keys {
+            stream.append(&&key.0[..]);
+        }
+    }
+}
+
+// r and s are RLP integers, so leading zero bytes must be dropped
+fn append_signature_scalars(stream: &mut RlpStream, signature: &Signature) {
+    for scalar in [&signature.r, &signature.s] {
+        let start = scalar.iter().position(|b| *b != 0).unwrap_or(scalar.len());
+        stream.append(&&scalar[start..]);
+    }
+}
+
+fn decode_hex(s: &str) -> Result<Vec<u8>> {
+    let s = s.strip_prefix("0x").unwrap_or(s);
+    hex::decode(s).with_context(|| format!("invalid hex string {}", s))
+}
+
+/// Integers as `0x`-prefixed hex strings; decimal strings and JSON numbers
+/// are accepted on input.
+pub mod quantity {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+    use std::convert::TryFrom;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Raw {
+        Number(u64),
+        String(String),
+    }
+
+    pub fn serialize<T, S>(value: &T, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        T: Copy + Into<u128>,
+        S: Serializer,
+    {
+        serializer.serialize_str(&format!("{:#x}", (*value).into()))
+    }
+
+    pub fn deserialize<'de, T, D>(deserializer: D) -> Result<T, D::Error>
+    where
+        T: TryFrom<u128>,
+        D: Deserializer<'de>,
+    {
+        let value = match Raw::deserialize(deserializer)? {
+            Raw::Number(n) => u128::from(n),
+            Raw::String(s) => match s.strip_prefix("0x") {
+                Some(hex) => u128::from_str_radix(hex, 16),
+                None => s.parse::<u128>(),
+            }
+            .map_err(de::Error::custom)?,
+        };
+        T::try_from(value).map_err(|_| de::Error::custom("quantity out of range"))
+    }
+}
+
+/// Byte strings as `0x`-prefixed hex.
+mod data {
+    use serde::{de, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(value: &[u8], serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&format!("0x{}", hex::encode(value)))
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<u8>, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        super::decode_hex(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn legacy_example() -> Transaction {
+        // the example of EIP-155
+        Transaction::Legacy(LegacyTransaction {
+            chain_id: 1,
+            nonce: 9,
+            gas_price: 20_000_000_000,
+            gas_limit: 21000,
+            to: Some(
+                "0x3535353535353535353535353535353535353535"
+                    .parse()
+                    .unwrap(),
+            ),
+            value: 1_000_000_000_000_000_000,
+            data: vec![],
+        })
+    }
+
+    fn eip1559_example() -> Transaction {
+        Transaction::Eip1559(Eip1559Transaction {
+            chain_id: 1,
+            nonce: 34,
+            max_priority_fee_per_gas: 2_000_000_000,
+            max_fee_per_gas: 100_000_000_000,
+            gas_limit: 100_000,
+            to: Some(
+                "0x09616c3d61b3331fc4109a9e41a8bdb7d9776609"
+                    .parse()
+                    .unwrap(),
+            ),
+            value: 0x5af3107a4000,
+            data: b"abcdef".to_vec(),
+            access_list: vec![AccessListItem {
+                address: "0x0000000000000000000000000000000000000001"
+                    .parse()
+                    .unwrap(),
+                storage_keys: vec![
+                    "0x0100000000000000000000000000000000000000000000000000000000000000"
+                        .parse()
+                        .unwrap(),
+                ],
+            }],
+        })
+    }
+
+    fn signature(r: &str, s: &str, recid: u8) -> Signature {
+        Signature {
+            r: r.parse::<H256>().unwrap().0,
+            s: s.parse::<H256>().unwrap().0,
+            recid,
+        }
+    }
+
+    fn sign_output(r: &str, s: &str, recid: u8) -> String {
+        format!(
+            r#"{{"r":{{"curve":"secp256k1","scalar":"{}"}},"s":{{"curve":"secp256k1","scalar":"{}"}},"recid":{}}}"#,
+            r, s, recid
+        )
+    }
+
+    #[test]
+    fn encodes_eip155_example() {
+        let tx = legacy_example();
+        assert_eq!(
+            hex::encode(tx.encode_unsigned()),
+            "ec098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a764000080018080"
+        );
+        assert_eq!(
+            hex::encode(tx.signing_hash()),
+            "daf5a779ae972f972197303d7b574746c7ef83eadac0f2791ad23db92e4c8e53"
+        );
+
+        let signature = signature(
+            "0x28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+            "0x67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+            0,
+        );
+        assert_eq!(
+            hex::encode(tx.encode_signed(&signature)),
+            "f86c098504a817c800825208943535353535353535353535353535353535353535880de0b6b3a7640000\
+             8025a028ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276a067cbe9d899\
+             7f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83"
+        );
+    }
+
+    #[test]
+    fn encodes_eip2930_tx() {
+        // signed tx of go-ethereum's TestEIP2718TransactionEncode
+        let tx = Transaction::Eip2930(Eip2930Transaction {
+            chain_id: 1,
+            nonce: 3,
+            gas_price: 1,
+            gas_limit: 25000,
+            to: Some(
+                "0xb94f5374fce5edbc8e2a8697c15331677e6ebf0b"
+                    .parse()
+                    .unwrap(),
+            ),
+            value: 10,
+            data: vec![0x55, 0x44],
+            access_list: vec![],
+        });
+        let signature = signature(
+            "0xc9519f4f2b30335884581971573fadf60c6204f59a911df35ee8a540456b2660",
+            "0x32f1e8e2c5dd761f9e4f88f41c8310aeaba26a8bfcdacfedfa12ec3862d37521",
+            1,
+        );
+        assert_eq!(
+            hex::encode(tx.encode_signed(&signature)),
+            "01f8630103018261a894b94f5374fce5edbc8e2a8697c15331677e6ebf0b0a825544c001a0c9519f4f\
+             2b30335884581971573fadf60c6204f59a911df35ee8a540456b2660a032f1e8e2c5dd761f9e4f88f4\
+             1c8310aeaba26a8bfcdacfedfa12ec3862d37521"
+        );
+        assert_eq!(
+            hex::encode(tx.signing_hash()),
+            "49b486f0ec0a60dfbbca2d30cb07c9e8ffb2a2ff41f29a1ab6737475f6ff69f3"
+        );
+    }
+
+    #[test]
+    fn encodes_eip1559_tx() {
+        let tx = eip1559_example();
+        assert_eq!(
+            hex::encode(tx.encode_unsigned()),
+            "02f86e0122847735940085174876e800830186a09409616c3d61b3331fc4109a9e41a8bdb7d9776609\
+             865af3107a400086616263646566f838f7940000000000000000000000000000000000000001e1a001\
+             00000000000000000000000000000000000000000000000000000000000000"
+        );
+        assert_eq!(
+            hex::encode(tx.signing_hash()),
+            "9a77a4504fd13106113fe5d67a254c10085c475f21759d3d13f2c26951b8dc97"
+        );
+
+        // r has a leading zero byte, which RLP drops
+        let signature = signature(
+            "0x00ababababababababababababababababababababababababababababababab",
+            "0x7fcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd",
+            1,
+        );
+        assert_eq!(
+            hex::encode(tx.encode_signed(&signature)),
+            "02f8b00122847735940085174876e800830186a09409616c3d61b3331fc4109a9e41a8bdb7d9776609\
+             865af3107a400086616263646566f838f7940000000000000000000000000000000000000001e1a001\
+             00000000000000000000000000000000000000000000000000000000000000019fabababababababab\
+             abababababababababababababababababababababababa07fcdcdcdcdcdcdcdcdcdcdcdcdcdcdcdcd\
+             cdcdcdcdcdcdcdcdcdcdcdcdcdcdcd"
+        );
+    }
+
+    #[test]
+    fn reads_recovery_id_of_sign_output() {
+        let (r, s) = (
+            "28ef61340bd939bc2195fe537567866003e1a15d3c71ff63e1590620aa636276",
+            "67cbe9d8997f761aecb703304b3800ccf555c9f3dc64214b297fb1966a3b6d83",
+        );
+        // v of a legacy tx on chain 1, and the yParity item of a typed tx
+        for (recid, v, y_parity) in [(0, "25", "80"), (1, "26", "01")] {
+            let signature = Signature::from_sign_output(&sign_output(r, s, recid)).unwrap();
+            assert_eq!(signature.recid, recid);
+            assert_eq!(hex::encode(signature.r), r);
+            assert_eq!(hex::encode(signature.s), s);
+
+            let legacy = hex::encode(legacy_example().encode_signed(&signature));
+            assert!(legacy.contains(&format!("80{}a0{}", v, r)), "{}", legacy);
+            let typed = hex::encode(eip1559_example().encode_signed(&signature));
+            assert!(typed.contains(&format!("{}a0{}", y_parity, r)), "{}", typed);
+        }
+
+        assert!(Signature::from_sign_output(&sign_output(r, s, 2)).is_err());
+    }
+
+    #[test]
+    fn trims_leading_zeros_of_quantities() {
+        #[derive(Serialize, Deserialize)]
+        struct Value(#[serde(with = "quantity")] u128);
+
+        assert_eq!(serde_json::to_string(&Value(0)).unwrap(), r#""0x0""#);
+        assert_eq!(serde_json::to_string(&Value(0x0100)).unwrap(), r#""0x100""#);
+        for input in [r#""0x000a""#, r#""0xa""#, r#""10""#, "10"] {
+            assert_eq!(
+                serde_json::from_str::<Value>(input).unwrap().0,
+                10,
+                "{}",
+                input
+            );
+        }
+        assert!(serde_json::from_str::<Value>(r#""0x""#).is_err());
+    }
+}
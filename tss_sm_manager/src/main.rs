@@ -14,6 +14,7 @@ use rocket::State;
 use serde::{Deserialize, Serialize};
 use tokio::sync::{Notify, RwLock};
 use dotenv::dotenv;
+use tss_sm_client::tls::with_tls;
 
 #[rocket::get("/rooms/<room_id>/subscribe")]
 async fn subscribe(
@@ -193,7 +194,7 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             .parse::<u16>()
             .unwrap(),
     ));
-    let _ = rocket::custom(figment)
+    let _ = rocket::custom(with_tls(figment))
         .mount("/", rocket::routes![subscribe, issue_idx, broadcast])
         .manage(Db::empty())
         .launch()